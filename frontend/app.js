@@ -1,10 +1,27 @@
+const PRIORITY_MARKERS = { none: '·', low: '!', medium: '!!', high: '!!!' };
+const PRIORITY_LABELS = { none: 'keine', low: 'niedrig', medium: 'mittel', high: 'hoch' };
+const ROLE_LABELS = { viewer: 'nur lesen', editor: 'geteilt' };
+
 class TodoApp {
     constructor() {
         this.basePath = window.BASE_PATH || '';
+        this.csrfToken = window.CSRF_TOKEN || '';
         this.todos = [];
+        this.lists = [];
+        this.currentListId = parseInt(localStorage.getItem('donezo.list')) || null;
         this.tokens = [];
+        this.sessions = [];
+        // Two-factor status, and the secret being enrolled or the recovery codes just created
+        this.totp = null;
+        this.totpSetup = null;
+        this.recoveryCodes = null;
         this.draggedItem = null;
         this.editingId = null;
+        // Todo whose notes are shown below it, and whether they are being edited
+        this.notesId = null;
+        this.notesHtml = '';
+        this.notesEditing = false;
+        this.searchTimer = null;
         // Touch drag state
         this.touchDragId = null;
         this.touchClone = null;
@@ -20,7 +37,74 @@ class TodoApp {
     init() {
         this.bindElements();
         this.bindEvents();
-        this.loadTodos();
+        this.loadLists();
+        this.listenForChanges();
+    }
+
+    // Applies changes made elsewhere, e.g. on another device, as they happen
+    listenForChanges() {
+        const events = new EventSource(`${this.basePath}/api/events`);
+        const on = (name, handler) => events.addEventListener(name, (e) => handler(JSON.parse(e.data)));
+
+        on('created', ({ todo }) => this.applyTodo(todo));
+        on('updated', ({ todo }) => this.applyTodo(todo));
+        on('deleted', ({ id, list_id }) => {
+            if (list_id !== this.currentListId) return;
+            // Subtasks are deleted along with their parent
+            const deleted = new Set([id]);
+            let count;
+            do {
+                count = deleted.size;
+                this.todos.forEach(t => {
+                    if (deleted.has(t.parent_id)) deleted.add(t.id);
+                });
+            } while (deleted.size !== count);
+            this.todos = this.todos.filter(t => !deleted.has(t.id));
+            this.refreshTodos(false);
+        });
+        on('reordered', ({ list_id }) => {
+            if (list_id === this.currentListId) this.refreshTodos(true);
+        });
+        on('changed', ({ list_id }) => {
+            if (list_id === this.currentListId) this.refreshTodos(true);
+        });
+        // Only the device that deleted the last list creates a new one
+        on('list_changed', () => this.loadLists(false));
+        on('resync', () => this.loadLists(false));
+
+        // The browser reconnects by itself, but changes made in between are lost
+        let disconnected = false;
+        events.addEventListener('error', () => { disconnected = true; });
+        events.addEventListener('open', () => {
+            if (disconnected) this.loadLists(false);
+            disconnected = false;
+        });
+    }
+
+    // Takes over a todo as created or updated elsewhere
+    applyTodo(todo) {
+        const index = this.todos.findIndex(t => t.id === todo.id);
+        if (todo.list_id !== this.currentListId) {
+            // Moved to another list, along with its subtasks
+            if (index !== -1) this.refreshTodos(true);
+            return;
+        }
+        if (index === -1) {
+            this.todos.push(todo);
+        } else {
+            this.todos[index] = todo;
+        }
+        this.refreshTodos(false);
+    }
+
+    // Shows changes from elsewhere, unless that would throw away an edit in progress
+    refreshTodos(reload) {
+        if (this.editingId !== null || this.notesEditing) return;
+        if (reload) {
+            this.loadTodos();
+        } else {
+            this.renderTodos();
+        }
     }
 
     bindElements() {
@@ -28,10 +112,26 @@ class TodoApp {
         this.createTokenForm = document.getElementById('create-token-form');
 
         this.todoTitle = document.getElementById('todo-title');
+        this.todoDue = document.getElementById('todo-due');
+        this.todoRecurrence = document.getElementById('todo-recurrence');
         this.tokenName = document.getElementById('token-name');
+        this.tokenScopes = document.getElementById('token-scopes');
+        this.tokenList = document.getElementById('token-list');
+        this.tokenExpiry = document.getElementById('token-expiry');
+
+        this.listSelect = document.getElementById('list-select');
+        this.newListBtn = document.getElementById('new-list-btn');
+        this.deleteListBtn = document.getElementById('delete-list-btn');
+
+        this.searchInput = document.getElementById('search-input');
+        this.searchResults = document.getElementById('search-results');
 
         this.todosList = document.getElementById('todos-list');
         this.tokensList = document.getElementById('tokens-list');
+        this.sessionsList = document.getElementById('sessions-list');
+        this.logoutEverywhereBtn = document.getElementById('logout-everywhere-btn');
+        this.totpBtn = document.getElementById('totp-btn');
+        this.totpPanel = document.getElementById('totp-panel');
 
         this.completedSection = document.getElementById('completed-section');
         this.completedList = document.getElementById('completed-list');
@@ -52,8 +152,19 @@ class TodoApp {
         this.addTodoForm.addEventListener('submit', (e) => this.handleAddTodo(e));
         this.createTokenForm.addEventListener('submit', (e) => this.handleCreateToken(e));
 
+        this.listSelect.addEventListener('change', () => this.selectList(parseInt(this.listSelect.value)));
+        this.newListBtn.addEventListener('click', () => this.handleNewList());
+        this.deleteListBtn.addEventListener('click', () => this.handleDeleteList());
+
+        this.searchInput.addEventListener('input', () => {
+            clearTimeout(this.searchTimer);
+            this.searchTimer = setTimeout(() => this.handleSearch(), 200);
+        });
+
         this.logoutBtn.addEventListener('click', () => this.handleLogout());
         this.tokensBtn.addEventListener('click', () => this.openTokensModal());
+        this.logoutEverywhereBtn.addEventListener('click', () => this.handleLogoutEverywhere());
+        this.totpBtn.addEventListener('click', () => this.handleTotpButton());
         this.closeTokensModal.addEventListener('click', () => this.closeModal(this.tokensModal));
 
         this.tokensModal.addEventListener('click', (e) => {
@@ -69,9 +180,117 @@ class TodoApp {
         });
     }
 
+    // fetch() that sends the CSRF token along with changes, which the server
+    // requires for requests authenticated by the session cookie
+    async request(url, options = {}) {
+        const method = (options.method || 'GET').toUpperCase();
+        if (method === 'GET' || method === 'HEAD') {
+            return fetch(url, options);
+        }
+        const headers = { ...options.headers, 'X-CSRF-Token': this.csrfToken };
+        const response = await fetch(url, { ...options, headers });
+        if (response.status === 403) {
+            const body = await response.clone().json().catch(() => ({}));
+            if (body.csrf_invalid) {
+                // The server restarted and issued new tokens; reload to get ours
+                window.location.reload();
+            }
+        }
+        return response;
+    }
+
+    async loadLists(createDefault = true) {
+        try {
+            const response = await this.request(`${this.basePath}/api/lists`);
+            if (response.status === 401) {
+                window.location.href = `${this.basePath}/login`;
+                return;
+            }
+            this.lists = await response.json();
+
+            if (this.lists.length === 0 && createDefault) {
+                const created = await this.request(`${this.basePath}/api/lists`, {
+                    method: 'POST',
+                    headers: { 'Content-Type': 'application/json' },
+                    body: JSON.stringify({ name: 'Todos' }),
+                });
+                if (created.ok) {
+                    this.lists.push(await created.json());
+                }
+            }
+
+            if (!this.lists.some(l => l.id === this.currentListId)) {
+                this.currentListId = this.lists.length > 0 ? this.lists[0].id : null;
+            }
+
+            this.renderLists();
+            await this.loadTodos();
+        } catch (error) {
+            console.error('Failed to load lists:', error);
+        }
+    }
+
+    renderLists() {
+        this.listSelect.innerHTML = this.lists.map(list => `
+            <option value="${list.id}" ${list.id === this.currentListId ? 'selected' : ''}>${this.escapeHtml(list.name)}${list.role !== 'owner' ? ` (${ROLE_LABELS[list.role]})` : ''}</option>
+        `).join('');
+    }
+
+    async selectList(id) {
+        this.currentListId = id;
+        this.editingId = null;
+        localStorage.setItem('donezo.list', id);
+        this.renderLists();
+        await this.loadTodos();
+    }
+
+    async handleNewList() {
+        const name = (prompt('Name der neuen Liste:') || '').trim();
+        if (!name) return;
+
+        try {
+            const response = await this.request(`${this.basePath}/api/lists`, {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({ name }),
+            });
+
+            if (response.ok) {
+                const list = await response.json();
+                if (!this.lists.some(l => l.id === list.id)) {
+                    this.lists.push(list);
+                }
+                await this.selectList(list.id);
+            }
+        } catch (error) {
+            console.error('Failed to create list:', error);
+        }
+    }
+
+    async handleDeleteList() {
+        const list = this.lists.find(l => l.id === this.currentListId);
+        if (!list) return;
+        if (!confirm(`Liste "${list.name}" und alle ihre Aufgaben löschen?`)) return;
+
+        try {
+            const response = await this.request(`${this.basePath}/api/lists/${list.id}`, {
+                method: 'DELETE',
+            });
+
+            if (response.ok) {
+                this.currentListId = null;
+                await this.loadLists();
+            }
+        } catch (error) {
+            console.error('Failed to delete list:', error);
+        }
+    }
+
     async loadTodos() {
+        if (this.currentListId === null) return;
+
         try {
-            const response = await fetch(`${this.basePath}/api/todos`);
+            const response = await this.request(`${this.basePath}/api/lists/${this.currentListId}/todos`);
             if (response.status === 401) {
                 window.location.href = `${this.basePath}/login`;
                 return;
@@ -83,6 +302,50 @@ class TodoApp {
         }
     }
 
+    async handleSearch() {
+        const q = this.searchInput.value.trim();
+        const searching = q.length > 0;
+
+        this.searchResults.classList.toggle('hidden', !searching);
+        this.todosList.classList.toggle('hidden', searching);
+        this.completedSection.classList.toggle('hidden', searching || !this.todos.some(t => t.completed));
+        if (!searching) return;
+
+        try {
+            const response = await this.request(`${this.basePath}/api/todos/search?q=${encodeURIComponent(q)}`);
+            if (!response.ok) return;
+            const results = await response.json();
+            if (q !== this.searchInput.value.trim()) return;
+            this.renderSearchResults(results);
+        } catch (error) {
+            console.error('Failed to search todos:', error);
+        }
+    }
+
+    renderSearchResults(results) {
+        if (results.length === 0) {
+            this.searchResults.innerHTML = '<p class="text-gray-500 dark:text-gray-400 text-center">Nichts gefunden.</p>';
+            return;
+        }
+
+        this.searchResults.innerHTML = results.map(result => {
+            const list = this.lists.find(l => l.id === result.list_id);
+            return `
+                <div class="bg-white dark:bg-gray-800 rounded-lg shadow-sm p-4 flex items-center gap-3 border border-gray-200 dark:border-gray-700">
+                    <span class="flex-1 min-w-0 font-medium ${result.completed ? 'text-gray-500 dark:text-gray-400 line-through' : 'text-gray-800 dark:text-gray-100'}">${this.highlight(result.snippet)}</span>
+                    ${list ? `<span class="flex-shrink-0 text-xs text-gray-500 dark:text-gray-400">${this.escapeHtml(list.name)}</span>` : ''}
+                </div>
+            `;
+        }).join('');
+    }
+
+    // Escapes a search snippet while keeping its <mark> highlights
+    highlight(snippet) {
+        return this.escapeHtml(snippet)
+            .replace(/&lt;mark&gt;/g, '<mark>')
+            .replace(/&lt;\/mark&gt;/g, '</mark>');
+    }
+
     toggleCompletedSection() {
         this.completedExpanded = !this.completedExpanded;
         this.completedList.classList.toggle('hidden', !this.completedExpanded);
@@ -94,13 +357,13 @@ class TodoApp {
         const completedTodos = this.todos.filter(t => t.completed);
 
         // Render open todos
-        this.todosList.innerHTML = openTodos.map(todo => this.renderTodoItem(todo)).join('');
+        this.todosList.innerHTML = openTodos.map(todo => this.renderTodoItem(todo, this.depthOf(todo, openTodos))).join('');
 
         // Render completed section
         if (completedTodos.length > 0) {
             this.completedSection.classList.remove('hidden');
             this.completedCount.textContent = `Erledigt (${completedTodos.length})`;
-            this.completedList.innerHTML = completedTodos.map(todo => this.renderTodoItem(todo)).join('');
+            this.completedList.innerHTML = completedTodos.map(todo => this.renderTodoItem(todo, this.depthOf(todo, completedTodos))).join('');
         } else {
             this.completedSection.classList.add('hidden');
         }
@@ -110,6 +373,7 @@ class TodoApp {
             const checkbox = document.getElementById(`checkbox-${todo.id}`);
 
             checkbox.addEventListener('change', () => this.toggleTodo(todo.id));
+            document.getElementById(`notes-${todo.id}`).addEventListener('click', () => this.toggleNotes(todo.id));
 
             if (todo.completed) {
                 const deleteBtn = document.getElementById(`delete-${todo.id}`);
@@ -118,6 +382,10 @@ class TodoApp {
                 }
             } else {
                 const dragHandle = document.getElementById(`drag-${todo.id}`);
+                const subtaskBtn = document.getElementById(`subtask-${todo.id}`);
+                subtaskBtn.addEventListener('click', () => this.handleAddSubtask(todo.id));
+                const priorityBtn = document.getElementById(`priority-${todo.id}`);
+                priorityBtn.addEventListener('click', () => this.cyclePriority(todo.id));
 
                 if (this.editingId === todo.id) {
                     const input = document.getElementById(`edit-input-${todo.id}`);
@@ -143,6 +411,10 @@ class TodoApp {
             }
         });
 
+        if (this.notesId !== null) {
+            this.bindNotesPanel();
+        }
+
         // Bind edit input events after render
         if (this.editingId) {
             const input = document.getElementById(`edit-input-${this.editingId}`);
@@ -153,12 +425,132 @@ class TodoApp {
         }
     }
 
-    renderTodoItem(todo) {
+    // Nesting level of a todo among the given todos
+    depthOf(todo, todos) {
+        let depth = 0;
+        let parent = todos.find(t => t.id === todo.parent_id);
+        while (parent) {
+            depth++;
+            parent = todos.find(t => t.id === parent.parent_id);
+        }
+        return depth;
+    }
+
+    renderTodoItem(todo, depth = 0) {
         const isEditing = this.editingId === todo.id;
         const isCompleted = todo.completed;
 
+        return this.renderTodoRow(todo, depth, isEditing, isCompleted)
+            + (this.notesId === todo.id ? this.renderNotesPanel(todo, depth) : '');
+    }
+
+    renderNotesPanel(todo, depth) {
+        return `
+            <div id="notes-panel" style="margin-left: ${depth * 2}rem" class="bg-white dark:bg-gray-800 rounded-lg shadow-sm p-4 space-y-3 border border-gray-200 dark:border-gray-700 text-gray-700 dark:text-gray-300">
+                ${this.notesEditing ? `
+                    <textarea
+                        id="notes-input"
+                        rows="6"
+                        class="w-full px-2 py-1 border border-blue-400 dark:bg-gray-700 dark:text-gray-100 rounded focus:outline-none focus:ring-2 focus:ring-blue-500"
+                        placeholder="Notizen (Markdown)"
+                    >${this.escapeHtml(todo.notes || '')}</textarea>
+                    <div class="flex justify-between items-center gap-2">
+                        <span class="flex-1 text-xs text-gray-500 dark:text-gray-400">Markdown</span>
+                        <button id="notes-cancel" class="px-3 py-1 text-gray-600 dark:text-gray-400 hover:bg-gray-100 dark:hover:bg-gray-700 rounded transition-colors">Abbrechen</button>
+                        <button id="notes-save" class="px-3 py-1 bg-blue-600 text-white rounded hover:bg-blue-700 transition-colors">Speichern</button>
+                    </div>
+                ` : `
+                    <div class="notes">${this.notesHtml || '<p class="text-gray-400">Keine Notizen</p>'}</div>
+                    <div class="flex justify-between items-center">
+                        <span></span>
+                        <button id="notes-edit" class="px-3 py-1 text-gray-600 dark:text-gray-400 hover:bg-gray-100 dark:hover:bg-gray-700 rounded transition-colors">Bearbeiten</button>
+                    </div>
+                `}
+            </div>
+        `;
+    }
+
+    bindNotesPanel() {
+        if (this.notesEditing) {
+            const input = document.getElementById('notes-input');
+            input.focus();
+            document.getElementById('notes-save').addEventListener('click', () => this.saveNotes(input.value));
+            document.getElementById('notes-cancel').addEventListener('click', () => {
+                this.notesEditing = false;
+                this.renderTodos();
+            });
+        } else {
+            document.getElementById('notes-edit').addEventListener('click', () => {
+                this.notesEditing = true;
+                this.renderTodos();
+            });
+        }
+    }
+
+    async toggleNotes(id) {
+        this.notesEditing = false;
+        if (this.notesId === id) {
+            this.notesId = null;
+            this.renderTodos();
+            return;
+        }
+
+        try {
+            // Only the single todo comes with its notes rendered
+            const response = await this.request(`${this.basePath}/api/todos/${id}`);
+            if (response.ok) {
+                const todo = await response.json();
+                this.notesId = id;
+                this.notesHtml = todo.notes_html;
+                this.renderTodos();
+            }
+        } catch (error) {
+            console.error('Failed to load notes:', error);
+        }
+    }
+
+    async saveNotes(notes) {
+        const id = this.notesId;
+        const todo = this.todos.find(t => t.id === id);
+
+        try {
+            const response = await this.request(`${this.basePath}/api/todos/${id}`, {
+                method: 'PUT',
+                headers: { 'Content-Type': 'application/json', ...this.ifMatch(todo) },
+                body: JSON.stringify({ notes }),
+            });
+
+            if (response.status === 412) {
+                await this.handleConflict();
+            } else if (response.ok) {
+                const updated = await response.json();
+                const index = this.todos.findIndex(t => t.id === id);
+                if (index !== -1) {
+                    this.todos[index] = updated;
+                }
+                this.notesId = null;
+                await this.toggleNotes(id);
+            }
+        } catch (error) {
+            console.error('Failed to save notes:', error);
+        }
+    }
+
+    // Makes an edit fail instead of overwriting changes made elsewhere since
+    // the todo was loaded
+    ifMatch(todo) {
+        return todo ? { 'If-Match': `"${todo.version}"` } : {};
+    }
+
+    async handleConflict() {
+        alert('This todo was changed elsewhere in the meantime. Please check it and try again.');
+        this.notesId = null;
+        await this.loadTodos();
+    }
+
+    renderTodoRow(todo, depth, isEditing, isCompleted) {
         return `
-            <div id="todo-${todo.id}" data-id="${todo.id}" class="todo-item bg-white dark:bg-gray-800 rounded-lg shadow-sm p-4 flex items-center gap-3 border border-gray-200 dark:border-gray-700 hover:shadow-md transition-all duration-300">
+            <div id="todo-${todo.id}" data-id="${todo.id}" style="margin-left: ${depth * 2}rem" class="todo-item bg-white dark:bg-gray-800 rounded-lg shadow-sm p-4 flex items-center gap-3 border border-gray-200 dark:border-gray-700 hover:shadow-md transition-all duration-300">
                 ${!isCompleted ? `
                     <div
                         id="drag-${todo.id}"
@@ -191,6 +583,40 @@ class TodoApp {
                         title="${isCompleted ? '' : 'Click to edit'}"
                     >${this.escapeHtml(todo.title)}</span>
                 `}
+                ${todo.tags.map(tag => `
+                    <span class="flex-shrink-0 text-xs px-2 py-1 rounded bg-gray-100 dark:bg-gray-700 text-gray-600 dark:text-gray-300">#${this.escapeHtml(tag)}</span>
+                `).join('')}
+                ${todo.due_at ? this.renderDue(todo) : ''}
+                ${!isCompleted ? `
+                    <button
+                        id="priority-${todo.id}"
+                        class="flex-shrink-0 w-6 p-1 text-xs font-bold rounded ${todo.priority === 'high' ? 'text-red-600 dark:text-red-400' : todo.priority === 'none' ? 'text-gray-400' : 'text-gray-600 dark:text-gray-300'} hover:bg-gray-100 dark:hover:bg-gray-700 transition-colors"
+                        title="Priorität: ${PRIORITY_LABELS[todo.priority]}"
+                    >${PRIORITY_MARKERS[todo.priority]}</button>
+                ` : ''}
+                <button
+                    id="notes-${todo.id}"
+                    class="flex-shrink-0 p-1 ${todo.notes ? 'text-gray-600 dark:text-gray-300' : 'text-gray-400'} hover:text-gray-600 dark:hover:text-gray-300 rounded transition-colors"
+                    title="Notizen"
+                >
+                    <svg class="w-5 h-5" fill="none" stroke="currentColor" viewBox="0 0 24 24">
+                        <path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M9 12h6m-6 4h6m2 5H7a2 2 0 01-2-2V5a2 2 0 012-2h5.586a1 1 0 01.707.293l5.414 5.414a1 1 0 01.293.707V19a2 2 0 01-2 2z"></path>
+                    </svg>
+                </button>
+                ${todo.recurrence ? `
+                    <span class="flex-shrink-0 text-xs text-gray-500 dark:text-gray-400" title="Wiederkehrend">↻</span>
+                ` : ''}
+                ${!isCompleted ? `
+                    <button
+                        id="subtask-${todo.id}"
+                        class="flex-shrink-0 p-1 text-gray-400 hover:text-gray-600 dark:hover:text-gray-300 rounded transition-colors"
+                        title="Unteraufgabe hinzufügen"
+                    >
+                        <svg class="w-5 h-5" fill="none" stroke="currentColor" viewBox="0 0 24 24">
+                            <path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M12 4v16m8-8H4"></path>
+                        </svg>
+                    </button>
+                ` : ''}
                 ${isCompleted ? `
                     <button
                         id="delete-${todo.id}"
@@ -206,6 +632,25 @@ class TodoApp {
         `;
     }
 
+    renderDue(todo) {
+        const overdue = !todo.completed && this.isOverdue(todo.due_at);
+        const label = todo.due_at.length === 10
+            ? new Date(`${todo.due_at}T00:00:00`).toLocaleDateString()
+            : new Date(todo.due_at).toLocaleString([], { dateStyle: 'short', timeStyle: 'short' });
+
+        return `
+            <span class="flex-shrink-0 text-xs ${overdue ? 'text-red-600 dark:text-red-400 font-medium' : 'text-gray-500 dark:text-gray-400'}" title="Fällig">${this.escapeHtml(label)}</span>
+        `;
+    }
+
+    isOverdue(dueAt) {
+        if (dueAt.length === 10) {
+            const today = new Date().toISOString().slice(0, 10);
+            return dueAt < today;
+        }
+        return new Date(dueAt) < new Date();
+    }
+
     startEdit(id) {
         if (this.editingId === id) return;
         this.editingId = id;
@@ -253,13 +698,16 @@ class TodoApp {
         }
 
         try {
-            const response = await fetch(`${this.basePath}/api/todos/${id}`, {
+            const response = await this.request(`${this.basePath}/api/todos/${id}`, {
                 method: 'PUT',
-                headers: { 'Content-Type': 'application/json' },
+                headers: { 'Content-Type': 'application/json', ...this.ifMatch(todo) },
                 body: JSON.stringify({ title: newTitle }),
             });
 
-            if (response.ok) {
+            if (response.status === 412) {
+                await this.handleConflict();
+                return;
+            } else if (response.ok) {
                 const updated = await response.json();
                 const index = this.todos.findIndex(t => t.id === id);
                 if (index !== -1) {
@@ -278,20 +726,52 @@ class TodoApp {
         this.renderTodos();
     }
 
+    async cyclePriority(id) {
+        const todo = this.todos.find(t => t.id === id);
+        if (!todo) return;
+
+        const priorities = Object.keys(PRIORITY_MARKERS);
+        const priority = priorities[(priorities.indexOf(todo.priority) + 1) % priorities.length];
+
+        try {
+            const response = await this.request(`${this.basePath}/api/todos/${id}`, {
+                method: 'PUT',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({ priority }),
+            });
+
+            if (response.ok) {
+                const updated = await response.json();
+                const index = this.todos.findIndex(t => t.id === id);
+                if (index !== -1) {
+                    this.todos[index] = updated;
+                }
+                this.renderTodos();
+            }
+        } catch (error) {
+            console.error('Failed to update priority:', error);
+        }
+    }
+
     async toggleTodo(id) {
         const todo = this.todos.find(t => t.id === id);
         if (!todo) return;
 
         const newCompleted = !todo.completed;
+        // Completing a todo completes its subtasks as well
+        const cascade = newCompleted && this.todos.some(t => t.parent_id === id);
 
         try {
-            const response = await fetch(`${this.basePath}/api/todos/${id}`, {
+            const response = await this.request(`${this.basePath}/api/todos/${id}`, {
                 method: 'PUT',
                 headers: { 'Content-Type': 'application/json' },
-                body: JSON.stringify({ completed: newCompleted }),
+                body: JSON.stringify({ completed: newCompleted, cascade }),
             });
 
-            if (response.ok) {
+            // Completing a recurring todo creates its next occurrence
+            if (response.ok && (cascade || (newCompleted && todo.recurrence))) {
+                await this.loadTodos();
+            } else if (response.ok) {
                 const updated = await response.json();
                 const index = this.todos.findIndex(t => t.id === id);
                 if (index !== -1) {
@@ -313,14 +793,12 @@ class TodoApp {
         }
 
         try {
-            await fetch(`${this.basePath}/api/todos/${id}`, {
+            await this.request(`${this.basePath}/api/todos/${id}`, {
                 method: 'DELETE',
             });
 
-            setTimeout(() => {
-                this.todos = this.todos.filter(t => t.id !== id);
-                this.renderTodos();
-            }, 300);
+            // Reload, as deleting a todo also deletes its subtasks
+            setTimeout(() => this.loadTodos(), 300);
         } catch (error) {
             console.error('Failed to delete todo:', error);
             if (item) {
@@ -367,16 +845,23 @@ class TodoApp {
 
         if (this.draggedItem === targetId) return;
 
-        const draggedIndex = this.todos.findIndex(t => t.id === this.draggedItem);
-        const targetIndex = this.todos.findIndex(t => t.id === targetId);
+        await this.moveTodo(this.draggedItem, targetId);
+    }
 
-        if (draggedIndex === -1 || targetIndex === -1) return;
+    // Moves a todo to the position of another one with the same parent
+    async moveTodo(draggedId, targetId) {
+        const dragged = this.todos.find(t => t.id === draggedId);
+        const target = this.todos.find(t => t.id === targetId);
+        if (!dragged || !target || dragged.parent_id !== target.parent_id) return;
 
-        const [removed] = this.todos.splice(draggedIndex, 1);
-        this.todos.splice(targetIndex, 0, removed);
+        const siblings = this.todos.filter(t => t.parent_id === dragged.parent_id);
+        const draggedIndex = siblings.indexOf(dragged);
+        const targetIndex = siblings.indexOf(target);
 
-        this.renderTodos();
-        await this.saveOrder();
+        siblings.splice(draggedIndex, 1);
+        siblings.splice(targetIndex, 0, dragged);
+
+        await this.saveOrder(siblings.map(t => t.id));
     }
 
     // Touch drag-and-drop for mobile
@@ -495,15 +980,7 @@ class TodoApp {
 
         // Perform reorder if we have a valid target
         if (targetId !== null) {
-            const draggedIndex = this.todos.findIndex(t => t.id === draggedId);
-            const targetIndex = this.todos.findIndex(t => t.id === targetId);
-
-            if (draggedIndex !== -1 && targetIndex !== -1) {
-                const [removed] = this.todos.splice(draggedIndex, 1);
-                this.todos.splice(targetIndex, 0, removed);
-                this.renderTodos();
-                await this.saveOrder();
-            }
+            await this.moveTodo(draggedId, targetId);
         }
 
         this.touchDragId = null;
@@ -512,35 +989,66 @@ class TodoApp {
         this.touchCurrentY = 0;
     }
 
-    async saveOrder() {
+    async saveOrder(ids) {
         try {
-            const ids = this.todos.map(t => t.id);
-            await fetch(`${this.basePath}/api/todos/reorder`, {
+            const response = await this.request(`${this.basePath}/api/lists/${this.currentListId}/todos/reorder`, {
                 method: 'PUT',
                 headers: { 'Content-Type': 'application/json' },
                 body: JSON.stringify({ ids }),
             });
+
+            if (response.ok) {
+                this.todos = await response.json();
+                this.renderTodos();
+            }
         } catch (error) {
             console.error('Failed to save order:', error);
         }
     }
 
+    async handleAddSubtask(parentId) {
+        const title = (prompt('Unteraufgabe:') || '').trim();
+        if (!title) return;
+
+        try {
+            const response = await this.request(`${this.basePath}/api/todos`, {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({ title, parent_id: parentId }),
+            });
+
+            if (response.ok) {
+                await this.loadTodos();
+            }
+        } catch (error) {
+            console.error('Failed to add subtask:', error);
+        }
+    }
+
     async handleAddTodo(e) {
         e.preventDefault();
 
-        const title = this.todoTitle.value.trim();
+        // "#word" in the title becomes a tag
+        const input = this.todoTitle.value;
+        const tags = [...input.matchAll(/#([^\s#,]+)/g)].map(m => m[1]);
+        const title = input.replace(/#[^\s#,]+/g, '').replace(/\s+/g, ' ').trim();
         if (!title) return;
+        const due_at = this.todoDue.value || null;
+        const recurrence = this.recurrenceRule(this.todoRecurrence.value, due_at);
 
         try {
-            const response = await fetch(`${this.basePath}/api/todos`, {
+            const response = await this.request(`${this.basePath}/api/todos`, {
                 method: 'POST',
                 headers: { 'Content-Type': 'application/json' },
-                body: JSON.stringify({ title }),
+                body: JSON.stringify({ title, due_at, tags, recurrence, list_id: this.currentListId }),
             });
 
             if (response.ok) {
                 const todo = await response.json();
-                this.todos.push(todo);
+                // The change may have come in as an event already
+                if (!this.todos.some(t => t.id === todo.id)) {
+                    this.todos.push(todo);
+                }
                 this.renderTodos();
                 this.addTodoForm.reset();
 
@@ -560,9 +1068,24 @@ class TodoApp {
         }
     }
 
+    // Weekly and monthly todos repeat on the weekday or day of their due date
+    recurrenceRule(type, dueAt) {
+        if (!type) return null;
+
+        const start = dueAt ? new Date(`${dueAt}T00:00:00`) : new Date();
+        switch (type) {
+            case 'weekly':
+                return { type, weekdays: [['sun', 'mon', 'tue', 'wed', 'thu', 'fri', 'sat'][start.getDay()]] };
+            case 'monthly':
+                return { type, day: start.getDate() };
+            default:
+                return { type };
+        }
+    }
+
     async handleLogout() {
         try {
-            await fetch(`${this.basePath}/api/logout`, { method: 'POST' });
+            await this.request(`${this.basePath}/api/logout`, { method: 'POST' });
             window.location.href = `${this.basePath}/login`;
         } catch (error) {
             console.error('Failed to logout:', error);
@@ -571,12 +1094,17 @@ class TodoApp {
 
     async openTokensModal() {
         this.tokensModal.classList.remove('hidden');
-        await this.loadTokens();
+        this.tokenList.innerHTML = '<option value="">All lists</option>' + this.lists.map(list => `
+            <option value="${list.id}">${this.escapeHtml(list.name)}</option>
+        `).join('');
+        this.totpSetup = null;
+        this.recoveryCodes = null;
+        await Promise.all([this.loadTokens(), this.loadSessions(), this.loadTotp()]);
     }
 
     async loadTokens() {
         try {
-            const response = await fetch(`${this.basePath}/api/tokens`);
+            const response = await this.request(`${this.basePath}/api/tokens`);
             if (response.ok) {
                 this.tokens = await response.json();
                 this.renderTokens();
@@ -596,7 +1124,8 @@ class TodoApp {
             <div class="flex items-center justify-between p-3 bg-gray-50 dark:bg-gray-700 rounded-md mb-2">
                 <div class="flex-1 min-w-0">
                     <p class="font-medium text-gray-800 dark:text-gray-100">${this.escapeHtml(token.name || 'Unnamed token')}</p>
-                    <p class="text-xs text-gray-500 dark:text-gray-400 font-mono truncate">${token.token}</p>
+                    <p class="text-xs text-gray-500 dark:text-gray-400 font-mono truncate">${this.escapeHtml(token.prefix)}… · ${this.tokenAccess(token)}</p>
+                    <p class="text-xs ${this.isStaleToken(token) ? 'text-red-600' : 'text-gray-500 dark:text-gray-400'} truncate">${this.tokenUsage(token)}</p>
                 </div>
                 <button
                     onclick="app.revokeToken(${token.id})"
@@ -611,16 +1140,50 @@ class TodoApp {
         `).join('');
     }
 
+    tokenAccess(token) {
+        const scopes = token.scopes.join(', ');
+        const list = token.list_id !== null && this.lists.find(l => l.id === token.list_id);
+        return this.escapeHtml(list ? `${scopes} (${list.name})` : scopes);
+    }
+
+    tokenUsage(token) {
+        const date = (timestamp) => new Date(timestamp * 1000).toLocaleDateString();
+        const parts = [];
+        if (token.last_used_at === null) {
+            parts.push('Never used');
+        } else {
+            const from = [token.last_ip, token.last_user_agent].filter(Boolean).join(', ');
+            parts.push(`Last used ${date(token.last_used_at)}${from ? ` (${from})` : ''}`);
+        }
+        if (token.expires_at !== null) {
+            const expired = token.expires_at * 1000 <= Date.now();
+            parts.push(`${expired ? 'expired' : 'expires'} ${date(token.expires_at)}`);
+        }
+        return this.escapeHtml(parts.join(' · '));
+    }
+
+    // Expired tokens and tokens unused for 90 days are worth revoking
+    isStaleToken(token) {
+        const now = Date.now() / 1000;
+        const lastActivity = token.last_used_at ?? token.created_at;
+        return (token.expires_at !== null && token.expires_at <= now)
+            || now - lastActivity > 90 * 24 * 60 * 60;
+    }
+
     async handleCreateToken(e) {
         e.preventDefault();
 
         const name = this.tokenName.value.trim() || null;
+        const scopes = this.tokenScopes.value.split(' ');
+        const list_id = parseInt(this.tokenList.value) || null;
+        const days = parseInt(this.tokenExpiry.value);
+        const expires_at = days ? Math.floor(Date.now() / 1000) + days * 24 * 60 * 60 : null;
 
         try {
-            const response = await fetch(`${this.basePath}/api/tokens`, {
+            const response = await this.request(`${this.basePath}/api/tokens`, {
                 method: 'POST',
                 headers: { 'Content-Type': 'application/json' },
-                body: JSON.stringify({ name }),
+                body: JSON.stringify({ name, scopes, list_id, expires_at }),
             });
 
             if (response.ok) {
@@ -639,7 +1202,7 @@ class TodoApp {
         if (!confirm('Are you sure you want to revoke this token?')) return;
 
         try {
-            const response = await fetch(`${this.basePath}/api/tokens/${id}`, {
+            const response = await this.request(`${this.basePath}/api/tokens/${id}`, {
                 method: 'DELETE',
             });
 
@@ -652,6 +1215,187 @@ class TodoApp {
         }
     }
 
+    async loadSessions() {
+        try {
+            const response = await this.request(`${this.basePath}/api/sessions`);
+            if (response.ok) {
+                this.sessions = await response.json();
+                this.renderSessions();
+            }
+        } catch (error) {
+            console.error('Failed to load sessions:', error);
+        }
+    }
+
+    renderSessions() {
+        const date = (timestamp) => new Date(timestamp * 1000).toLocaleString([], { dateStyle: 'short', timeStyle: 'short' });
+        this.sessionsList.innerHTML = this.sessions.map(session => `
+            <div class="flex items-center justify-between p-3 bg-gray-50 dark:bg-gray-700 rounded-md mb-2">
+                <div class="flex-1 min-w-0">
+                    <p class="font-medium text-gray-800 dark:text-gray-100 truncate">${this.escapeHtml(session.user_agent || 'Unknown device')}</p>
+                    <p class="text-xs text-gray-500 dark:text-gray-400 truncate">
+                        ${session.current ? 'This session' : `Last seen ${date(session.last_seen_at ?? session.created_at)}`}${session.ip ? ` · ${this.escapeHtml(session.ip)}` : ''}
+                    </p>
+                </div>
+                ${session.current ? '' : `
+                    <button
+                        onclick="app.revokeSession('${session.id}')"
+                        class="ml-2 p-2 text-red-600 hover:bg-red-100 rounded transition-colors"
+                        title="Revoke session"
+                    >
+                        <svg class="w-5 h-5" fill="none" stroke="currentColor" viewBox="0 0 24 24">
+                            <path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M6 18L18 6M6 6l12 12"></path>
+                        </svg>
+                    </button>
+                `}
+            </div>
+        `).join('');
+    }
+
+    async revokeSession(id) {
+        if (!confirm('Are you sure you want to log out this session?')) return;
+
+        try {
+            const response = await this.request(`${this.basePath}/api/sessions/${id}`, {
+                method: 'DELETE',
+            });
+
+            if (response.ok) {
+                this.sessions = this.sessions.filter(s => s.id !== id);
+                this.renderSessions();
+            }
+        } catch (error) {
+            console.error('Failed to revoke session:', error);
+        }
+    }
+
+    async handleLogoutEverywhere() {
+        if (!confirm('Log out of all sessions, including this one?')) return;
+
+        try {
+            await this.request(`${this.basePath}/api/sessions`, { method: 'DELETE' });
+            window.location.href = `${this.basePath}/login`;
+        } catch (error) {
+            console.error('Failed to log out everywhere:', error);
+        }
+    }
+
+    async loadTotp() {
+        try {
+            const response = await this.request(`${this.basePath}/api/totp`);
+            if (response.ok) {
+                this.totp = await response.json();
+                this.renderTotp();
+            }
+        } catch (error) {
+            console.error('Failed to load two-factor status:', error);
+        }
+    }
+
+    renderTotp() {
+        if (this.totpSetup) {
+            this.totpBtn.textContent = 'Cancel';
+            this.totpPanel.innerHTML = `
+                <p class="mb-2">Scan the code with an authenticator app, or enter the key <span class="font-mono">${this.escapeHtml(this.totpSetup.secret)}</span>, then confirm with a code from the app.</p>
+                <div class="mx-auto mb-2 bg-white p-2 rounded-md" style="width: fit-content">${this.totpSetup.qr_code_svg}</div>
+                <form id="totp-enable-form" class="flex gap-2">
+                    <input
+                        type="text"
+                        id="totp-enable-code"
+                        autocomplete="one-time-code"
+                        placeholder="123456"
+                        class="flex-1 min-w-0 px-3 py-2 border border-gray-300 dark:border-gray-600 dark:bg-gray-700 dark:text-gray-100 rounded-md focus:outline-none focus:ring-2 focus:ring-blue-500"
+                    >
+                    <button type="submit" class="flex-shrink-0 px-4 py-2 bg-blue-600 text-white rounded-md hover:bg-blue-700 transition-colors">Confirm</button>
+                </form>
+            `;
+            document.getElementById('totp-enable-form').addEventListener('submit', (e) => this.handleEnableTotp(e));
+            return;
+        }
+
+        this.totpBtn.textContent = this.totp.enabled ? 'Disable' : 'Enable';
+        if (this.recoveryCodes) {
+            this.totpPanel.innerHTML = `
+                <p class="mb-2">Two-factor authentication is enabled. Store these recovery codes somewhere safe; each one logs you in once without the app. They won't be shown again.</p>
+                <p class="font-mono">${this.recoveryCodes.map(code => this.escapeHtml(code)).join('<br>')}</p>
+            `;
+        } else if (this.totp.enabled) {
+            this.totpPanel.textContent = `Enabled · ${this.totp.recovery_codes_left} recovery codes left`;
+        } else {
+            this.totpPanel.textContent = 'Logins only need the password.';
+        }
+    }
+
+    async handleTotpButton() {
+        if (this.totpSetup) {
+            this.totpSetup = null;
+            this.renderTotp();
+        } else if (this.totp.enabled) {
+            await this.handleDisableTotp();
+        } else {
+            await this.handleSetupTotp();
+        }
+    }
+
+    async handleSetupTotp() {
+        try {
+            const response = await this.request(`${this.basePath}/api/totp/setup`, { method: 'POST' });
+            if (response.ok) {
+                this.totpSetup = await response.json();
+                this.recoveryCodes = null;
+                this.renderTotp();
+            }
+        } catch (error) {
+            console.error('Failed to set up two-factor authentication:', error);
+        }
+    }
+
+    async handleEnableTotp(e) {
+        e.preventDefault();
+        const code = document.getElementById('totp-enable-code').value.trim();
+        if (!code) return;
+
+        try {
+            const response = await this.request(`${this.basePath}/api/totp/enable`, {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({ code }),
+            });
+
+            if (response.ok) {
+                this.recoveryCodes = (await response.json()).recovery_codes;
+                this.totpSetup = null;
+                await this.loadTotp();
+            } else {
+                alert('Invalid code. Please try again.');
+            }
+        } catch (error) {
+            console.error('Failed to enable two-factor authentication:', error);
+        }
+    }
+
+    async handleDisableTotp() {
+        const code = (prompt('Enter a code from your authenticator app or a recovery code to disable two-factor authentication:') || '').trim();
+        if (!code) return;
+
+        try {
+            const response = await this.request(`${this.basePath}/api/totp/disable`, {
+                method: 'POST',
+                headers: { 'Content-Type': 'application/json' },
+                body: JSON.stringify({ code }),
+            });
+
+            if (response.ok) {
+                this.recoveryCodes = null;
+                await this.loadTotp();
+            } else {
+                alert('Invalid code.');
+            }
+        } catch (error) {
+            console.error('Failed to disable two-factor authentication:', error);
+        }
+    }
+
     closeModal(modal) {
         modal.classList.add('hidden');
     }
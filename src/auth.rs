@@ -1,6 +1,11 @@
 use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
 use argon2::Argon2;
+use hmac::{Hmac, Mac};
 use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::OnceLock;
 
 pub fn hash_password(password: &str) -> String {
     let mut salt_bytes = [0u8; 16];
@@ -13,6 +18,47 @@ pub fn hash_password(password: &str) -> String {
         .to_string()
 }
 
+/// Where the admin password comes from at startup; exactly one must be given.
+#[derive(Debug, Default)]
+pub struct PasswordSource {
+    pub password: Option<String>,
+    /// An Argon2 hash in PHC string format, as printed by `donezo hash-password`.
+    pub hash: Option<String>,
+    /// A file containing the password, e.g. a Docker or systemd secret.
+    pub file: Option<PathBuf>,
+}
+
+impl PasswordSource {
+    /// Returns the admin password hash, hashing a plain password if needed.
+    pub fn resolve(self) -> Result<String, String> {
+        match (self.password, self.hash, self.file) {
+            (Some(password), None, None) => Ok(hash_password(&password)),
+            (None, Some(hash), None) => {
+                let hash = hash.trim();
+                PasswordHash::new(hash).map_err(|err| format!("invalid password hash: {err}"))?;
+                Ok(hash.to_string())
+            }
+            (None, None, Some(file)) => {
+                let password = std::fs::read_to_string(&file)
+                    .map_err(|err| format!("reading {}: {err}", file.display()))?;
+                let password = password.trim_end_matches(['\n', '\r']);
+                if password.is_empty() {
+                    return Err(format!("{} is empty", file.display()));
+                }
+                Ok(hash_password(password))
+            }
+            (None, None, None) => Err(
+                "one of DONEZO_PASSWORD, DONEZO_PASSWORD_HASH or DONEZO_PASSWORD_FILE must be set"
+                    .to_string(),
+            ),
+            _ => Err(
+                "only one of DONEZO_PASSWORD, DONEZO_PASSWORD_HASH or DONEZO_PASSWORD_FILE may be set"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
 pub fn verify_password(password: &str, hash: &str) -> bool {
     let parsed_hash = match PasswordHash::new(hash) {
         Ok(h) => h,
@@ -23,6 +69,14 @@ pub fn verify_password(password: &str, hash: &str) -> bool {
         .is_ok()
 }
 
+/// Takes as long as `verify_password` for logins with unknown usernames, so
+/// that response times don't reveal which accounts exist.
+pub fn verify_dummy_password(password: &str) {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    let hash = DUMMY_HASH.get_or_init(|| hash_password("dummy"));
+    verify_password(password, hash);
+}
+
 pub fn generate_token() -> String {
     const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
     let mut rng = rand::rng();
@@ -34,6 +88,94 @@ pub fn generate_token() -> String {
         .collect()
 }
 
+/// Number of leading characters of an API token stored in plain text, so that
+/// tokens can be told apart without revealing them.
+pub const TOKEN_PREFIX_LEN: usize = 8;
+
+/// API tokens are long random strings, so a fast unsalted hash is enough to
+/// keep them useless to someone reading the database, while still allowing
+/// lookups by value.
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+pub fn token_prefix(token: &str) -> String {
+    token.chars().take(TOKEN_PREFIX_LEN).collect()
+}
+
+/// Identifies a session without revealing its id, which is the secret kept in
+/// the session cookie.
+pub fn session_public_id(session_id: &str) -> String {
+    hash_token(session_id)[..16].to_string()
+}
+
+/// A single-use code for logging in without the authenticator app, formatted
+/// as `xxxx-xxxx-xxxx` for readability.
+pub fn generate_recovery_code() -> String {
+    const CHARSET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+    let mut rng = rand::rng();
+    (0..3)
+        .map(|_| {
+            (0..4)
+                .map(|_| CHARSET[rng.random_range(0..CHARSET.len())] as char)
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Hashes a recovery code as typed, ignoring case, spaces and dashes.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_token(&normalized)
+}
+
 pub fn generate_session_id() -> String {
     generate_token()
 }
+
+/// Key for deriving the CSRF tokens of browser logins, generated anew on every
+/// start.
+pub struct CsrfKey([u8; 32]);
+
+impl Default for CsrfKey {
+    fn default() -> Self {
+        CsrfKey(rand::random())
+    }
+}
+
+impl CsrfKey {
+    /// The CSRF token of a browser login, identified by `subject`.
+    pub fn token(&self, subject: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes keys of any size");
+        mac.update(subject.as_bytes());
+        to_hex(&mac.finalize().into_bytes())
+    }
+
+    /// Checks `token` in constant time.
+    pub fn verify(&self, subject: &str, token: &str) -> bool {
+        constant_time_eq(&self.token(subject), token)
+    }
+}
+
+/// Compares two secrets without revealing through timing where they differ.
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
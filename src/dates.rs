@@ -0,0 +1,119 @@
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{Date, Duration, OffsetDateTime, UtcOffset};
+
+use crate::models::{Recurrence, Weekday};
+
+/// Normalizes a due date given either as a plain date (`2024-05-01`) or as an
+/// RFC 3339 date-time. Date-times are converted to UTC so that due dates sort
+/// and compare as plain strings in SQLite.
+pub fn parse_due(input: &str) -> Option<String> {
+    let input = input.trim();
+
+    if let Ok(date) = Date::parse(input, format_description!("[year]-[month]-[day]")) {
+        return Some(format_date(date));
+    }
+
+    let datetime = OffsetDateTime::parse(input, &Rfc3339).ok()?;
+    Some(format_datetime(datetime))
+}
+
+/// Seconds since the Unix epoch, the way timestamps are stored.
+pub fn unix_now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+pub fn format_date(date: Date) -> String {
+    date.format(format_description!("[year]-[month]-[day]"))
+        .expect("formatting date")
+}
+
+pub fn format_datetime(datetime: OffsetDateTime) -> String {
+    datetime
+        .to_offset(UtcOffset::UTC)
+        .format(format_description!(
+            "[year]-[month]-[day]T[hour]:[minute]:[second]Z"
+        ))
+        .expect("formatting date-time")
+}
+
+/// Computes the due date of the occurrence following one that was due at
+/// `due_at` and got completed on `today`.
+///
+/// Scheduled rules pick the first matching day after the previous due date
+/// that is not in the past, so an overdue chore does not respawn overdue. The
+/// time of day of a due date-time is kept. Returns `None` if the next due date
+/// would be past the last representable date, which ends the series.
+pub fn next_due(rule: &Recurrence, due_at: Option<&str>, today: Date) -> Option<String> {
+    let (due_date, time_of_day) = match due_at {
+        Some(due_at) if due_at.len() >= 10 => {
+            let (date, time_of_day) = due_at.split_at(10);
+            let date = Date::parse(date, format_description!("[year]-[month]-[day]")).ok();
+            (date, time_of_day)
+        }
+        _ => (None, ""),
+    };
+
+    let date = match rule {
+        Recurrence::AfterCompletion { days } => {
+            today.checked_add(Duration::days(i64::from(*days)))?
+        }
+        _ => {
+            let mut next = following(rule, due_date.unwrap_or(today))?;
+            while next < today {
+                next = following(rule, next)?;
+            }
+            next
+        }
+    };
+
+    Some(format!("{}{}", format_date(date), time_of_day))
+}
+
+/// The first day strictly after `after` matching a rule, if there is one.
+fn following(rule: &Recurrence, after: Date) -> Option<Date> {
+    match rule {
+        Recurrence::Daily => after.checked_add(Duration::days(1)),
+        Recurrence::Weekly { weekdays } => {
+            // `next_occurrence` panics past the last representable date
+            after.checked_add(Duration::weeks(1))?;
+            Some(
+                weekdays
+                    .iter()
+                    .map(|weekday| after.next_occurrence(to_time_weekday(*weekday)))
+                    .min()
+                    .unwrap_or(after + Duration::weeks(1)),
+            )
+        }
+        Recurrence::Monthly { day } => {
+            let this_month = day_in_month(after.year(), after.month(), *day)?;
+            if this_month > after {
+                Some(this_month)
+            } else {
+                let (year, month) = match after.month() {
+                    time::Month::December => (after.year() + 1, time::Month::January),
+                    month => (after.year(), month.next()),
+                };
+                day_in_month(year, month, *day)
+            }
+        }
+        Recurrence::AfterCompletion { days } => after.checked_add(Duration::days(i64::from(*days))),
+    }
+}
+
+fn day_in_month(year: i32, month: time::Month, day: u8) -> Option<Date> {
+    let day = day.clamp(1, month.length(year));
+    Date::from_calendar_date(year, month, day).ok()
+}
+
+fn to_time_weekday(weekday: Weekday) -> time::Weekday {
+    match weekday {
+        Weekday::Mon => time::Weekday::Monday,
+        Weekday::Tue => time::Weekday::Tuesday,
+        Weekday::Wed => time::Weekday::Wednesday,
+        Weekday::Thu => time::Weekday::Thursday,
+        Weekday::Fri => time::Weekday::Friday,
+        Weekday::Sat => time::Weekday::Saturday,
+        Weekday::Sun => time::Weekday::Sunday,
+    }
+}
@@ -1,28 +1,88 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 
-use rusqlite::{Connection, Result};
+use rusqlite::{Connection, OptionalExtension, Result};
 
+use crate::auth::{hash_token, token_prefix};
+use crate::dates;
 use crate::error::AppError;
-use crate::models::{ApiToken, Session, Todo};
+use crate::etag::IfMatch;
+use crate::models::{
+    ApiToken, CreateTodo, DeliveryStatus, DueFilter, ListMember, Priority, Recurrence, Role, Scope,
+    SearchQuery, SearchResult, Session, SyncChanges, Todo, TodoFilter, TodoList, TodoSort,
+    Tombstone, TotpState, UpdateTodo, User, Webhook, WebhookDelivery, WebhookEvent, ADMIN_USERNAME,
+};
 
 pub type DbPool = Arc<Mutex<Connection>>;
 
 pub fn init_db() -> Result<DbPool> {
-    let conn = Connection::open("todos.db")?;
+    init_db_with_connection(Connection::open("todos.db")?)
+}
 
+pub fn init_db_with_connection(conn: Connection) -> Result<DbPool> {
     conn.execute_batch(
         "
+        PRAGMA foreign_keys = ON;
+
+        CREATE TABLE IF NOT EXISTS users (
+            id INTEGER PRIMARY KEY,
+            username TEXT UNIQUE NOT NULL,
+            password_hash TEXT NOT NULL,
+            is_admin INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER DEFAULT (strftime('%s', 'now')),
+            totp_secret TEXT,
+            totp_pending_secret TEXT,
+            totp_last_step INTEGER
+        );
+
+        CREATE TABLE IF NOT EXISTS recovery_codes (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            code_hash TEXT NOT NULL,
+            used_at INTEGER
+        );
+
         CREATE TABLE IF NOT EXISTS sessions (
             id TEXT PRIMARY KEY,
             created_at INTEGER DEFAULT (strftime('%s', 'now')),
-            expires_at INTEGER NOT NULL
+            expires_at INTEGER NOT NULL,
+            user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+            last_seen_at INTEGER,
+            ip TEXT,
+            user_agent TEXT
         );
 
         CREATE TABLE IF NOT EXISTS api_tokens (
             id INTEGER PRIMARY KEY,
-            token TEXT UNIQUE NOT NULL,
+            token_hash TEXT UNIQUE NOT NULL,
+            prefix TEXT NOT NULL,
             name TEXT,
-            created_at INTEGER DEFAULT (strftime('%s', 'now'))
+            scopes TEXT NOT NULL DEFAULT 'todos:read todos:write plain:read',
+            list_id INTEGER REFERENCES lists(id) ON DELETE CASCADE,
+            created_at INTEGER DEFAULT (strftime('%s', 'now')),
+            expires_at INTEGER,
+            last_used_at INTEGER,
+            last_ip TEXT,
+            last_user_agent TEXT,
+            user_id INTEGER REFERENCES users(id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS lists (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            position INTEGER DEFAULT 0,
+            created_at INTEGER DEFAULT (strftime('%s', 'now')),
+            updated_at INTEGER DEFAULT (strftime('%s', 'now')),
+            user_id INTEGER REFERENCES users(id) ON DELETE CASCADE
+        );
+
+        CREATE TABLE IF NOT EXISTS list_members (
+            list_id INTEGER NOT NULL REFERENCES lists(id) ON DELETE CASCADE,
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            role TEXT NOT NULL,
+            created_at INTEGER DEFAULT (strftime('%s', 'now')),
+            seq INTEGER,
+            PRIMARY KEY (list_id, user_id)
         );
 
         CREATE TABLE IF NOT EXISTS todos (
@@ -31,7 +91,63 @@ pub fn init_db() -> Result<DbPool> {
             completed INTEGER DEFAULT 0,
             position INTEGER DEFAULT 0,
             created_at INTEGER DEFAULT (strftime('%s', 'now')),
-            updated_at INTEGER DEFAULT (strftime('%s', 'now'))
+            updated_at INTEGER DEFAULT (strftime('%s', 'now')),
+            list_id INTEGER REFERENCES lists(id) ON DELETE CASCADE,
+            due_at TEXT,
+            parent_id INTEGER REFERENCES todos(id) ON DELETE CASCADE,
+            recurrence TEXT,
+            priority INTEGER NOT NULL DEFAULT 0,
+            notes TEXT,
+            user_id INTEGER REFERENCES users(id) ON DELETE CASCADE,
+            seq INTEGER
+        );
+
+        CREATE TABLE IF NOT EXISTS tags (
+            id INTEGER PRIMARY KEY,
+            name TEXT UNIQUE NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS todo_tags (
+            todo_id INTEGER NOT NULL REFERENCES todos(id) ON DELETE CASCADE,
+            tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+            PRIMARY KEY (todo_id, tag_id)
+        );
+
+        CREATE TABLE IF NOT EXISTS sync_state (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            seq INTEGER NOT NULL DEFAULT 0,
+            pruned_seq INTEGER NOT NULL DEFAULT 0
+        );
+        INSERT OR IGNORE INTO sync_state (id) VALUES (1);
+
+        CREATE TABLE IF NOT EXISTS tombstones (
+            seq INTEGER PRIMARY KEY,
+            todo_id INTEGER NOT NULL,
+            list_id INTEGER NOT NULL,
+            deleted_at INTEGER DEFAULT (strftime('%s', 'now'))
+        );
+
+        CREATE TABLE IF NOT EXISTS webhooks (
+            id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            url TEXT NOT NULL,
+            events TEXT NOT NULL,
+            secret TEXT NOT NULL,
+            created_at INTEGER DEFAULT (strftime('%s', 'now'))
+        );
+
+        CREATE TABLE IF NOT EXISTS webhook_deliveries (
+            id INTEGER PRIMARY KEY,
+            webhook_id INTEGER NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+            event TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'pending',
+            attempts INTEGER NOT NULL DEFAULT 0,
+            response_status INTEGER,
+            error TEXT,
+            created_at INTEGER DEFAULT (strftime('%s', 'now')),
+            last_attempt_at INTEGER,
+            next_attempt_at INTEGER
         );
         ",
     )?;
@@ -52,33 +168,559 @@ pub fn init_db() -> Result<DbPool> {
         )?;
     }
 
+    // Migration: move todos created before lists existed into a default list
+    let has_list_id: bool = conn.prepare("SELECT list_id FROM todos LIMIT 1").is_ok();
+    if !has_list_id {
+        conn.execute(
+            "ALTER TABLE todos ADD COLUMN list_id INTEGER REFERENCES lists(id) ON DELETE CASCADE",
+            [],
+        )?;
+    }
+    let orphans: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM todos WHERE list_id IS NULL",
+        [],
+        |row| row.get(0),
+    )?;
+    if orphans > 0 {
+        conn.execute("INSERT INTO lists (name, position) VALUES ('Todos', 1)", [])?;
+        let list_id = conn.last_insert_rowid();
+        conn.execute(
+            "UPDATE todos SET list_id = ?1 WHERE list_id IS NULL",
+            [list_id],
+        )?;
+    }
+
+    // Migration: add due_at column if it doesn't exist
+    let has_due_at: bool = conn.prepare("SELECT due_at FROM todos LIMIT 1").is_ok();
+    if !has_due_at {
+        conn.execute("ALTER TABLE todos ADD COLUMN due_at TEXT", [])?;
+    }
+
+    // Migration: add parent_id column if it doesn't exist
+    let has_parent_id: bool = conn.prepare("SELECT parent_id FROM todos LIMIT 1").is_ok();
+    if !has_parent_id {
+        conn.execute(
+            "ALTER TABLE todos ADD COLUMN parent_id INTEGER REFERENCES todos(id) ON DELETE CASCADE",
+            [],
+        )?;
+    }
+
+    // Migration: add recurrence column if it doesn't exist
+    let has_recurrence: bool = conn.prepare("SELECT recurrence FROM todos LIMIT 1").is_ok();
+    if !has_recurrence {
+        conn.execute("ALTER TABLE todos ADD COLUMN recurrence TEXT", [])?;
+    }
+
+    // Migration: add priority column if it doesn't exist
+    let has_priority: bool = conn.prepare("SELECT priority FROM todos LIMIT 1").is_ok();
+    if !has_priority {
+        conn.execute(
+            "ALTER TABLE todos ADD COLUMN priority INTEGER NOT NULL DEFAULT 0",
+            [],
+        )?;
+    }
+
+    // Migration: add notes column if it doesn't exist
+    let has_notes: bool = conn.prepare("SELECT notes FROM todos LIMIT 1").is_ok();
+    if !has_notes {
+        conn.execute("ALTER TABLE todos ADD COLUMN notes TEXT", [])?;
+    }
+
+    // Migration: add user_id columns if they don't exist. Existing rows are
+    // handed to the admin user by `bootstrap_admin`.
+    for table in ["sessions", "api_tokens", "lists", "todos"] {
+        let has_user_id: bool = conn
+            .prepare(&format!("SELECT user_id FROM {table} LIMIT 1"))
+            .is_ok();
+        if !has_user_id {
+            conn.execute(
+                &format!(
+                    "ALTER TABLE {table} ADD COLUMN user_id INTEGER
+                     REFERENCES users(id) ON DELETE CASCADE"
+                ),
+                [],
+            )?;
+        }
+    }
+
+    // Migration: API tokens used to be stored in plain text. Keep the column,
+    // but replace each token by its hash and remember its prefix.
+    let has_token_hash: bool = conn
+        .prepare("SELECT token_hash FROM api_tokens LIMIT 1")
+        .is_ok();
+    if !has_token_hash {
+        conn.execute_batch(
+            "
+            ALTER TABLE api_tokens RENAME COLUMN token TO token_hash;
+            ALTER TABLE api_tokens ADD COLUMN prefix TEXT NOT NULL DEFAULT '';
+            ",
+        )?;
+        let plaintext: Vec<(i64, String)> = conn
+            .prepare("SELECT id, token_hash FROM api_tokens")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        for (id, token) in plaintext {
+            conn.execute(
+                "UPDATE api_tokens SET token_hash = ?1, prefix = ?2 WHERE id = ?3",
+                (hash_token(&token), token_prefix(&token), id),
+            )?;
+        }
+    }
+
+    // Migration: add token scopes if they don't exist. Existing tokens keep
+    // full access.
+    let has_scopes: bool = conn
+        .prepare("SELECT scopes FROM api_tokens LIMIT 1")
+        .is_ok();
+    if !has_scopes {
+        conn.execute_batch(
+            "
+            ALTER TABLE api_tokens
+                ADD COLUMN scopes TEXT NOT NULL DEFAULT 'todos:read todos:write plain:read';
+            ALTER TABLE api_tokens
+                ADD COLUMN list_id INTEGER REFERENCES lists(id) ON DELETE CASCADE;
+            ",
+        )?;
+    }
+
+    // Migration: add two-factor columns if they don't exist
+    for (column, definition) in [
+        ("totp_secret", "TEXT"),
+        ("totp_pending_secret", "TEXT"),
+        ("totp_last_step", "INTEGER"),
+    ] {
+        let has_column: bool = conn
+            .prepare(&format!("SELECT {column} FROM users LIMIT 1"))
+            .is_ok();
+        if !has_column {
+            conn.execute(
+                &format!("ALTER TABLE users ADD COLUMN {column} {definition}"),
+                [],
+            )?;
+        }
+    }
+
+    // Migration: add session activity columns if they don't exist
+    for (column, definition) in [
+        ("last_seen_at", "INTEGER"),
+        ("ip", "TEXT"),
+        ("user_agent", "TEXT"),
+    ] {
+        let has_column: bool = conn
+            .prepare(&format!("SELECT {column} FROM sessions LIMIT 1"))
+            .is_ok();
+        if !has_column {
+            conn.execute(
+                &format!("ALTER TABLE sessions ADD COLUMN {column} {definition}"),
+                [],
+            )?;
+        }
+    }
+
+    // Migration: add token expiry and usage columns if they don't exist
+    for (column, definition) in [
+        ("expires_at", "INTEGER"),
+        ("last_used_at", "INTEGER"),
+        ("last_ip", "TEXT"),
+        ("last_user_agent", "TEXT"),
+    ] {
+        let has_column: bool = conn
+            .prepare(&format!("SELECT {column} FROM api_tokens LIMIT 1"))
+            .is_ok();
+        if !has_column {
+            conn.execute(
+                &format!("ALTER TABLE api_tokens ADD COLUMN {column} {definition}"),
+                [],
+            )?;
+        }
+    }
+
+    // Migration: add sync sequence columns if they don't exist. Existing rows
+    // are only sent to clients syncing from scratch.
+    for table in ["todos", "list_members"] {
+        let has_seq: bool = conn
+            .prepare(&format!("SELECT seq FROM {table} LIMIT 1"))
+            .is_ok();
+        if !has_seq {
+            conn.execute(&format!("ALTER TABLE {table} ADD COLUMN seq INTEGER"), [])?;
+        }
+    }
+
+    // Every change to a todo gives it the next sequence number, and deleting
+    // it or moving it to another list leaves a tombstone in its old list, so
+    // that `sync_changes` can tell clients what changed since their last sync.
+    // Adding a member numbers the membership, so that the new member gets all
+    // todos of the list.
+    conn.execute_batch(
+        "
+        CREATE TRIGGER IF NOT EXISTS todos_seq_insert AFTER INSERT ON todos BEGIN
+            UPDATE sync_state SET seq = seq + 1;
+            UPDATE todos SET seq = (SELECT seq FROM sync_state) WHERE id = new.id;
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS todos_seq_update AFTER UPDATE ON todos
+        WHEN new.seq IS old.seq BEGIN
+            UPDATE sync_state SET seq = seq + 1 WHERE new.list_id IS NOT old.list_id;
+            INSERT INTO tombstones (seq, todo_id, list_id)
+            SELECT seq, old.id, old.list_id FROM sync_state
+            WHERE new.list_id IS NOT old.list_id;
+            UPDATE sync_state SET seq = seq + 1;
+            UPDATE todos SET seq = (SELECT seq FROM sync_state) WHERE id = new.id;
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS todos_seq_delete AFTER DELETE ON todos BEGIN
+            UPDATE sync_state SET seq = seq + 1;
+            INSERT INTO tombstones (seq, todo_id, list_id)
+            SELECT seq, old.id, old.list_id FROM sync_state;
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS list_members_seq_insert AFTER INSERT ON list_members BEGIN
+            UPDATE sync_state SET seq = seq + 1;
+            UPDATE list_members SET seq = (SELECT seq FROM sync_state)
+            WHERE list_id = new.list_id AND user_id = new.user_id;
+        END;
+        ",
+    )?;
+
+    // Full-text index over todo titles and notes, kept in sync by triggers.
+    // Indexes created before notes existed only cover titles and are rebuilt.
+    let has_fts: bool = conn.prepare("SELECT notes FROM todos_fts LIMIT 1").is_ok();
+    if !has_fts {
+        conn.execute_batch(
+            "
+            DROP TRIGGER IF EXISTS todos_fts_insert;
+            DROP TRIGGER IF EXISTS todos_fts_delete;
+            DROP TRIGGER IF EXISTS todos_fts_update;
+            DROP TABLE IF EXISTS todos_fts;
+            ",
+        )?;
+    }
+    conn.execute_batch(
+        "
+        CREATE VIRTUAL TABLE IF NOT EXISTS todos_fts USING fts5(
+            title,
+            notes,
+            content = 'todos',
+            content_rowid = 'id',
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+
+        CREATE TRIGGER IF NOT EXISTS todos_fts_insert AFTER INSERT ON todos BEGIN
+            INSERT INTO todos_fts (rowid, title, notes) VALUES (new.id, new.title, new.notes);
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS todos_fts_delete AFTER DELETE ON todos BEGIN
+            INSERT INTO todos_fts (todos_fts, rowid, title, notes)
+            VALUES ('delete', old.id, old.title, old.notes);
+        END;
+
+        CREATE TRIGGER IF NOT EXISTS todos_fts_update AFTER UPDATE OF title, notes ON todos BEGIN
+            INSERT INTO todos_fts (todos_fts, rowid, title, notes)
+            VALUES ('delete', old.id, old.title, old.notes);
+            INSERT INTO todos_fts (rowid, title, notes) VALUES (new.id, new.title, new.notes);
+        END;
+        ",
+    )?;
+    if !has_fts {
+        conn.execute("INSERT INTO todos_fts (todos_fts) VALUES ('rebuild')", [])?;
+    }
+
     Ok(Arc::new(Mutex::new(conn)))
 }
 
+// User operations
+const USER_COLUMNS: &str = "id, username, is_admin, totp_secret IS NOT NULL, created_at";
+
+fn row_to_user(row: &rusqlite::Row) -> Result<User> {
+    Ok(User {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        is_admin: row.get::<_, i32>(2)? != 0,
+        totp_enabled: row.get(3)?,
+        created_at: row.get(4)?,
+    })
+}
+
+/// Creates or updates the admin user with the password given at startup, and
+/// hands it everything created before there were users.
+pub fn bootstrap_admin(pool: &DbPool, password_hash: &str) -> Result<User, AppError> {
+    let conn = pool.lock().unwrap();
+
+    conn.execute(
+        "INSERT INTO users (username, password_hash, is_admin) VALUES (?1, ?2, 1)
+         ON CONFLICT (username) DO UPDATE SET password_hash = excluded.password_hash,
+                                              is_admin = 1",
+        (ADMIN_USERNAME, password_hash),
+    )?;
+    let admin = conn.query_row(
+        &format!("SELECT {USER_COLUMNS} FROM users WHERE username = ?1"),
+        [ADMIN_USERNAME],
+        row_to_user,
+    )?;
+
+    for table in ["sessions", "api_tokens", "lists", "todos"] {
+        conn.execute(
+            &format!("UPDATE {table} SET user_id = ?1 WHERE user_id IS NULL"),
+            [admin.id],
+        )?;
+    }
+
+    Ok(admin)
+}
+
+/// Returns `None` if the username is already taken.
+pub fn create_user(
+    pool: &DbPool,
+    username: &str,
+    password_hash: &str,
+    is_admin: bool,
+) -> Result<Option<User>, AppError> {
+    let conn = pool.lock().unwrap();
+    let inserted = conn.execute(
+        "INSERT INTO users (username, password_hash, is_admin) VALUES (?1, ?2, ?3)
+         ON CONFLICT (username) DO NOTHING",
+        (username, password_hash, is_admin as i32),
+    )?;
+    if inserted == 0 {
+        return Ok(None);
+    }
+
+    let user = conn.query_row(
+        &format!("SELECT {USER_COLUMNS} FROM users WHERE id = ?1"),
+        [conn.last_insert_rowid()],
+        row_to_user,
+    )?;
+    Ok(Some(user))
+}
+
+pub fn get_user(pool: &DbPool, id: i64) -> Result<Option<User>, AppError> {
+    let conn = pool.lock().unwrap();
+    let user = conn
+        .query_row(
+            &format!("SELECT {USER_COLUMNS} FROM users WHERE id = ?1"),
+            [id],
+            row_to_user,
+        )
+        .optional()?;
+    Ok(user)
+}
+
+pub fn get_user_by_name(pool: &DbPool, username: &str) -> Result<Option<User>, AppError> {
+    let conn = pool.lock().unwrap();
+    let user = conn
+        .query_row(
+            &format!("SELECT {USER_COLUMNS} FROM users WHERE username = ?1"),
+            [username],
+            row_to_user,
+        )
+        .optional()?;
+    Ok(user)
+}
+
+/// Looks up a user by name along with their password hash.
+pub fn get_user_credentials(
+    pool: &DbPool,
+    username: &str,
+) -> Result<Option<(User, String)>, AppError> {
+    let conn = pool.lock().unwrap();
+    let credentials = conn
+        .query_row(
+            &format!("SELECT {USER_COLUMNS}, password_hash FROM users WHERE username = ?1"),
+            [username],
+            |row| Ok((row_to_user(row)?, row.get(5)?)),
+        )
+        .optional()?;
+    Ok(credentials)
+}
+
+pub fn list_users(pool: &DbPool) -> Result<Vec<User>, AppError> {
+    let conn = pool.lock().unwrap();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {USER_COLUMNS} FROM users ORDER BY username ASC"
+    ))?;
+    let users = stmt
+        .query_map([], row_to_user)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(users)
+}
+
+/// Deletes a user together with all of their lists, todos, sessions and tokens.
+pub fn delete_user(pool: &DbPool, id: i64) -> Result<bool, AppError> {
+    let conn = pool.lock().unwrap();
+    let rows = conn.execute("DELETE FROM users WHERE id = ?1", [id])?;
+    Ok(rows > 0)
+}
+
+// Two-factor operations
+pub fn get_totp_state(pool: &DbPool, user_id: i64) -> Result<TotpState, AppError> {
+    let conn = pool.lock().unwrap();
+    let state = conn
+        .query_row(
+            "SELECT totp_secret, totp_pending_secret, totp_last_step FROM users WHERE id = ?1",
+            [user_id],
+            |row| {
+                Ok(TotpState {
+                    secret: row.get(0)?,
+                    pending_secret: row.get(1)?,
+                    last_step: row.get(2)?,
+                })
+            },
+        )
+        .optional()?;
+    Ok(state.unwrap_or_default())
+}
+
+/// Stores a secret that becomes active once confirmed by `enable_totp`.
+pub fn set_pending_totp_secret(pool: &DbPool, user_id: i64, secret: &str) -> Result<(), AppError> {
+    let conn = pool.lock().unwrap();
+    conn.execute(
+        "UPDATE users SET totp_pending_secret = ?1 WHERE id = ?2",
+        (secret, user_id),
+    )?;
+    Ok(())
+}
+
+/// Activates the pending secret and replaces all recovery codes.
+pub fn enable_totp(
+    pool: &DbPool,
+    user_id: i64,
+    last_step: i64,
+    recovery_code_hashes: &[String],
+) -> Result<(), AppError> {
+    let mut conn = pool.lock().unwrap();
+    let tx = conn.transaction()?;
+    tx.execute(
+        "UPDATE users SET totp_secret = totp_pending_secret, totp_pending_secret = NULL,
+         totp_last_step = ?1 WHERE id = ?2",
+        (last_step, user_id),
+    )?;
+    tx.execute("DELETE FROM recovery_codes WHERE user_id = ?1", [user_id])?;
+    for hash in recovery_code_hashes {
+        tx.execute(
+            "INSERT INTO recovery_codes (user_id, code_hash) VALUES (?1, ?2)",
+            (user_id, hash),
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn disable_totp(pool: &DbPool, user_id: i64) -> Result<(), AppError> {
+    let conn = pool.lock().unwrap();
+    conn.execute(
+        "UPDATE users SET totp_secret = NULL, totp_pending_secret = NULL, totp_last_step = NULL
+         WHERE id = ?1",
+        [user_id],
+    )?;
+    conn.execute("DELETE FROM recovery_codes WHERE user_id = ?1", [user_id])?;
+    Ok(())
+}
+
+/// Remembers the time step of an accepted code so it cannot be used again.
+pub fn record_totp_step(pool: &DbPool, user_id: i64, step: i64) -> Result<(), AppError> {
+    let conn = pool.lock().unwrap();
+    conn.execute(
+        "UPDATE users SET totp_last_step = ?1 WHERE id = ?2",
+        (step, user_id),
+    )?;
+    Ok(())
+}
+
+/// Marks an unused recovery code as used. Returns `false` if there is none.
+pub fn use_recovery_code(pool: &DbPool, user_id: i64, code_hash: &str) -> Result<bool, AppError> {
+    let conn = pool.lock().unwrap();
+    let rows = conn.execute(
+        "UPDATE recovery_codes SET used_at = strftime('%s', 'now')
+         WHERE user_id = ?1 AND code_hash = ?2 AND used_at IS NULL",
+        (user_id, code_hash),
+    )?;
+    Ok(rows > 0)
+}
+
+pub fn count_recovery_codes(pool: &DbPool, user_id: i64) -> Result<i64, AppError> {
+    let conn = pool.lock().unwrap();
+    let count = conn.query_row(
+        "SELECT COUNT(*) FROM recovery_codes WHERE user_id = ?1 AND used_at IS NULL",
+        [user_id],
+        |row| row.get(0),
+    )?;
+    Ok(count)
+}
+
 // Session operations
+const SESSION_COLUMNS: &str = "id, user_id, created_at, expires_at, last_seen_at, ip, user_agent";
+
+fn row_to_session(row: &rusqlite::Row) -> rusqlite::Result<Session> {
+    Ok(Session {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        created_at: row.get(2)?,
+        expires_at: row.get(3)?,
+        last_seen_at: row.get(4)?,
+        ip: row.get(5)?,
+        user_agent: row.get(6)?,
+    })
+}
+
 pub fn create_session(pool: &DbPool, session: &Session) -> Result<(), AppError> {
     let conn = pool.lock().unwrap();
     conn.execute(
-        "INSERT INTO sessions (id, expires_at) VALUES (?1, ?2)",
-        (&session.id, session.expires_at),
+        "INSERT INTO sessions (id, user_id, created_at, expires_at, last_seen_at, ip, user_agent)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        (
+            &session.id,
+            session.user_id,
+            session.created_at,
+            session.expires_at,
+            session.last_seen_at,
+            &session.ip,
+            &session.user_agent,
+        ),
     )?;
     Ok(())
 }
 
 pub fn get_session(pool: &DbPool, id: &str) -> Result<Option<Session>, AppError> {
     let conn = pool.lock().unwrap();
-    let mut stmt = conn.prepare("SELECT id, created_at, expires_at FROM sessions WHERE id = ?1")?;
-    let mut rows = stmt.query([id])?;
+    let session = conn
+        .query_row(
+            &format!("SELECT {SESSION_COLUMNS} FROM sessions WHERE id = ?1"),
+            [id],
+            row_to_session,
+        )
+        .optional()?;
+    Ok(session)
+}
 
-    if let Some(row) = rows.next()? {
-        Ok(Some(Session {
-            id: row.get(0)?,
-            created_at: row.get(1)?,
-            expires_at: row.get(2)?,
-        }))
-    } else {
-        Ok(None)
-    }
+/// Records activity on a session. To avoid a write on every request, this is
+/// skipped if the session was seen within the last minute.
+pub fn touch_session(
+    pool: &DbPool,
+    id: &str,
+    ip: Option<&str>,
+    user_agent: Option<&str>,
+) -> Result<(), AppError> {
+    let conn = pool.lock().unwrap();
+    conn.execute(
+        "UPDATE sessions SET last_seen_at = strftime('%s', 'now'), ip = ?1, user_agent = ?2
+         WHERE id = ?3 AND (last_seen_at IS NULL OR last_seen_at < strftime('%s', 'now') - 60)",
+        (ip, user_agent, id),
+    )?;
+    Ok(())
+}
+
+/// Unexpired sessions of a user, most recently active first.
+pub fn list_sessions(pool: &DbPool, user_id: i64) -> Result<Vec<Session>, AppError> {
+    let conn = pool.lock().unwrap();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {SESSION_COLUMNS} FROM sessions
+         WHERE user_id = ?1 AND expires_at > strftime('%s', 'now')
+         ORDER BY COALESCE(last_seen_at, created_at) DESC"
+    ))?;
+    let sessions = stmt
+        .query_map([user_id], row_to_session)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(sessions)
 }
 
 pub fn delete_session(pool: &DbPool, id: &str) -> Result<(), AppError> {
@@ -87,181 +729,750 @@ pub fn delete_session(pool: &DbPool, id: &str) -> Result<(), AppError> {
     Ok(())
 }
 
+/// Logs a user out everywhere.
+pub fn delete_user_sessions(pool: &DbPool, user_id: i64) -> Result<(), AppError> {
+    let conn = pool.lock().unwrap();
+    conn.execute("DELETE FROM sessions WHERE user_id = ?1", [user_id])?;
+    Ok(())
+}
+
 pub fn cleanup_expired_sessions(pool: &DbPool) -> Result<(), AppError> {
     let conn = pool.lock().unwrap();
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as i64;
+    let now = dates::unix_now();
     conn.execute("DELETE FROM sessions WHERE expires_at < ?1", [now])?;
     Ok(())
 }
 
 // API Token operations
+const TOKEN_COLUMNS: &str = "id, user_id, prefix, name, scopes, list_id, created_at, expires_at,
+     last_used_at, last_ip, last_user_agent";
+
+fn row_to_token(row: &rusqlite::Row) -> rusqlite::Result<ApiToken> {
+    let scopes: String = row.get(4)?;
+    Ok(ApiToken {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        prefix: row.get(2)?,
+        name: row.get(3)?,
+        scopes: scopes.split_whitespace().filter_map(Scope::parse).collect(),
+        list_id: row.get(5)?,
+        created_at: row.get(6)?,
+        expires_at: row.get(7)?,
+        last_used_at: row.get(8)?,
+        last_ip: row.get(9)?,
+        last_user_agent: row.get(10)?,
+    })
+}
+
+/// Stores the hash of `token`; the token itself cannot be read back later.
 pub fn create_api_token(
     pool: &DbPool,
+    user_id: i64,
     token: &str,
     name: Option<&str>,
+    scopes: &[Scope],
+    list_id: Option<i64>,
+    expires_at: Option<i64>,
 ) -> Result<ApiToken, AppError> {
+    let scopes: Vec<&str> = scopes.iter().map(|scope| scope.as_str()).collect();
     let conn = pool.lock().unwrap();
     conn.execute(
-        "INSERT INTO api_tokens (token, name) VALUES (?1, ?2)",
-        (token, name),
+        "INSERT INTO api_tokens (user_id, token_hash, prefix, name, scopes, list_id, expires_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        (
+            user_id,
+            hash_token(token),
+            token_prefix(token),
+            name,
+            scopes.join(" "),
+            list_id,
+            expires_at,
+        ),
     )?;
     let id = conn.last_insert_rowid();
 
-    let mut stmt =
-        conn.prepare("SELECT id, token, name, created_at FROM api_tokens WHERE id = ?1")?;
-    let token = stmt.query_row([id], |row| {
-        Ok(ApiToken {
-            id: row.get(0)?,
-            token: row.get(1)?,
-            name: row.get(2)?,
-            created_at: row.get(3)?,
-        })
-    })?;
-
+    let token = conn.query_row(
+        &format!("SELECT {TOKEN_COLUMNS} FROM api_tokens WHERE id = ?1"),
+        [id],
+        row_to_token,
+    )?;
     Ok(token)
 }
 
 pub fn get_api_token_by_value(pool: &DbPool, token: &str) -> Result<Option<ApiToken>, AppError> {
     let conn = pool.lock().unwrap();
-    let mut stmt =
-        conn.prepare("SELECT id, token, name, created_at FROM api_tokens WHERE token = ?1")?;
-    let mut rows = stmt.query([token])?;
+    let token = conn
+        .query_row(
+            &format!("SELECT {TOKEN_COLUMNS} FROM api_tokens WHERE token_hash = ?1"),
+            [hash_token(token)],
+            row_to_token,
+        )
+        .optional()?;
+    Ok(token)
+}
 
-    if let Some(row) = rows.next()? {
-        Ok(Some(ApiToken {
-            id: row.get(0)?,
-            token: row.get(1)?,
-            name: row.get(2)?,
-            created_at: row.get(3)?,
-        }))
-    } else {
-        Ok(None)
-    }
+pub fn get_api_token(pool: &DbPool, id: i64) -> Result<Option<ApiToken>, AppError> {
+    let conn = pool.lock().unwrap();
+    let token = conn
+        .query_row(
+            &format!("SELECT {TOKEN_COLUMNS} FROM api_tokens WHERE id = ?1"),
+            [id],
+            row_to_token,
+        )
+        .optional()?;
+    Ok(token)
 }
 
-pub fn list_api_tokens(pool: &DbPool) -> Result<Vec<ApiToken>, AppError> {
+pub fn list_api_tokens(pool: &DbPool, user_id: i64) -> Result<Vec<ApiToken>, AppError> {
     let conn = pool.lock().unwrap();
-    let mut stmt = conn
-        .prepare("SELECT id, token, name, created_at FROM api_tokens ORDER BY created_at DESC")?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {TOKEN_COLUMNS} FROM api_tokens WHERE user_id = ?1
+         ORDER BY created_at DESC"
+    ))?;
     let tokens = stmt
-        .query_map([], |row| {
-            Ok(ApiToken {
-                id: row.get(0)?,
-                token: row.get(1)?,
-                name: row.get(2)?,
-                created_at: row.get(3)?,
-            })
-        })?
+        .query_map([user_id], row_to_token)?
         .collect::<Result<Vec<_>, _>>()?;
     Ok(tokens)
 }
 
-pub fn delete_api_token(pool: &DbPool, id: i64) -> Result<bool, AppError> {
+/// Remembers when and from where a token was last used.
+pub fn record_api_token_use(
+    pool: &DbPool,
+    id: i64,
+    ip: Option<&str>,
+    user_agent: Option<&str>,
+) -> Result<(), AppError> {
     let conn = pool.lock().unwrap();
-    let rows = conn.execute("DELETE FROM api_tokens WHERE id = ?1", [id])?;
+    conn.execute(
+        "UPDATE api_tokens SET last_used_at = strftime('%s', 'now'), last_ip = ?1,
+         last_user_agent = ?2 WHERE id = ?3",
+        (ip, user_agent, id),
+    )?;
+    Ok(())
+}
+
+pub fn delete_api_token(pool: &DbPool, user_id: i64, id: i64) -> Result<bool, AppError> {
+    let conn = pool.lock().unwrap();
+    let rows = conn.execute(
+        "DELETE FROM api_tokens WHERE id = ?1 AND user_id = ?2",
+        (id, user_id),
+    )?;
     Ok(rows > 0)
 }
 
-// Todo operations
-pub fn create_todo(pool: &DbPool, title: &str) -> Result<Todo, AppError> {
+// List operations
+
+/// Lists the user bound to `?1` owns or is a member of. Ends in a `WHERE`
+/// clause that further conditions can be appended to with `AND`.
+const VISIBLE_LISTS: &str = "lists
+    LEFT JOIN list_members ON list_members.list_id = lists.id AND list_members.user_id = ?1
+    WHERE (lists.user_id = ?1 OR list_members.user_id IS NOT NULL)";
+const LIST_COLUMNS: &str = "lists.id, lists.name, lists.position, lists.created_at,
+    lists.updated_at, CASE WHEN lists.user_id = ?1 THEN 'owner' ELSE list_members.role END";
+
+fn row_to_list(row: &rusqlite::Row) -> Result<TodoList> {
+    Ok(TodoList {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        position: row.get(2)?,
+        created_at: row.get(3)?,
+        updated_at: row.get(4)?,
+        role: Role::parse(&row.get::<_, String>(5)?),
+    })
+}
+
+/// Returns the id of the user's first own list, creating one if there are none yet.
+fn default_list_id(conn: &Connection, user_id: i64) -> Result<i64> {
+    let existing = conn.query_row(
+        "SELECT id FROM lists WHERE user_id = ?1 ORDER BY position ASC, id ASC LIMIT 1",
+        [user_id],
+        |row| row.get(0),
+    );
+
+    match existing {
+        Ok(id) => Ok(id),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            conn.execute(
+                "INSERT INTO lists (user_id, name, position) VALUES (?1, 'Todos', 1)",
+                [user_id],
+            )?;
+            Ok(conn.last_insert_rowid())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+pub fn create_list(pool: &DbPool, user_id: i64, name: &str) -> Result<TodoList, AppError> {
     let conn = pool.lock().unwrap();
 
-    // Get max position and add 1
     let max_pos: i64 = conn
-        .query_row("SELECT COALESCE(MAX(position), 0) FROM todos", [], |row| {
-            row.get(0)
-        })
+        .query_row(
+            "SELECT COALESCE(MAX(position), 0) FROM lists WHERE user_id = ?1",
+            [user_id],
+            |row| row.get(0),
+        )
         .unwrap_or(0);
 
     conn.execute(
-        "INSERT INTO todos (title, position) VALUES (?1, ?2)",
-        (title, max_pos + 1),
+        "INSERT INTO lists (user_id, name, position) VALUES (?1, ?2, ?3)",
+        (user_id, name, max_pos + 1),
     )?;
     let id = conn.last_insert_rowid();
 
-    let mut stmt = conn.prepare(
-        "SELECT id, title, completed, position, created_at, updated_at FROM todos WHERE id = ?1",
+    let list = get_list_internal(&conn, user_id, id)?.ok_or(AppError::NotFound)?;
+    Ok(list)
+}
+
+/// Lists the user's own lists followed by the ones shared with them.
+pub fn list_lists(pool: &DbPool, user_id: i64) -> Result<Vec<TodoList>, AppError> {
+    let conn = pool.lock().unwrap();
+    list_lists_internal(&conn, user_id)
+}
+
+fn list_lists_internal(conn: &Connection, user_id: i64) -> Result<Vec<TodoList>, AppError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {LIST_COLUMNS} FROM {VISIBLE_LISTS}
+         ORDER BY lists.user_id = ?1 DESC, lists.position ASC, lists.id ASC"
+    ))?;
+    let lists = stmt
+        .query_map([user_id], row_to_list)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(lists)
+}
+
+pub fn get_list(pool: &DbPool, user_id: i64, id: i64) -> Result<Option<TodoList>, AppError> {
+    let conn = pool.lock().unwrap();
+    get_list_internal(&conn, user_id, id)
+}
+
+fn get_list_internal(
+    conn: &Connection,
+    user_id: i64,
+    id: i64,
+) -> Result<Option<TodoList>, AppError> {
+    let list = conn
+        .query_row(
+            &format!("SELECT {LIST_COLUMNS} FROM {VISIBLE_LISTS} AND lists.id = ?2"),
+            (user_id, id),
+            row_to_list,
+        )
+        .optional()?;
+    Ok(list)
+}
+
+/// The user's role on a list, or `None` if they cannot see it.
+pub fn list_role(pool: &DbPool, user_id: i64, list_id: i64) -> Result<Option<Role>, AppError> {
+    let conn = pool.lock().unwrap();
+    Ok(get_list_internal(&conn, user_id, list_id)?.map(|list| list.role))
+}
+
+/// The user's role on the list a todo is in, or `None` if they cannot see it.
+pub fn todo_role(pool: &DbPool, user_id: i64, todo_id: i64) -> Result<Option<Role>, AppError> {
+    let conn = pool.lock().unwrap();
+    let list_id: Option<i64> = conn
+        .query_row(
+            "SELECT list_id FROM todos WHERE id = ?1",
+            [todo_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    match list_id {
+        Some(list_id) => Ok(get_list_internal(&conn, user_id, list_id)?.map(|list| list.role)),
+        None => Ok(None),
+    }
+}
+
+/// Renames a list owned by the user.
+pub fn rename_list(
+    pool: &DbPool,
+    user_id: i64,
+    id: i64,
+    name: &str,
+) -> Result<Option<TodoList>, AppError> {
+    let conn = pool.lock().unwrap();
+    conn.execute(
+        "UPDATE lists SET name = ?1, updated_at = strftime('%s', 'now')
+         WHERE id = ?2 AND user_id = ?3",
+        (name, id, user_id),
     )?;
-    let todo = stmt.query_row([id], |row| {
-        Ok(Todo {
-            id: row.get(0)?,
-            title: row.get(1)?,
-            completed: row.get::<_, i32>(2)? != 0,
-            position: row.get(3)?,
-            created_at: row.get(4)?,
-            updated_at: row.get(5)?,
-        })
-    })?;
+    get_list_internal(&conn, user_id, id)
+}
 
-    Ok(todo)
+/// Deletes a list owned by the user.
+pub fn delete_list(pool: &DbPool, user_id: i64, id: i64) -> Result<bool, AppError> {
+    let conn = pool.lock().unwrap();
+    let rows = conn.execute(
+        "DELETE FROM lists WHERE id = ?1 AND user_id = ?2",
+        (id, user_id),
+    )?;
+    Ok(rows > 0)
 }
 
-pub fn list_todos(pool: &DbPool) -> Result<Vec<Todo>, AppError> {
+// List member operations
+
+/// Everyone with access to a list, the owner first.
+pub fn list_members(pool: &DbPool, list_id: i64) -> Result<Vec<ListMember>, AppError> {
     let conn = pool.lock().unwrap();
     let mut stmt = conn.prepare(
-        "SELECT id, title, completed, position, created_at, updated_at FROM todos ORDER BY position ASC",
+        "SELECT users.id, users.username, 'owner' FROM lists
+         JOIN users ON users.id = lists.user_id
+         WHERE lists.id = ?1
+         UNION ALL
+         SELECT * FROM (
+             SELECT users.id, users.username, list_members.role FROM list_members
+             JOIN users ON users.id = list_members.user_id
+             WHERE list_members.list_id = ?1
+             ORDER BY users.username ASC
+         )",
     )?;
-    let todos = stmt
-        .query_map([], |row| {
-            Ok(Todo {
-                id: row.get(0)?,
-                title: row.get(1)?,
-                completed: row.get::<_, i32>(2)? != 0,
-                position: row.get(3)?,
-                created_at: row.get(4)?,
-                updated_at: row.get(5)?,
+    let members = stmt
+        .query_map([list_id], |row| {
+            Ok(ListMember {
+                user_id: row.get(0)?,
+                username: row.get(1)?,
+                role: Role::parse(&row.get::<_, String>(2)?),
             })
         })?
         .collect::<Result<Vec<_>, _>>()?;
-    Ok(todos)
+    Ok(members)
+}
+
+/// Gives a user a role on a list, replacing any role they had before. The
+/// owner cannot be made a member of their own list.
+///
+/// Returns `None` if there is no user with that name.
+pub fn set_list_member(
+    pool: &DbPool,
+    list_id: i64,
+    username: &str,
+    role: Role,
+) -> Result<Option<ListMember>, AppError> {
+    let conn = pool.lock().unwrap();
+    let Some(user_id) = conn
+        .query_row(
+            "SELECT id FROM users WHERE username = ?1",
+            [username],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+
+    let owner_id: i64 = conn.query_row(
+        "SELECT user_id FROM lists WHERE id = ?1",
+        [list_id],
+        |row| row.get(0),
+    )?;
+    if user_id == owner_id {
+        return Err(AppError::BadRequest("The owner cannot be a member"));
+    }
+
+    conn.execute(
+        "INSERT INTO list_members (list_id, user_id, role) VALUES (?1, ?2, ?3)
+         ON CONFLICT (list_id, user_id) DO UPDATE SET role = excluded.role",
+        (list_id, user_id, role.as_str()),
+    )?;
+    Ok(Some(ListMember {
+        user_id,
+        username: username.to_string(),
+        role,
+    }))
+}
+
+/// Changes the role of an existing member. Returns `None` if they are not one.
+pub fn update_list_member(
+    pool: &DbPool,
+    list_id: i64,
+    user_id: i64,
+    role: Role,
+) -> Result<Option<ListMember>, AppError> {
+    let conn = pool.lock().unwrap();
+    conn.execute(
+        "UPDATE list_members SET role = ?1 WHERE list_id = ?2 AND user_id = ?3",
+        (role.as_str(), list_id, user_id),
+    )?;
+    let member = conn
+        .query_row(
+            "SELECT users.id, users.username, list_members.role FROM list_members
+             JOIN users ON users.id = list_members.user_id
+             WHERE list_members.list_id = ?1 AND list_members.user_id = ?2",
+            (list_id, user_id),
+            |row| {
+                Ok(ListMember {
+                    user_id: row.get(0)?,
+                    username: row.get(1)?,
+                    role: Role::parse(&row.get::<_, String>(2)?),
+                })
+            },
+        )
+        .optional()?;
+    Ok(member)
 }
 
-pub fn get_todo(pool: &DbPool, id: i64) -> Result<Option<Todo>, AppError> {
+/// The users who can see a list: its owner and members.
+pub fn list_audience(pool: &DbPool, list_id: i64) -> Result<Vec<i64>, AppError> {
     let conn = pool.lock().unwrap();
     let mut stmt = conn.prepare(
-        "SELECT id, title, completed, position, created_at, updated_at FROM todos WHERE id = ?1",
+        "SELECT user_id FROM lists WHERE id = ?1
+         UNION SELECT user_id FROM list_members WHERE list_id = ?1",
     )?;
-    let mut rows = stmt.query([id])?;
+    let user_ids = stmt
+        .query_map([list_id], |row| row.get(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(user_ids)
+}
 
-    if let Some(row) = rows.next()? {
-        Ok(Some(Todo {
-            id: row.get(0)?,
-            title: row.get(1)?,
-            completed: row.get::<_, i32>(2)? != 0,
-            position: row.get(3)?,
-            created_at: row.get(4)?,
-            updated_at: row.get(5)?,
-        }))
+pub fn remove_list_member(pool: &DbPool, list_id: i64, user_id: i64) -> Result<bool, AppError> {
+    let conn = pool.lock().unwrap();
+    let rows = conn.execute(
+        "DELETE FROM list_members WHERE list_id = ?1 AND user_id = ?2",
+        (list_id, user_id),
+    )?;
+    Ok(rows > 0)
+}
+
+// Todo operations
+const TODO_COLUMNS: &str = "todos.id, todos.list_id, todos.title, todos.completed,
+    todos.position, todos.due_at, todos.created_at, todos.updated_at, todos.parent_id,
+    todos.recurrence, todos.priority, todos.notes,
+    (SELECT group_concat(tags.name, ',' ORDER BY tags.name)
+     FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id
+     WHERE todo_tags.todo_id = todos.id),
+    COALESCE(todos.seq, 0)";
+const TODO_COLUMN_COUNT: usize = 14;
+
+/// Restricts todos to the lists visible to the user bound to `?1`.
+const VISIBLE_TODOS: &str = "todos.list_id IN (
+        SELECT id FROM lists WHERE user_id = ?1
+        UNION
+        SELECT list_id FROM list_members WHERE user_id = ?1
+    )";
+
+/// Selects the ids of all descendants of the todo bound to `?1`.
+const SUBTREE: &str = "WITH RECURSIVE subtree(id) AS (
+        SELECT id FROM todos WHERE parent_id = ?1
+        UNION ALL
+        SELECT todos.id FROM todos JOIN subtree ON todos.parent_id = subtree.id
+    )";
+
+fn row_to_todo(row: &rusqlite::Row) -> Result<Todo> {
+    Ok(Todo {
+        id: row.get(0)?,
+        list_id: row.get(1)?,
+        title: row.get(2)?,
+        completed: row.get::<_, i32>(3)? != 0,
+        position: row.get(4)?,
+        due_at: row.get(5)?,
+        created_at: row.get(6)?,
+        updated_at: row.get(7)?,
+        parent_id: row.get(8)?,
+        recurrence: row
+            .get::<_, Option<String>>(9)?
+            .and_then(|rule| serde_json::from_str(&rule).ok()),
+        priority: Priority::from_level(row.get(10)?),
+        notes: row.get(11)?,
+        tags: row
+            .get::<_, Option<String>>(12)?
+            .map(|tags| tags.split(',').map(str::to_string).collect())
+            .unwrap_or_default(),
+        version: row.get(13)?,
+    })
+}
+
+const HAS_TAG: &str = "EXISTS (SELECT 1 FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id
+    WHERE todo_tags.todo_id = todos.id AND tags.name = ?)";
+const LACKS_TAG: &str =
+    "NOT EXISTS (SELECT 1 FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id
+    WHERE todo_tags.todo_id = todos.id AND tags.name = ?)";
+
+fn recurrence_to_sql(rule: Option<&Recurrence>) -> Option<String> {
+    rule.map(|rule| serde_json::to_string(rule).expect("serializing recurrence"))
+}
+
+/// Replaces the tags of a todo, dropping tags that are no longer used anywhere.
+fn set_todo_tags(conn: &Connection, todo_id: i64, tags: &[String]) -> Result<(), AppError> {
+    conn.execute("DELETE FROM todo_tags WHERE todo_id = ?1", [todo_id])?;
+
+    for tag in tags {
+        conn.execute("INSERT OR IGNORE INTO tags (name) VALUES (?1)", [tag])?;
+        conn.execute(
+            "INSERT OR IGNORE INTO todo_tags (todo_id, tag_id)
+             SELECT ?1, id FROM tags WHERE name = ?2",
+            (todo_id, tag),
+        )?;
+    }
+
+    conn.execute(
+        "DELETE FROM tags WHERE id NOT IN (SELECT tag_id FROM todo_tags)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Position after the last sibling, i.e. the last todo sharing list and parent.
+fn next_position(conn: &Connection, list_id: i64, parent_id: Option<i64>) -> i64 {
+    conn.query_row(
+        "SELECT COALESCE(MAX(position), 0) FROM todos WHERE list_id = ?1 AND parent_id IS ?2",
+        (list_id, parent_id),
+        |row| row.get::<_, i64>(0),
+    )
+    .unwrap_or(0)
+        + 1
+}
+
+fn is_descendant(conn: &Connection, id: i64, ancestor: i64) -> Result<bool, AppError> {
+    let found = conn.query_row(
+        &format!("{SUBTREE} SELECT EXISTS (SELECT 1 FROM subtree WHERE id = ?2)"),
+        (ancestor, id),
+        |row| row.get(0),
+    )?;
+    Ok(found)
+}
+
+/// Orders todos depth-first so that subtasks directly follow their parent.
+/// Todos whose parent is not part of `todos` are treated as top-level.
+fn tree_order(todos: Vec<Todo>) -> Vec<Todo> {
+    fn visit(
+        parent: Option<i64>,
+        children: &mut HashMap<Option<i64>, Vec<Todo>>,
+        ordered: &mut Vec<Todo>,
+    ) {
+        for todo in children.remove(&parent).unwrap_or_default() {
+            let id = todo.id;
+            ordered.push(todo);
+            visit(Some(id), children, ordered);
+        }
+    }
+
+    let ids: HashSet<i64> = todos.iter().map(|t| t.id).collect();
+    let mut children: HashMap<Option<i64>, Vec<Todo>> = HashMap::new();
+    for todo in todos {
+        let parent = todo.parent_id.filter(|p| ids.contains(p));
+        children.entry(parent).or_default().push(todo);
+    }
+
+    let mut ordered = Vec::with_capacity(ids.len());
+    visit(None, &mut children, &mut ordered);
+    ordered
+}
+
+/// Creates a todo at the end of its list, or of the user's default list if
+/// none is given. Subtasks are appended to their parent's subtasks. The caller
+/// checks that the user may edit the list.
+///
+/// Returns `None` if the requested list or parent is not visible to the user.
+pub fn create_todo(
+    pool: &DbPool,
+    user_id: i64,
+    todo: &CreateTodo,
+) -> Result<Option<Todo>, AppError> {
+    let conn = pool.lock().unwrap();
+
+    let list_id = match (todo.parent_id, todo.list_id) {
+        (Some(parent_id), list_id) => {
+            let Some(parent) = get_todo_internal(&conn, user_id, parent_id)? else {
+                return Ok(None);
+            };
+            if list_id.is_some_and(|id| id != parent.list_id) {
+                return Err(AppError::BadRequest(
+                    "Subtasks must be in the same list as their parent",
+                ));
+            }
+            parent.list_id
+        }
+        (None, Some(id)) if get_list_internal(&conn, user_id, id)?.is_none() => return Ok(None),
+        (None, Some(id)) => id,
+        (None, None) => default_list_id(&conn, user_id)?,
+    };
+
+    conn.execute(
+        "INSERT INTO todos (user_id, list_id, parent_id, title, notes, position, priority,
+                            due_at, recurrence)
+         VALUES ((SELECT user_id FROM lists WHERE id = ?1), ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        (
+            list_id,
+            todo.parent_id,
+            &todo.title,
+            &todo.notes,
+            next_position(&conn, list_id, todo.parent_id),
+            todo.priority.level(),
+            &todo.due_at,
+            recurrence_to_sql(todo.recurrence.as_ref()),
+        ),
+    )?;
+    let id = conn.last_insert_rowid();
+    set_todo_tags(&conn, id, &todo.tags)?;
+
+    get_todo_internal(&conn, user_id, id)
+}
+
+pub fn list_todos(pool: &DbPool, user_id: i64, filter: &TodoFilter) -> Result<Vec<Todo>, AppError> {
+    let conn = pool.lock().unwrap();
+
+    // Bare `?` placeholders below are numbered after the `?1` in VISIBLE_TODOS
+    let mut conditions = vec![VISIBLE_TODOS];
+    let mut params: Vec<Box<dyn rusqlite::ToSql>> = vec![Box::new(user_id)];
+
+    if let Some(list_id) = filter.list_id {
+        conditions.push("list_id = ?");
+        params.push(Box::new(list_id));
+    }
+    if let Some(completed) = filter.completed {
+        conditions.push("completed = ?");
+        params.push(Box::new(completed as i32));
+    }
+    if let Some(due) = filter.due {
+        conditions.push("completed = 0");
+        conditions.push(match due {
+            DueFilter::Today => "substr(due_at, 1, 10) = date('now')",
+            DueFilter::Overdue => {
+                "CASE WHEN length(due_at) = 10 THEN due_at < date('now')
+                 ELSE due_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now') END"
+            }
+            DueFilter::Week => {
+                "substr(due_at, 1, 10) BETWEEN date('now') AND date('now', '+6 days')"
+            }
+        });
+    }
+
+    for tag in &filter.tag {
+        let (condition, name) = match tag.strip_prefix('-') {
+            Some(name) => (LACKS_TAG, name),
+            None => (HAS_TAG, tag.as_str()),
+        };
+        conditions.push(condition);
+        params.push(Box::new(name.to_string()));
+    }
+
+    let mut query = format!(
+        "SELECT {TODO_COLUMNS} FROM todos WHERE {}",
+        conditions.join(" AND ")
+    );
+    let default_sort = match filter.due {
+        Some(_) => TodoSort::Due,
+        None => TodoSort::Position,
+    };
+    query.push_str(match filter.sort.unwrap_or(default_sort) {
+        TodoSort::Position => " ORDER BY list_id ASC, position ASC",
+        TodoSort::Priority => " ORDER BY priority DESC, list_id ASC, position ASC",
+        TodoSort::Due => " ORDER BY due_at IS NULL, due_at ASC, list_id ASC, position ASC",
+        TodoSort::Created => " ORDER BY created_at ASC, id ASC",
+    });
+
+    let params_refs: Vec<&dyn rusqlite::ToSql> = params.iter().map(|p| p.as_ref()).collect();
+    let mut stmt = conn.prepare(&query)?;
+    let todos = stmt
+        .query_map(params_refs.as_slice(), row_to_todo)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if filter.due.is_some() {
+        Ok(todos)
     } else {
-        Ok(None)
+        Ok(tree_order(todos))
     }
 }
 
+pub fn get_todo(pool: &DbPool, user_id: i64, id: i64) -> Result<Option<Todo>, AppError> {
+    let conn = pool.lock().unwrap();
+    get_todo_internal(&conn, user_id, id)
+}
+
+/// Updates the given fields of a todo.
+///
+/// Re-parenting moves the todo into its new parent's list, while moving it to
+/// another list makes it a top-level todo there. Either way it is appended to
+/// its new siblings and its subtasks follow it.
+///
+/// Completing a recurring todo, directly or by cascading from its parent,
+/// also creates its next occurrence. The caller checks that the user may edit
+/// the lists involved.
+///
+/// Returns `None` if the todo, the target list or the new parent is not
+/// visible to the user. Fails if the todo doesn't match `condition`.
 pub fn update_todo(
     pool: &DbPool,
+    user_id: i64,
     id: i64,
-    title: Option<&str>,
-    completed: Option<bool>,
-) -> Result<Option<Todo>, AppError> {
-    let conn = pool.lock().unwrap();
+    changes: &UpdateTodo,
+    condition: Option<&IfMatch>,
+) -> Result<Option<UpdatedTodo>, AppError> {
+    let mut conn = pool.lock().unwrap();
+    let tx = conn.transaction()?;
+
+    let Some(current) = get_todo_internal(&tx, user_id, id)? else {
+        return Ok(None);
+    };
+    if condition.is_some_and(|condition| !condition.matches(current.version)) {
+        return Err(AppError::PreconditionFailed);
+    }
 
     let mut updates = Vec::new();
     let mut params: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
 
-    if let Some(t) = title {
+    if let Some(t) = &changes.title {
         updates.push("title = ?");
         params.push(Box::new(t.to_string()));
     }
-    if let Some(c) = completed {
+    if let Some(c) = changes.completed {
         updates.push("completed = ?");
         params.push(Box::new(c as i32));
     }
 
-    if updates.is_empty() {
-        return get_todo_internal(&conn, id);
+    let placement = match (changes.parent_id, changes.list_id) {
+        (Some(Some(parent_id)), _) => {
+            if parent_id == id || is_descendant(&tx, parent_id, id)? {
+                return Err(AppError::BadRequest("A todo cannot be its own subtask"));
+            }
+            let Some(parent) = get_todo_internal(&tx, user_id, parent_id)? else {
+                return Ok(None);
+            };
+            (current.parent_id != Some(parent_id)).then_some((parent.list_id, Some(parent_id)))
+        }
+        (Some(None), list_id) => {
+            let list_id = list_id.unwrap_or(current.list_id);
+            (current.parent_id.is_some() || list_id != current.list_id).then_some((list_id, None))
+        }
+        (None, Some(list_id)) => (list_id != current.list_id).then_some((list_id, None)),
+        (None, None) => None,
+    };
+    if let Some((list_id, parent_id)) = placement {
+        if list_id != current.list_id && get_list_internal(&tx, user_id, list_id)?.is_none() {
+            return Ok(None);
+        }
+        updates.push("list_id = ?");
+        params.push(Box::new(list_id));
+        updates.push("parent_id = ?");
+        params.push(Box::new(parent_id));
+        updates.push("position = ?");
+        params.push(Box::new(next_position(&tx, list_id, parent_id)));
+    }
+
+    if let Some(notes) = &changes.notes {
+        updates.push("notes = ?");
+        params.push(Box::new(notes.clone()));
+    }
+    if let Some(priority) = changes.priority {
+        updates.push("priority = ?");
+        params.push(Box::new(priority.level()));
+    }
+    if let Some(due_at) = &changes.due_at {
+        updates.push("due_at = ?");
+        params.push(Box::new(due_at.clone()));
+    }
+    if let Some(recurrence) = &changes.recurrence {
+        updates.push("recurrence = ?");
+        params.push(Box::new(recurrence_to_sql(recurrence.as_ref())));
+    }
+    if let Some(tags) = &changes.tags {
+        set_todo_tags(&tx, id, tags)?;
+    }
+
+    if updates.is_empty() && changes.tags.is_none() {
+        return Ok(Some(UpdatedTodo {
+            todo: current,
+            next_occurrences: Vec::new(),
+        }));
     }
 
     updates.push("updated_at = strftime('%s', 'now')");
@@ -270,66 +1481,555 @@ pub fn update_todo(
     let query = format!("UPDATE todos SET {} WHERE id = ?", updates.join(", "));
 
     let params_refs: Vec<&dyn rusqlite::ToSql> = params.iter().map(|p| p.as_ref()).collect();
-    conn.execute(&query, params_refs.as_slice())?;
+    tx.execute(&query, params_refs.as_slice())?;
+
+    if let Some((list_id, _)) = placement.filter(|(list_id, _)| *list_id != current.list_id) {
+        // Todos belong to the owner of their list
+        tx.execute(
+            &format!(
+                "{SUBTREE} UPDATE todos
+                 SET list_id = ?2, user_id = (SELECT user_id FROM lists WHERE id = ?2)
+                 WHERE id = ?1 OR id IN subtree"
+            ),
+            (id, list_id),
+        )?;
+    }
+    let mut cascaded = Vec::new();
+    if let (Some(completed), true) = (changes.completed, changes.cascade) {
+        cascaded = tx
+            .prepare(&format!(
+                "{SUBTREE} UPDATE todos SET completed = ?2, updated_at = strftime('%s', 'now')
+                 WHERE id IN subtree AND completed != ?2
+                 RETURNING id"
+            ))?
+            .query_map((id, completed as i32), |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+    }
+
+    let Some(mut todo) = get_todo_internal(&tx, user_id, id)? else {
+        return Ok(None);
+    };
+    // Completed todos mapped to the occurrences following them
+    let mut spawned = Vec::new();
+    if todo.completed && !current.completed {
+        if let Some(rule) = todo.recurrence.clone() {
+            if let Some(next_id) = spawn_next_occurrence(&tx, &todo, todo.parent_id, &rule)? {
+                spawned.push((id, next_id));
+            }
+            todo = get_todo_internal(&tx, user_id, id)?.ok_or(AppError::NotFound)?;
+        }
+    }
+
+    // Subtasks completed along with the todo recur just the same, under the
+    // next occurrence of their parent if it recurs too and at the top level
+    // otherwise. Parents go first so that their subtasks can follow them.
+    let mut subtasks = Vec::new();
+    if todo.completed {
+        for subtask_id in cascaded {
+            subtasks.extend(get_todo_internal(&tx, user_id, subtask_id)?);
+        }
+    }
+    let parents: HashMap<i64, Option<i64>> = subtasks
+        .iter()
+        .map(|subtask| (subtask.id, subtask.parent_id))
+        .collect();
+    subtasks.sort_by_key(|subtask| {
+        std::iter::successors(Some(subtask.id), |id| parents.get(id).copied().flatten()).count()
+    });
+    for subtask in &subtasks {
+        let Some(rule) = &subtask.recurrence else {
+            continue;
+        };
+        let parent_id = subtask.parent_id.and_then(|parent_id| {
+            spawned
+                .iter()
+                .find(|(completed, _)| *completed == parent_id)
+                .map(|(_, next_id)| *next_id)
+        });
+        if let Some(next_id) = spawn_next_occurrence(&tx, subtask, parent_id, rule)? {
+            spawned.push((subtask.id, next_id));
+        }
+    }
+
+    let mut next_occurrences = Vec::new();
+    for (_, next_id) in spawned {
+        next_occurrences.extend(get_todo_internal(&tx, user_id, next_id)?);
+    }
+    tx.commit()?;
 
-    get_todo_internal(&conn, id)
+    Ok(Some(UpdatedTodo {
+        todo,
+        next_occurrences,
+    }))
 }
 
-pub fn reorder_todos(pool: &DbPool, ids: &[i64]) -> Result<(), AppError> {
+/// A todo after an update, along with the occurrences that completing it and
+/// its recurring subtasks created.
+pub struct UpdatedTodo {
+    pub todo: Todo,
+    pub next_occurrences: Vec<Todo>,
+}
+
+/// Creates the occurrence following a just completed recurring todo under
+/// `parent_id`. The rule moves over to the new todo so that the completed one
+/// stays behind as history; subtasks are not copied. A series without a next
+/// due date just ends. Returns the id of the new todo.
+fn spawn_next_occurrence(
+    conn: &Connection,
+    todo: &Todo,
+    parent_id: Option<i64>,
+    rule: &Recurrence,
+) -> Result<Option<i64>, AppError> {
+    let today = time::OffsetDateTime::now_utc().date();
+    let Some(due_at) = dates::next_due(rule, todo.due_at.as_deref(), today) else {
+        conn.execute(
+            "UPDATE todos SET recurrence = NULL WHERE id = ?1",
+            [todo.id],
+        )?;
+        return Ok(None);
+    };
+
+    conn.execute(
+        "INSERT INTO todos (user_id, list_id, parent_id, title, notes, position, priority,
+                            due_at, recurrence)
+         VALUES ((SELECT user_id FROM lists WHERE id = ?1), ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        (
+            todo.list_id,
+            parent_id,
+            &todo.title,
+            &todo.notes,
+            next_position(conn, todo.list_id, parent_id),
+            todo.priority.level(),
+            due_at,
+            recurrence_to_sql(Some(rule)),
+        ),
+    )?;
+    let next_id = conn.last_insert_rowid();
+    set_todo_tags(conn, next_id, &todo.tags)?;
+
+    conn.execute(
+        "UPDATE todos SET recurrence = NULL WHERE id = ?1",
+        [todo.id],
+    )?;
+    Ok(Some(next_id))
+}
+
+/// Turns free-form user input into an FTS5 query that matches all words as
+/// prefixes, so that `gro mil` finds "Buy groceries and milk".
+fn fts_query(input: &str) -> Option<String> {
+    let terms: Vec<String> = input
+        .split_whitespace()
+        .map(|term| term.replace('"', ""))
+        .filter(|term| !term.is_empty())
+        .map(|term| format!("\"{term}\"*"))
+        .collect();
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+/// Searches todos by title and notes, best matches first with title matches
+/// weighing more. Matched words in the snippet are wrapped in `<mark>` tags.
+pub fn search_todos(
+    pool: &DbPool,
+    user_id: i64,
+    query: &SearchQuery,
+) -> Result<Vec<SearchResult>, AppError> {
+    let Some(fts_query) = fts_query(&query.q) else {
+        return Ok(Vec::new());
+    };
+
+    let conn = pool.lock().unwrap();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {TODO_COLUMNS}, snippet(todos_fts, -1, '<mark>', '</mark>', '…', 16)
+         FROM todos_fts JOIN todos ON todos.id = todos_fts.rowid
+         WHERE {VISIBLE_TODOS} AND todos_fts MATCH ?2
+           AND (?3 IS NULL OR todos.list_id = ?3)
+         ORDER BY bm25(todos_fts, 10.0, 1.0)
+         LIMIT ?4"
+    ))?;
+    let results = stmt
+        .query_map(
+            (user_id, fts_query, query.list_id, query.limit.unwrap_or(50)),
+            |row| {
+                Ok(SearchResult {
+                    todo: row_to_todo(row)?,
+                    snippet: row.get(TODO_COLUMN_COUNT)?,
+                })
+            },
+        )?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(results)
+}
+
+/// Assigns positions in the order of `ids` within a list the caller may edit.
+/// Only siblings of the first todo are reordered.
+pub fn reorder_todos(pool: &DbPool, list_id: i64, ids: &[i64]) -> Result<(), AppError> {
     let conn = pool.lock().unwrap();
 
+    let Some(first) = ids.first() else {
+        return Ok(());
+    };
+    let parent_id: Option<i64> = conn
+        .query_row(
+            "SELECT parent_id FROM todos WHERE id = ?1 AND list_id = ?2",
+            (first, list_id),
+            |row| row.get(0),
+        )
+        .optional()?
+        .flatten();
+
     for (position, id) in ids.iter().enumerate() {
         conn.execute(
-            "UPDATE todos SET position = ?, updated_at = strftime('%s', 'now') WHERE id = ?",
-            (position as i64, id),
+            "UPDATE todos SET position = ?1, updated_at = strftime('%s', 'now')
+             WHERE id = ?2 AND list_id = ?3 AND parent_id IS ?4",
+            (position as i64, id, list_id, parent_id),
         )?;
     }
 
     Ok(())
 }
 
-fn get_todo_internal(conn: &Connection, id: i64) -> Result<Option<Todo>, AppError> {
-    let mut stmt = conn.prepare(
-        "SELECT id, title, completed, position, created_at, updated_at FROM todos WHERE id = ?1",
-    )?;
-    let mut rows = stmt.query([id])?;
+fn get_todo_internal(conn: &Connection, user_id: i64, id: i64) -> Result<Option<Todo>, AppError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {TODO_COLUMNS} FROM todos WHERE {VISIBLE_TODOS} AND todos.id = ?2"
+    ))?;
+    let mut rows = stmt.query((user_id, id))?;
 
     if let Some(row) = rows.next()? {
-        Ok(Some(Todo {
-            id: row.get(0)?,
-            title: row.get(1)?,
-            completed: row.get::<_, i32>(2)? != 0,
-            position: row.get(3)?,
-            created_at: row.get(4)?,
-            updated_at: row.get(5)?,
-        }))
+        Ok(Some(row_to_todo(row)?))
     } else {
         Ok(None)
     }
 }
 
-pub fn delete_todo(pool: &DbPool, id: i64) -> Result<bool, AppError> {
+/// The subtasks of a todo, their subtasks included.
+pub fn subtasks(pool: &DbPool, user_id: i64, id: i64) -> Result<Vec<Todo>, AppError> {
+    let conn = pool.lock().unwrap();
+    // Subtasks are always in the list of their parent
+    if get_todo_internal(&conn, user_id, id)?.is_none() {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare(&format!(
+        "{SUBTREE} SELECT {TODO_COLUMNS} FROM todos WHERE todos.id IN subtree
+         ORDER BY todos.id ASC"
+    ))?;
+    let todos = stmt
+        .query_map([id], row_to_todo)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(todos)
+}
+
+/// Deletes a todo along with its subtasks. Fails if the todo doesn't match
+/// `condition`.
+pub fn delete_todo(
+    pool: &DbPool,
+    user_id: i64,
+    id: i64,
+    condition: Option<&IfMatch>,
+) -> Result<bool, AppError> {
     let conn = pool.lock().unwrap();
-    let rows = conn.execute("DELETE FROM todos WHERE id = ?1", [id])?;
+    if let Some(condition) = condition {
+        match get_todo_internal(&conn, user_id, id)? {
+            Some(todo) if !condition.matches(todo.version) => {
+                return Err(AppError::PreconditionFailed)
+            }
+            Some(_) => {}
+            None => return Ok(false),
+        }
+    }
+    let rows = conn.execute(
+        &format!("DELETE FROM todos WHERE {VISIBLE_TODOS} AND id = ?2"),
+        (user_id, id),
+    )?;
     Ok(rows > 0)
 }
 
-pub fn list_open_todos(pool: &DbPool) -> Result<Vec<Todo>, AppError> {
+// Sync operations
+
+/// How long tombstones are kept. Clients that haven't synced for longer start
+/// over.
+const TOMBSTONE_RETENTION_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// Everything that changed for the user since the sequence number `since`.
+/// Clients syncing for the first time, from before the oldest tombstone or
+/// from a cursor the server doesn't know get all todos, with `reset` set.
+pub fn sync_changes(pool: &DbPool, user_id: i64, since: i64) -> Result<SyncChanges, AppError> {
+    let conn = pool.lock().unwrap();
+
+    let (seq, pruned_seq): (i64, i64) =
+        conn.query_row("SELECT seq, pruned_seq FROM sync_state", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+    let reset = since <= 0 || since < pruned_seq || since > seq;
+
+    let lists = list_lists_internal(&conn, user_id)?;
+
+    // Lists shared with the user since then are sent in full
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {TODO_COLUMNS} FROM todos WHERE {VISIBLE_TODOS}
+           AND (?3 OR todos.seq > ?2 OR todos.list_id IN (
+               SELECT list_id FROM list_members WHERE user_id = ?1 AND seq > ?2
+           ))
+         ORDER BY list_id ASC, position ASC"
+    ))?;
+    let todos = stmt
+        .query_map((user_id, since, reset), row_to_todo)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let deleted = if reset {
+        Vec::new()
+    } else {
+        let mut stmt = conn.prepare(
+            "SELECT todo_id, list_id FROM tombstones
+             WHERE seq > ?2 AND list_id IN (
+                 SELECT id FROM lists WHERE user_id = ?1
+                 UNION
+                 SELECT list_id FROM list_members WHERE user_id = ?1
+             )
+             ORDER BY seq ASC",
+        )?;
+        let rows = stmt.query_map((user_id, since), |row| {
+            Ok(Tombstone {
+                id: row.get(0)?,
+                list_id: row.get(1)?,
+            })
+        })?;
+        rows.collect::<Result<Vec<_>, _>>()?
+    };
+
+    Ok(SyncChanges {
+        seq,
+        reset,
+        lists,
+        todos: tree_order(todos),
+        deleted,
+    })
+}
+
+/// Drops tombstones past their retention, remembering the newest one dropped
+/// so that clients syncing from before it start over.
+pub fn prune_tombstones(pool: &DbPool) -> Result<(), AppError> {
+    let conn = pool.lock().unwrap();
+    let now = dates::unix_now();
+    let cutoff = now - TOMBSTONE_RETENTION_SECS;
+
+    conn.execute(
+        "UPDATE sync_state SET pruned_seq = MAX(pruned_seq, (
+             SELECT COALESCE(MAX(seq), 0) FROM tombstones WHERE deleted_at < ?1
+         ))",
+        [cutoff],
+    )?;
+    conn.execute("DELETE FROM tombstones WHERE deleted_at < ?1", [cutoff])?;
+    Ok(())
+}
+
+// Webhook operations
+const WEBHOOK_COLUMNS: &str = "id, url, events, created_at";
+
+fn row_to_webhook(row: &rusqlite::Row) -> rusqlite::Result<Webhook> {
+    let events: String = row.get(2)?;
+    Ok(Webhook {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        events: events
+            .split_whitespace()
+            .filter_map(WebhookEvent::parse)
+            .collect(),
+        created_at: row.get(3)?,
+    })
+}
+
+pub fn create_webhook(
+    pool: &DbPool,
+    user_id: i64,
+    url: &str,
+    events: &[WebhookEvent],
+    secret: &str,
+) -> Result<Webhook, AppError> {
+    let events: Vec<&str> = events.iter().map(|event| event.as_str()).collect();
+    let conn = pool.lock().unwrap();
+    conn.execute(
+        "INSERT INTO webhooks (user_id, url, events, secret) VALUES (?1, ?2, ?3, ?4)",
+        (user_id, url, events.join(" "), secret),
+    )?;
+    let webhook = conn.query_row(
+        &format!("SELECT {WEBHOOK_COLUMNS} FROM webhooks WHERE id = ?1"),
+        [conn.last_insert_rowid()],
+        row_to_webhook,
+    )?;
+    Ok(webhook)
+}
+
+pub fn list_webhooks(pool: &DbPool, user_id: i64) -> Result<Vec<Webhook>, AppError> {
+    let conn = pool.lock().unwrap();
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {WEBHOOK_COLUMNS} FROM webhooks WHERE user_id = ?1 ORDER BY id ASC"
+    ))?;
+    let webhooks = stmt
+        .query_map([user_id], row_to_webhook)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(webhooks)
+}
+
+/// Deletes a webhook along with its pending deliveries and log.
+pub fn delete_webhook(pool: &DbPool, user_id: i64, id: i64) -> Result<bool, AppError> {
+    let conn = pool.lock().unwrap();
+    let rows = conn.execute(
+        "DELETE FROM webhooks WHERE id = ?1 AND user_id = ?2",
+        (id, user_id),
+    )?;
+    Ok(rows > 0)
+}
+
+/// Queues `payload` for the webhooks of the given users that are subscribed
+/// to `event`. Returns the number of deliveries queued.
+pub fn enqueue_webhook_deliveries(
+    pool: &DbPool,
+    user_ids: &[i64],
+    event: WebhookEvent,
+    payload: &str,
+) -> Result<usize, AppError> {
     let conn = pool.lock().unwrap();
     let mut stmt = conn.prepare(
-        "SELECT id, title, completed, position, created_at, updated_at FROM todos WHERE completed = 0 ORDER BY position ASC",
+        "INSERT INTO webhook_deliveries (webhook_id, event, payload, next_attempt_at)
+         SELECT id, ?2, ?3, strftime('%s', 'now') FROM webhooks
+         WHERE user_id = ?1 AND ' ' || events || ' ' LIKE '% ' || ?2 || ' %'",
     )?;
-    let todos = stmt
-        .query_map([], |row| {
-            Ok(Todo {
+    let mut rows = 0;
+    for user_id in user_ids {
+        rows += stmt.execute((user_id, event.as_str(), payload))?;
+    }
+    Ok(rows)
+}
+
+/// A queued delivery along with where to send it.
+pub struct PendingDelivery {
+    pub id: i64,
+    pub event: WebhookEvent,
+    pub payload: String,
+    pub attempts: i64,
+    pub url: String,
+    pub secret: String,
+}
+
+/// Pending deliveries whose next attempt is due at `now`, oldest first.
+pub fn due_webhook_deliveries(
+    pool: &DbPool,
+    now: i64,
+    limit: i64,
+) -> Result<Vec<PendingDelivery>, AppError> {
+    let conn = pool.lock().unwrap();
+    let mut stmt = conn.prepare(
+        "SELECT webhook_deliveries.id, event, payload, attempts, url, secret
+         FROM webhook_deliveries JOIN webhooks ON webhooks.id = webhook_id
+         WHERE status = 'pending' AND next_attempt_at <= ?1
+         ORDER BY next_attempt_at ASC, webhook_deliveries.id ASC
+         LIMIT ?2",
+    )?;
+    let deliveries = stmt
+        .query_map((now, limit), |row| {
+            Ok(PendingDelivery {
                 id: row.get(0)?,
-                title: row.get(1)?,
-                completed: row.get::<_, i32>(2)? != 0,
-                position: row.get(3)?,
-                created_at: row.get(4)?,
-                updated_at: row.get(5)?,
+                event: WebhookEvent::parse(&row.get::<_, String>(1)?)
+                    .unwrap_or(WebhookEvent::TodoUpdated),
+                payload: row.get(2)?,
+                attempts: row.get(3)?,
+                url: row.get(4)?,
+                secret: row.get(5)?,
             })
         })?
         .collect::<Result<Vec<_>, _>>()?;
-    Ok(todos)
+    Ok(deliveries)
+}
+
+/// Logs an attempt to send a delivery. Deliveries with a `next_attempt_at`
+/// stay queued for a retry.
+pub fn record_webhook_attempt(
+    pool: &DbPool,
+    id: i64,
+    status: DeliveryStatus,
+    response_status: Option<u16>,
+    error: Option<&str>,
+    next_attempt_at: Option<i64>,
+) -> Result<(), AppError> {
+    let conn = pool.lock().unwrap();
+    conn.execute(
+        "UPDATE webhook_deliveries
+         SET status = ?2, attempts = attempts + 1, response_status = ?3, error = ?4,
+             last_attempt_at = strftime('%s', 'now'), next_attempt_at = ?5
+         WHERE id = ?1",
+        (id, status.as_str(), response_status, error, next_attempt_at),
+    )?;
+    Ok(())
+}
+
+/// When the next queued delivery is due, if any.
+pub fn next_webhook_attempt(pool: &DbPool) -> Result<Option<i64>, AppError> {
+    let conn = pool.lock().unwrap();
+    let next = conn.query_row(
+        "SELECT MIN(next_attempt_at) FROM webhook_deliveries WHERE status = 'pending'",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(next)
+}
+
+/// The latest deliveries of one of the user's webhooks, newest first.
+/// Returns `None` if the user has no such webhook.
+pub fn list_webhook_deliveries(
+    pool: &DbPool,
+    user_id: i64,
+    webhook_id: i64,
+) -> Result<Option<Vec<WebhookDelivery>>, AppError> {
+    let conn = pool.lock().unwrap();
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM webhooks WHERE id = ?1 AND user_id = ?2)",
+        (webhook_id, user_id),
+        |row| row.get(0),
+    )?;
+    if !exists {
+        return Ok(None);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT id, event, status, attempts, response_status, error, created_at,
+                last_attempt_at, next_attempt_at
+         FROM webhook_deliveries WHERE webhook_id = ?1
+         ORDER BY id DESC LIMIT 100",
+    )?;
+    let deliveries = stmt
+        .query_map([webhook_id], |row| {
+            Ok(WebhookDelivery {
+                id: row.get(0)?,
+                event: WebhookEvent::parse(&row.get::<_, String>(1)?)
+                    .unwrap_or(WebhookEvent::TodoUpdated),
+                status: DeliveryStatus::parse(&row.get::<_, String>(2)?),
+                attempts: row.get(3)?,
+                response_status: row.get(4)?,
+                error: row.get(5)?,
+                created_at: row.get(6)?,
+                last_attempt_at: row.get(7)?,
+                next_attempt_at: row.get(8)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Some(deliveries))
+}
+
+/// How long finished deliveries stay in the log.
+const DELIVERY_RETENTION_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// Drops finished deliveries past their retention from the log.
+pub fn prune_webhook_deliveries(pool: &DbPool) -> Result<(), AppError> {
+    let conn = pool.lock().unwrap();
+    let now = dates::unix_now();
+    conn.execute(
+        "DELETE FROM webhook_deliveries WHERE status != 'pending' AND created_at < ?1",
+        [now - DELIVERY_RETENTION_SECS],
+    )?;
+    Ok(())
 }
@@ -1,13 +1,25 @@
 use axum::response::{IntoResponse, Response};
-use axum::{http::StatusCode, Json};
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    Json,
+};
 use serde_json::json;
 
 #[derive(Debug)]
 pub enum AppError {
     Database(String),
     Unauthorized,
+    Forbidden,
     NotFound,
     BadRequest(&'static str),
+    /// The password was right, but a TOTP code is needed as well.
+    TotpRequired,
+    /// Seconds until the client may try again.
+    TooManyRequests {
+        retry_after: u64,
+    },
+    /// The todo changed since the version given in `If-Match`.
+    PreconditionFailed,
 }
 
 impl IntoResponse for AppError {
@@ -15,8 +27,28 @@ impl IntoResponse for AppError {
         let (status, message) = match self {
             AppError::Database(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
             AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized".to_string()),
+            AppError::Forbidden => (StatusCode::FORBIDDEN, "Forbidden".to_string()),
             AppError::NotFound => (StatusCode::NOT_FOUND, "Not found".to_string()),
             AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.to_string()),
+            AppError::PreconditionFailed => (
+                StatusCode::PRECONDITION_FAILED,
+                "Todo was changed in the meantime".to_string(),
+            ),
+            AppError::TotpRequired => {
+                return (
+                    StatusCode::UNAUTHORIZED,
+                    Json(json!({ "error": "Two-factor code required", "totp_required": true })),
+                )
+                    .into_response();
+            }
+            AppError::TooManyRequests { retry_after } => {
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, retry_after.to_string())],
+                    Json(json!({ "error": "Too many login attempts" })),
+                )
+                    .into_response();
+            }
         };
 
         (status, Json(json!({ "error": message }))).into_response()
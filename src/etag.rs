@@ -0,0 +1,139 @@
+use axum::extract::FromRequestParts;
+use axum::http::{
+    header::{ETAG, IF_MATCH, IF_NONE_MATCH},
+    request::Parts,
+    HeaderMap, StatusCode,
+};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::auth::to_hex;
+
+/// The `If-Match` condition of a change: it only goes through if the todo is
+/// still at one of the given versions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IfMatch {
+    /// `*`, matching any version.
+    Any,
+    Versions(Vec<i64>),
+}
+
+impl IfMatch {
+    fn parse(value: &str) -> Self {
+        if value.trim() == "*" {
+            return IfMatch::Any;
+        }
+        // Weak tags and tags of another format never match
+        let versions = value
+            .split(',')
+            .filter_map(|tag| {
+                tag.trim()
+                    .strip_prefix('"')?
+                    .strip_suffix('"')?
+                    .parse()
+                    .ok()
+            })
+            .collect();
+        IfMatch::Versions(versions)
+    }
+
+    pub fn matches(&self, version: i64) -> bool {
+        match self {
+            IfMatch::Any => true,
+            IfMatch::Versions(versions) => versions.contains(&version),
+        }
+    }
+}
+
+/// The conditional headers of a request.
+#[derive(Debug, Default)]
+pub struct Conditions {
+    pub if_match: Option<IfMatch>,
+    /// Entity tags from `If-None-Match`, without the weak prefix.
+    if_none_match: Vec<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Conditions {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Conditions::from_headers(&parts.headers))
+    }
+}
+
+impl Conditions {
+    /// The condition of a change expecting the todo at `version`, if given.
+    pub fn version(version: Option<i64>) -> Self {
+        Conditions {
+            if_match: version.map(|version| IfMatch::Versions(vec![version])),
+            if_none_match: Vec::new(),
+        }
+    }
+
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let values = |name| {
+            headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let if_match = values(IF_MATCH);
+        let if_none_match = values(IF_NONE_MATCH)
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/").to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        Conditions {
+            if_match: (!if_match.is_empty()).then(|| IfMatch::parse(&if_match)),
+            if_none_match,
+        }
+    }
+
+    /// Responds with `body` and its `etag`, or with 304 Not Modified if the
+    /// client already has it.
+    pub fn respond<T>(&self, etag: String, body: T) -> Tagged<T> {
+        let not_modified = self
+            .if_none_match
+            .iter()
+            .any(|tag| tag == "*" || *tag == etag);
+        Tagged {
+            etag,
+            not_modified,
+            body,
+        }
+    }
+}
+
+/// The entity tag of a todo at `version`.
+pub fn version_tag(version: i64) -> String {
+    format!("\"{version}\"")
+}
+
+/// An entity tag derived from the content of a response, for responses
+/// without a version of their own, like lists of todos.
+pub fn content_tag<T: Serialize>(body: &T) -> String {
+    let json = serde_json::to_vec(body).expect("serializing response");
+    format!("\"{}\"", to_hex(&Sha256::digest(json)[..16]))
+}
+
+/// A JSON response along with its entity tag.
+pub struct Tagged<T> {
+    etag: String,
+    not_modified: bool,
+    pub body: T,
+}
+
+impl<T: Serialize> IntoResponse for Tagged<T> {
+    fn into_response(self) -> Response {
+        let etag = [(ETAG, self.etag)];
+        if self.not_modified {
+            (StatusCode::NOT_MODIFIED, etag).into_response()
+        } else {
+            (etag, Json(self.body)).into_response()
+        }
+    }
+}
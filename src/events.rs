@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::models::Todo;
+
+/// Changes buffered per event stream. Streams that fall further behind are
+/// told to reload everything instead.
+const CAPACITY: usize = 256;
+
+/// A change to todos or lists, sent to the clients of `GET /api/events` as an
+/// event named after the variant.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Change {
+    Created {
+        todo: Todo,
+    },
+    Updated {
+        todo: Todo,
+    },
+    /// The todo was deleted along with its subtasks.
+    Deleted {
+        id: i64,
+        list_id: i64,
+    },
+    /// The todos of a list, in their new order.
+    Reordered {
+        list_id: i64,
+        ids: Vec<i64>,
+    },
+    /// Several todos of a list changed at once, e.g. subtasks completed along
+    /// with their parent, so the list has to be reloaded.
+    Changed {
+        list_id: i64,
+    },
+    /// A list was created, renamed, shared or deleted.
+    ListChanged {
+        list_id: i64,
+    },
+}
+
+impl Change {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Change::Created { .. } => "created",
+            Change::Updated { .. } => "updated",
+            Change::Deleted { .. } => "deleted",
+            Change::Reordered { .. } => "reordered",
+            Change::Changed { .. } => "changed",
+            Change::ListChanged { .. } => "list_changed",
+        }
+    }
+}
+
+/// A change along with the users it is sent to.
+#[derive(Debug)]
+pub struct Envelope {
+    pub user_ids: Vec<i64>,
+    pub change: Change,
+}
+
+/// Fans changes out to the event streams of all connected clients.
+pub struct EventHub {
+    sender: broadcast::Sender<Arc<Envelope>>,
+}
+
+impl Default for EventHub {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        EventHub { sender }
+    }
+}
+
+impl EventHub {
+    pub fn publish(&self, user_ids: Vec<i64>, change: Change) {
+        // Only fails if no one is listening
+        let _ = self.sender.send(Arc::new(Envelope { user_ids, change }));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Envelope>> {
+        self.sender.subscribe()
+    }
+}
@@ -1,77 +1,342 @@
+use std::collections::HashMap;
+
 use axum::extract::{Path, State};
-use axum::{http::StatusCode, response::IntoResponse, Json};
-use tracing::info;
+use axum::{
+    http::{header::ETAG, HeaderName, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use axum_extra::extract::Query;
+use tracing::{info, warn};
 
+use crate::dates::parse_due;
 use crate::db::{
-    create_todo, delete_todo, get_todo, list_open_todos, list_todos, reorder_todos, update_todo,
+    create_list, create_todo, delete_list, delete_todo, get_list, get_todo, list_audience,
+    list_lists, list_members, list_role, list_todos, remove_list_member, rename_list,
+    reorder_todos, search_todos, set_list_member, subtasks, sync_changes, todo_role,
+    update_list_member, update_todo, UpdatedTodo,
 };
 use crate::error::AppError;
+use crate::etag::{content_tag, version_tag, Conditions, Tagged};
+use crate::events::Change;
+use crate::markdown;
 use crate::middleware::Auth;
-use crate::models::{CreateTodo, ReorderTodos, Todo, UpdateTodo};
+use crate::models::{
+    AddListMember, CreateList, CreateTodo, ListMember, PlainTextOptions, Recurrence, ReorderTodos,
+    Role, SearchQuery, SearchResult, SyncChanges, SyncQuery, Todo, TodoDetail, TodoFilter,
+    TodoList, UpdateList, UpdateListMember, UpdateTodo, WebhookEvent,
+};
 use crate::AppState;
 
+/// Fails with 404 if the user cannot see the list at all, and with 403 if
+/// their role on it is below `required`.
+fn require_role(role: Option<Role>, required: Role) -> Result<(), AppError> {
+    match role {
+        None => Err(AppError::NotFound),
+        Some(role) if role < required => Err(AppError::Forbidden),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Tells everyone who can see a list about a change to it. The change is made
+/// by then, so failing to announce it is only logged.
+fn notify(state: &AppState, list_id: i64, change: Change) {
+    match list_audience(&state.db, list_id) {
+        Ok(user_ids) => state.events.publish(user_ids, change),
+        Err(err) => warn!(list_id, ?err, "Failed to announce change"),
+    }
+}
+
+/// Queues webhook deliveries of a todo event for everyone who can see the
+/// todo's list. Like `notify`, this only logs failures.
+fn trigger(state: &AppState, event: WebhookEvent, todo: &Todo) {
+    match list_audience(&state.db, todo.list_id) {
+        Ok(user_ids) => trigger_for(state, &user_ids, event, todo),
+        Err(err) => warn!(id = todo.id, ?err, "Failed to queue webhook deliveries"),
+    }
+}
+
+/// Queues webhook deliveries of a todo event for the given users, e.g. the
+/// former audience of a deleted list.
+fn trigger_for(state: &AppState, user_ids: &[i64], event: WebhookEvent, todo: &Todo) {
+    if let Err(err) = state.webhooks.trigger(&state.db, user_ids, event, todo) {
+        warn!(id = todo.id, ?err, "Failed to queue webhook deliveries");
+    }
+}
+
+/// Queues `todo.updated` for a changed todo, and `todo.completed` if the change
+/// completed it.
+fn trigger_update(state: &AppState, was_completed: bool, todo: &Todo) {
+    trigger(state, WebhookEvent::TodoUpdated, todo);
+    if todo.completed && !was_completed {
+        trigger(state, WebhookEvent::TodoCompleted, todo);
+    }
+}
+
+/// Announces an updated todo, and that its lists have to be reloaded if the
+/// update changed other todos as well: subtasks moved or completed along with
+/// it, or the next occurrence of a recurring todo.
+fn notify_update(state: &AppState, before: &Todo, todo: &Todo, req: &UpdateTodo) {
+    notify(state, todo.list_id, Change::Updated { todo: todo.clone() });
+    if todo.list_id != before.list_id {
+        for list_id in [before.list_id, todo.list_id] {
+            notify(state, list_id, Change::Changed { list_id });
+        }
+    } else if (req.cascade && req.completed.is_some())
+        || (before.recurrence.is_some() && todo.completed && !before.completed)
+    {
+        let list_id = todo.list_id;
+        notify(state, list_id, Change::Changed { list_id });
+    }
+}
+
+fn notify_reorder(state: &AppState, user_id: i64, list_id: i64) {
+    let filter = TodoFilter {
+        list_id: Some(list_id),
+        ..TodoFilter::default()
+    };
+    match list_todos(&state.db, user_id, &filter) {
+        Ok(todos) => {
+            let ids = todos.iter().map(|todo| todo.id).collect();
+            notify(state, list_id, Change::Reordered { list_id, ids });
+        }
+        Err(err) => warn!(list_id, ?err, "Failed to announce reordering"),
+    }
+}
+
+fn normalize_due(due_at: Option<&str>) -> Result<Option<String>, AppError> {
+    match due_at {
+        Some(due_at) => parse_due(due_at)
+            .map(Some)
+            .ok_or(AppError::BadRequest("Invalid due date")),
+        None => Ok(None),
+    }
+}
+
+/// Treats blank notes as no notes at all.
+fn normalize_notes(notes: Option<String>) -> Option<String> {
+    notes.filter(|notes| !notes.trim().is_empty())
+}
+
+fn normalize_tags(tags: &[String]) -> Result<Vec<String>, AppError> {
+    let mut normalized = Vec::with_capacity(tags.len());
+
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if tag.is_empty() || tag.starts_with('-') || tag.contains(',') {
+            return Err(AppError::BadRequest("Invalid tag"));
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+
+    Ok(normalized)
+}
+
+/// Longest interval of an `after_completion` rule: ten years.
+const MAX_RECURRENCE_DAYS: u32 = 3650;
+
+fn validate_recurrence(rule: Option<&Recurrence>) -> Result<(), AppError> {
+    let valid = match rule {
+        Some(Recurrence::Weekly { weekdays }) => !weekdays.is_empty(),
+        Some(Recurrence::Monthly { day }) => (1..=31).contains(day),
+        Some(Recurrence::AfterCompletion { days }) => (1..=MAX_RECURRENCE_DAYS).contains(days),
+        Some(Recurrence::Daily) | None => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::BadRequest("Invalid recurrence"))
+    }
+}
+
+/// Lowercases tag filters the same way tags are stored, keeping the `-` prefix.
+fn normalize_filter(filter: &mut TodoFilter) {
+    for tag in &mut filter.tag {
+        *tag = tag.trim().to_lowercase();
+    }
+}
+
 pub async fn list_all_todos(
-    _auth: Auth,
+    Auth(user): Auth,
     State(state): State<AppState>,
-) -> Result<Json<Vec<Todo>>, AppError> {
-    let todos = list_todos(&state.db)?;
+    conditions: Conditions,
+    Query(mut filter): Query<TodoFilter>,
+) -> Result<Tagged<Vec<Todo>>, AppError> {
+    normalize_filter(&mut filter);
+    let todos = list_todos(&state.db, user.id, &filter)?;
     info!(count = todos.len(), "Listed todos");
-    Ok(Json(todos))
+    Ok(conditions.respond(content_tag(&todos), todos))
+}
+
+pub async fn search(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<SearchResult>>, AppError> {
+    if query.q.trim().is_empty() {
+        return Err(AppError::BadRequest("Query cannot be empty"));
+    }
+    if matches!(query.limit, Some(limit) if !(1..=200).contains(&limit)) {
+        return Err(AppError::BadRequest("Limit must be between 1 and 200"));
+    }
+
+    let results = search_todos(&state.db, user.id, &query)?;
+    info!(count = results.len(), "Searched todos");
+    Ok(Json(results))
 }
 
 pub async fn create_new_todo(
-    _auth: Auth,
+    Auth(user): Auth,
     State(state): State<AppState>,
-    Json(req): Json<CreateTodo>,
+    Json(mut req): Json<CreateTodo>,
 ) -> Result<(StatusCode, Json<Todo>), AppError> {
     if req.title.trim().is_empty() {
         return Err(AppError::BadRequest("Title cannot be empty"));
     }
+    req.notes = normalize_notes(req.notes.take());
+    req.due_at = normalize_due(req.due_at.as_deref())?;
+    req.tags = normalize_tags(&req.tags)?;
+    validate_recurrence(req.recurrence.as_ref())?;
 
-    let todo = create_todo(&state.db, &req.title)?;
-    info!(id = todo.id, title = %todo.title, "Created todo");
+    if let Some(parent_id) = req.parent_id {
+        require_role(todo_role(&state.db, user.id, parent_id)?, Role::Editor)?;
+    } else if let Some(list_id) = req.list_id {
+        require_role(list_role(&state.db, user.id, list_id)?, Role::Editor)?;
+    }
+
+    let todo = create_todo(&state.db, user.id, &req)?.ok_or(AppError::NotFound)?;
+    info!(id = todo.id, list_id = todo.list_id, title = %todo.title, "Created todo");
+    notify(&state, todo.list_id, Change::Created { todo: todo.clone() });
+    trigger(&state, WebhookEvent::TodoCreated, &todo);
     Ok((StatusCode::CREATED, Json(todo)))
 }
 
+/// Returns what changed since the client's previous sync, so that offline
+/// clients don't have to reload all todos.
+pub async fn sync(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    Query(query): Query<SyncQuery>,
+) -> Result<Json<SyncChanges>, AppError> {
+    let changes = sync_changes(&state.db, user.id, query.since)?;
+    info!(
+        since = query.since,
+        seq = changes.seq,
+        reset = changes.reset,
+        todos = changes.todos.len(),
+        deleted = changes.deleted.len(),
+        "Synced todos"
+    );
+    Ok(Json(changes))
+}
+
 pub async fn get_single_todo(
-    _auth: Auth,
+    Auth(user): Auth,
     State(state): State<AppState>,
+    conditions: Conditions,
     Path(id): Path<i64>,
-) -> Result<Json<Todo>, AppError> {
-    match get_todo(&state.db, id)? {
-        Some(todo) => Ok(Json(todo)),
+) -> Result<Tagged<TodoDetail>, AppError> {
+    match get_todo(&state.db, user.id, id)? {
+        Some(todo) => {
+            let etag = version_tag(todo.version);
+            let notes_html = todo.notes.as_deref().map(markdown::render);
+            Ok(conditions.respond(etag, TodoDetail { todo, notes_html }))
+        }
         None => Err(AppError::NotFound),
     }
 }
 
+/// Updates a todo. With `If-Match`, only if it is still at the given version.
 pub async fn update_existing_todo(
-    _auth: Auth,
+    Auth(user): Auth,
     State(state): State<AppState>,
+    conditions: Conditions,
     Path(id): Path<i64>,
-    Json(req): Json<UpdateTodo>,
-) -> Result<Json<Todo>, AppError> {
+    Json(mut req): Json<UpdateTodo>,
+) -> Result<([(HeaderName, String); 1], Json<Todo>), AppError> {
     if let Some(ref title) = req.title {
         if title.trim().is_empty() {
             return Err(AppError::BadRequest("Title cannot be empty"));
         }
     }
+    if let Some(notes) = req.notes.take() {
+        req.notes = Some(normalize_notes(notes));
+    }
+    if let Some(due_at) = req.due_at.take() {
+        req.due_at = Some(normalize_due(due_at.as_deref())?);
+    }
+    if let Some(tags) = req.tags.take() {
+        req.tags = Some(normalize_tags(&tags)?);
+    }
+    if let Some(recurrence) = &req.recurrence {
+        validate_recurrence(recurrence.as_ref())?;
+    }
 
-    match update_todo(&state.db, id, req.title.as_deref(), req.completed)? {
-        Some(todo) => {
+    require_role(todo_role(&state.db, user.id, id)?, Role::Editor)?;
+    let before = get_todo(&state.db, user.id, id)?.ok_or(AppError::NotFound)?;
+    if let Some(Some(parent_id)) = req.parent_id {
+        require_role(todo_role(&state.db, user.id, parent_id)?, Role::Editor)?;
+    } else if let Some(list_id) = req.list_id {
+        require_role(list_role(&state.db, user.id, list_id)?, Role::Editor)?;
+    }
+
+    // Subtasks completed or reopened along with the todo
+    let cascaded: Vec<i64> = match req.completed {
+        Some(completed) if req.cascade => subtasks(&state.db, user.id, id)?
+            .into_iter()
+            .filter(|subtask| subtask.completed != completed)
+            .map(|subtask| subtask.id)
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    match update_todo(&state.db, user.id, id, &req, conditions.if_match.as_ref())? {
+        Some(UpdatedTodo {
+            todo,
+            next_occurrences,
+        }) => {
             info!(id = todo.id, completed = todo.completed, "Updated todo");
-            Ok(Json(todo))
+            notify_update(&state, &before, &todo, &req);
+            trigger_update(&state, before.completed, &todo);
+            if !cascaded.is_empty() {
+                match subtasks(&state.db, user.id, id) {
+                    Ok(subtasks) => {
+                        for subtask in subtasks.iter().filter(|s| cascaded.contains(&s.id)) {
+                            trigger_update(&state, !subtask.completed, subtask);
+                        }
+                    }
+                    Err(err) => warn!(id, ?err, "Failed to queue webhook deliveries"),
+                }
+            }
+            for next in &next_occurrences {
+                trigger(&state, WebhookEvent::TodoCreated, next);
+            }
+            Ok(([(ETAG, version_tag(todo.version))], Json(todo)))
         }
         None => Err(AppError::NotFound),
     }
 }
 
+/// Deletes a todo. With `If-Match`, only if it is still at the given version.
 pub async fn delete_existing_todo(
-    _auth: Auth,
+    Auth(user): Auth,
     State(state): State<AppState>,
+    conditions: Conditions,
     Path(id): Path<i64>,
 ) -> Result<impl IntoResponse, AppError> {
-    if delete_todo(&state.db, id)? {
+    require_role(todo_role(&state.db, user.id, id)?, Role::Editor)?;
+    let todo = get_todo(&state.db, user.id, id)?.ok_or(AppError::NotFound)?;
+    let subtasks = subtasks(&state.db, user.id, id)?;
+
+    if delete_todo(&state.db, user.id, id, conditions.if_match.as_ref())? {
         info!(id, "Deleted todo");
+        let list_id = todo.list_id;
+        notify(&state, list_id, Change::Deleted { id, list_id });
+        for todo in std::iter::once(&todo).chain(&subtasks) {
+            trigger(&state, WebhookEvent::TodoDeleted, todo);
+        }
         Ok(StatusCode::NO_CONTENT)
     } else {
         Err(AppError::NotFound)
@@ -79,26 +344,339 @@ pub async fn delete_existing_todo(
 }
 
 pub async fn reorder(
-    _auth: Auth,
+    Auth(user): Auth,
     State(state): State<AppState>,
     Json(req): Json<ReorderTodos>,
 ) -> Result<Json<Vec<Todo>>, AppError> {
-    reorder_todos(&state.db, &req.ids)?;
-    let todos = list_todos(&state.db)?;
+    if let Some(first) = req.ids.first() {
+        let todo = get_todo(&state.db, user.id, *first)?.ok_or(AppError::NotFound)?;
+        require_role(list_role(&state.db, user.id, todo.list_id)?, Role::Editor)?;
+        reorder_todos(&state.db, todo.list_id, &req.ids)?;
+        notify_reorder(&state, user.id, todo.list_id);
+    }
+    let todos = list_todos(&state.db, user.id, &TodoFilter::default())?;
     info!("Reordered todos");
     Ok(Json(todos))
 }
 
 pub async fn plain_text_todos(
-    _auth: Auth,
+    Auth(user): Auth,
     State(state): State<AppState>,
+    Query(mut filter): Query<TodoFilter>,
+    Query(options): Query<PlainTextOptions>,
 ) -> Result<impl IntoResponse, AppError> {
-    let todos = list_open_todos(&state.db)?;
-    let text: String = todos.iter().map(|t| format!("{}\n", t.title)).collect();
+    normalize_filter(&mut filter);
+    filter.completed = Some(false);
+    let todos = list_todos(&state.db, user.id, &filter)?;
+    Ok(plain_text_response(&todos, &options))
+}
 
-    Ok((
+/// One title per line, subtasks indented by two spaces per level.
+fn plain_text_response(todos: &[Todo], options: &PlainTextOptions) -> impl IntoResponse {
+    let parents: HashMap<i64, Option<i64>> = todos.iter().map(|t| (t.id, t.parent_id)).collect();
+    let depth = |todo: &Todo| {
+        let mut depth = 0;
+        let mut parent = todo.parent_id;
+        while let Some(Some(next)) = parent.map(|id| parents.get(&id)) {
+            depth += 1;
+            parent = *next;
+        }
+        depth
+    };
+
+    let text: String = todos
+        .iter()
+        .map(|t| {
+            let marker = if options.markers {
+                t.priority.marker()
+            } else {
+                ""
+            };
+            format!("{}{}{}\n", "  ".repeat(depth(t)), marker, t.title)
+        })
+        .collect();
+
+    (
         StatusCode::OK,
         [("content-type", "text/plain; charset=utf-8")],
         text,
-    ))
+    )
+}
+
+pub async fn list_all_lists(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    conditions: Conditions,
+) -> Result<Tagged<Vec<TodoList>>, AppError> {
+    let lists = list_lists(&state.db, user.id)?;
+    Ok(conditions.respond(content_tag(&lists), lists))
+}
+
+pub async fn create_new_list(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    Json(req): Json<CreateList>,
+) -> Result<(StatusCode, Json<TodoList>), AppError> {
+    if req.name.trim().is_empty() {
+        return Err(AppError::BadRequest("Name cannot be empty"));
+    }
+
+    let list = create_list(&state.db, user.id, req.name.trim())?;
+    info!(id = list.id, name = %list.name, "Created list");
+    notify(&state, list.id, Change::ListChanged { list_id: list.id });
+    Ok((StatusCode::CREATED, Json(list)))
+}
+
+pub async fn get_single_list(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<TodoList>, AppError> {
+    match get_list(&state.db, user.id, id)? {
+        Some(list) => Ok(Json(list)),
+        None => Err(AppError::NotFound),
+    }
+}
+
+pub async fn update_existing_list(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(req): Json<UpdateList>,
+) -> Result<Json<TodoList>, AppError> {
+    if req.name.trim().is_empty() {
+        return Err(AppError::BadRequest("Name cannot be empty"));
+    }
+    require_role(list_role(&state.db, user.id, id)?, Role::Owner)?;
+
+    match rename_list(&state.db, user.id, id, req.name.trim())? {
+        Some(list) => {
+            info!(id = list.id, name = %list.name, "Renamed list");
+            notify(&state, id, Change::ListChanged { list_id: id });
+            Ok(Json(list))
+        }
+        None => Err(AppError::NotFound),
+    }
+}
+
+pub async fn delete_existing_list(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    require_role(list_role(&state.db, user.id, id)?, Role::Owner)?;
+    let user_ids = list_audience(&state.db, id)?;
+    let filter = TodoFilter {
+        list_id: Some(id),
+        ..TodoFilter::default()
+    };
+    let todos = list_todos(&state.db, user.id, &filter)?;
+
+    if delete_list(&state.db, user.id, id)? {
+        info!(id, "Deleted list");
+        for todo in &todos {
+            trigger_for(&state, &user_ids, WebhookEvent::TodoDeleted, todo);
+        }
+        state
+            .events
+            .publish(user_ids, Change::ListChanged { list_id: id });
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound)
+    }
+}
+
+pub async fn list_todos_of_list(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    conditions: Conditions,
+    Path(id): Path<i64>,
+    Query(mut filter): Query<TodoFilter>,
+) -> Result<Tagged<Vec<Todo>>, AppError> {
+    if get_list(&state.db, user.id, id)?.is_none() {
+        return Err(AppError::NotFound);
+    }
+
+    normalize_filter(&mut filter);
+    filter.list_id = Some(id);
+    let todos = list_todos(&state.db, user.id, &filter)?;
+    info!(list_id = id, count = todos.len(), "Listed todos");
+    Ok(conditions.respond(content_tag(&todos), todos))
+}
+
+/// Fails with 404 unless the todo is in the list.
+fn require_in_list(state: &AppState, user_id: i64, id: i64, list_id: i64) -> Result<(), AppError> {
+    match get_todo(&state.db, user_id, id)? {
+        Some(todo) if todo.list_id == list_id => Ok(()),
+        _ => Err(AppError::NotFound),
+    }
+}
+
+/// Creates a todo in the list. Unlike `POST /api/todos`, this works with API
+/// tokens restricted to the list.
+pub async fn create_todo_in_list(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    Path(list_id): Path<i64>,
+    Json(mut req): Json<CreateTodo>,
+) -> Result<(StatusCode, Json<Todo>), AppError> {
+    if let Some(parent_id) = req.parent_id {
+        require_in_list(&state, user.id, parent_id, list_id)?;
+    }
+    req.list_id = Some(list_id);
+    create_new_todo(Auth(user), State(state), Json(req)).await
+}
+
+pub async fn get_todo_of_list(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    conditions: Conditions,
+    Path((list_id, id)): Path<(i64, i64)>,
+) -> Result<Tagged<TodoDetail>, AppError> {
+    require_in_list(&state, user.id, id, list_id)?;
+    get_single_todo(Auth(user), State(state), conditions, Path(id)).await
+}
+
+/// Updates a todo of the list, which it can't be moved out of here.
+pub async fn update_todo_of_list(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    conditions: Conditions,
+    Path((list_id, id)): Path<(i64, i64)>,
+    Json(req): Json<UpdateTodo>,
+) -> Result<([(HeaderName, String); 1], Json<Todo>), AppError> {
+    require_in_list(&state, user.id, id, list_id)?;
+    if req.list_id.is_some_and(|target| target != list_id) {
+        return Err(AppError::BadRequest(
+            "Todo cannot be moved to another list here",
+        ));
+    }
+    if let Some(Some(parent_id)) = req.parent_id {
+        require_in_list(&state, user.id, parent_id, list_id)?;
+    }
+    update_existing_todo(Auth(user), State(state), conditions, Path(id), Json(req)).await
+}
+
+pub async fn delete_todo_of_list(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    conditions: Conditions,
+    Path((list_id, id)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    require_in_list(&state, user.id, id, list_id)?;
+    delete_existing_todo(Auth(user), State(state), conditions, Path(id)).await
+}
+
+pub async fn reorder_list(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(req): Json<ReorderTodos>,
+) -> Result<Json<Vec<Todo>>, AppError> {
+    require_role(list_role(&state.db, user.id, id)?, Role::Editor)?;
+
+    reorder_todos(&state.db, id, &req.ids)?;
+    let filter = TodoFilter {
+        list_id: Some(id),
+        ..TodoFilter::default()
+    };
+    let todos = list_todos(&state.db, user.id, &filter)?;
+    info!(list_id = id, "Reordered todos");
+    let ids = todos.iter().map(|todo| todo.id).collect();
+    notify(&state, id, Change::Reordered { list_id: id, ids });
+    Ok(Json(todos))
+}
+
+pub async fn plain_text_list(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Query(mut filter): Query<TodoFilter>,
+    Query(options): Query<PlainTextOptions>,
+) -> Result<impl IntoResponse, AppError> {
+    if get_list(&state.db, user.id, id)?.is_none() {
+        return Err(AppError::NotFound);
+    }
+
+    normalize_filter(&mut filter);
+    filter.list_id = Some(id);
+    filter.completed = Some(false);
+    let todos = list_todos(&state.db, user.id, &filter)?;
+    Ok(plain_text_response(&todos, &options))
+}
+
+pub async fn list_all_members(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<ListMember>>, AppError> {
+    require_role(list_role(&state.db, user.id, id)?, Role::Viewer)?;
+
+    let members = list_members(&state.db, id)?;
+    Ok(Json(members))
+}
+
+pub async fn add_member(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(req): Json<AddListMember>,
+) -> Result<(StatusCode, Json<ListMember>), AppError> {
+    require_role(list_role(&state.db, user.id, id)?, Role::Owner)?;
+    if req.role == Role::Owner {
+        return Err(AppError::BadRequest("Lists have a single owner"));
+    }
+
+    let member = set_list_member(&state.db, id, req.username.trim(), req.role)?
+        .ok_or(AppError::BadRequest("Unknown user"))?;
+    info!(list_id = id, user = %member.username, role = member.role.as_str(), "Shared list");
+    notify(&state, id, Change::ListChanged { list_id: id });
+    Ok((StatusCode::CREATED, Json(member)))
+}
+
+pub async fn update_member(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    Path((id, user_id)): Path<(i64, i64)>,
+    Json(req): Json<UpdateListMember>,
+) -> Result<Json<ListMember>, AppError> {
+    require_role(list_role(&state.db, user.id, id)?, Role::Owner)?;
+    if req.role == Role::Owner {
+        return Err(AppError::BadRequest("Lists have a single owner"));
+    }
+
+    match update_list_member(&state.db, id, user_id, req.role)? {
+        Some(member) => {
+            info!(list_id = id, user = %member.username, role = member.role.as_str(), "Changed member role");
+            notify(&state, id, Change::ListChanged { list_id: id });
+            Ok(Json(member))
+        }
+        None => Err(AppError::NotFound),
+    }
+}
+
+/// Removes a member from a list. Besides the owner, members may remove
+/// themselves to leave a list.
+pub async fn remove_member(
+    Auth(user): Auth,
+    State(state): State<AppState>,
+    Path((id, user_id)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let required = if user_id == user.id {
+        Role::Viewer
+    } else {
+        Role::Owner
+    };
+    require_role(list_role(&state.db, user.id, id)?, required)?;
+    let user_ids = list_audience(&state.db, id)?;
+
+    if remove_list_member(&state.db, id, user_id)? {
+        info!(list_id = id, user_id, "Removed list member");
+        state
+            .events
+            .publish(user_ids, Change::ListChanged { list_id: id });
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound)
+    }
 }
@@ -1,42 +1,98 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use std::time::Instant;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect, Response},
+    Json,
+};
 use axum_extra::extract::{
     cookie::{Cookie, SameSite},
-    CookieJar,
+    CookieJar, Query,
 };
 use serde_json::json;
-use tracing::info;
+use tracing::{info, warn};
 
-use crate::auth::{generate_session_id, generate_token, verify_password};
-use crate::db::{create_api_token, create_session, delete_api_token, delete_session, list_api_tokens};
+use crate::auth::{
+    generate_session_id, generate_token, session_public_id, verify_dummy_password,
+    verify_password,
+};
+use crate::dates::unix_now;
+use crate::db::{
+    create_api_token, create_session, delete_api_token, delete_session, delete_user_sessions,
+    get_user_by_name, get_user_credentials, list_api_tokens, list_role, list_sessions,
+};
 use crate::error::AppError;
-use crate::middleware::SessionAuth;
-use crate::models::{CreateApiToken, LoginRequest, Session};
+use crate::handlers::totp::verify_second_factor;
+use crate::middleware::{ClientInfo, SessionAuth};
+use crate::models::{
+    CreateApiToken, CreatedApiToken, LoginRequest, OidcCallback, Session, SessionInfo, User,
+};
+use crate::rate_limit::{Lockout, LoginLimiter};
 use crate::AppState;
 
 pub async fn login(
     State(state): State<AppState>,
+    client: ClientInfo,
     jar: CookieJar,
     Json(req): Json<LoginRequest>,
 ) -> Result<(CookieJar, impl IntoResponse), AppError> {
-    if !verify_password(&req.password, &state.password_hash) {
-        return Err(AppError::Unauthorized);
+    let limiter = &state.login_limiter;
+    if let Err(lockout) = limiter.check(client.ip, Instant::now()) {
+        return Err(AppError::TooManyRequests { retry_after: lockout.retry_after() });
+    }
+
+    let user = match get_user_credentials(&state.db, &req.username)? {
+        Some((user, password_hash)) if verify_password(&req.password, &password_hash) => user,
+        credentials => {
+            if credentials.is_none() {
+                verify_dummy_password(&req.password);
+            }
+            record_login_failure(limiter, &client, &req.username);
+            return Err(AppError::Unauthorized);
+        }
+    };
+
+    if user.totp_enabled {
+        let code = req.totp_code.as_deref().map(str::trim).unwrap_or_default();
+        if code.is_empty() {
+            return Err(AppError::TotpRequired);
+        }
+        if !verify_second_factor(&state.db, user.id, code)? {
+            record_login_failure(limiter, &client, &req.username);
+            return Err(AppError::Unauthorized);
+        }
     }
+    limiter.record_success(client.ip);
+
+    let jar = start_session(&state, jar, &user, client)?;
+    info!(user = %user.username, "User logged in");
+
+    Ok((jar, Json(json!({ "success": true }))))
+}
 
+/// Creates a session for `user` and sets its cookie.
+fn start_session(
+    state: &AppState,
+    jar: CookieJar,
+    user: &User,
+    client: ClientInfo,
+) -> Result<CookieJar, AppError> {
     let session_id = generate_session_id();
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as i64;
+    let now = unix_now();
     let expires_at = now + 7 * 24 * 60 * 60;
 
     let session = Session {
         id: session_id.clone(),
+        user_id: user.id,
         created_at: now,
         expires_at,
+        last_seen_at: Some(now),
+        ip: client.ip.map(|ip| ip.to_string()),
+        user_agent: client.user_agent,
     };
 
     create_session(&state.db, &session)?;
-    info!("User logged in");
 
     let cookie = Cookie::build(("session", session_id))
         .path("/")
@@ -44,7 +100,90 @@ pub async fn login(
         .same_site(SameSite::Strict)
         .max_age(time::Duration::days(7));
 
-    Ok((jar.add(cookie), Json(json!({ "success": true }))))
+    Ok(jar.add(cookie))
+}
+
+/// Cookie tying the redirect back from the OpenID Connect provider to the
+/// browser that started the login.
+const OIDC_STATE_COOKIE: &str = "oidc_state";
+
+/// Sends the browser to the OpenID Connect provider to log in.
+pub async fn oidc_login(
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<(CookieJar, Redirect), AppError> {
+    let oidc = state.oidc.as_ref().ok_or(AppError::NotFound)?;
+    let (login_state, url) = oidc.start_login();
+
+    // Lax, since the provider redirects back from another site
+    let cookie = Cookie::build((OIDC_STATE_COOKIE, login_state))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::minutes(10));
+
+    Ok((jar.add(cookie), Redirect::to(&url)))
+}
+
+/// Finishes an OpenID Connect login and logs in the user named by the ID
+/// token. Failures end up on the login page.
+pub async fn oidc_callback(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    jar: CookieJar,
+    Query(params): Query<OidcCallback>,
+) -> Result<(CookieJar, Response), AppError> {
+    let oidc = state.oidc.as_ref().ok_or(AppError::NotFound)?;
+    let expected_state = jar.get(OIDC_STATE_COOKIE).map(|cookie| cookie.value().to_string());
+    let jar = jar.remove(Cookie::build((OIDC_STATE_COOKIE, "")).path("/"));
+    let failed = Redirect::to(&format!("{}/login?error=oidc", state.base_path)).into_response();
+
+    if let Some(error) = params.error {
+        warn!(%error, "OpenID Connect provider refused login");
+        return Ok((jar, failed));
+    }
+    let (Some(code), Some(login_state)) = (params.code, params.state) else {
+        return Err(AppError::BadRequest("Missing code or state"));
+    };
+    if expected_state.as_deref() != Some(login_state.as_str()) {
+        warn!("OpenID Connect login finished in another browser");
+        return Ok((jar, failed));
+    }
+
+    let username = match oidc.finish_login(&login_state, &code).await {
+        Ok(username) => username,
+        Err(err) => {
+            warn!(%err, "OpenID Connect login failed");
+            return Ok((jar, failed));
+        }
+    };
+    let Some(user) = get_user_by_name(&state.db, &username)? else {
+        warn!(user = %username, "OpenID Connect login for unknown user");
+        return Ok((jar, failed));
+    };
+
+    let jar = start_session(&state, jar, &user, client)?;
+    info!(user = %user.username, "User logged in via OpenID Connect");
+
+    // A redirect response would still count as coming from the provider's
+    // site, so the strict session cookie wouldn't be sent along.
+    let index = if state.base_path.is_empty() { "/" } else { state.base_path.as_str() };
+    let page = format!(
+        r#"<!DOCTYPE html><meta http-equiv="refresh" content="0;url={index}"><a href="{index}">Continue</a>"#
+    );
+    Ok((jar, Html(page).into_response()))
+}
+
+fn record_login_failure(limiter: &LoginLimiter, client: &ClientInfo, username: &str) {
+    match limiter.record_failure(client.ip, Instant::now()) {
+        Some(Lockout::Ip(wait)) => {
+            warn!(ip = ?client.ip, user = %username, ?wait, "Locked out client after failed logins")
+        }
+        Some(Lockout::Global(wait)) => {
+            warn!(?wait, "Locked out all logins after failed logins")
+        }
+        None => {}
+    }
 }
 
 pub async fn logout(
@@ -64,31 +203,109 @@ pub async fn logout(
     Ok((jar.remove(cookie), Json(json!({ "success": true }))))
 }
 
+pub async fn list_all_sessions(
+    SessionAuth(user): SessionAuth,
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<Json<Vec<SessionInfo>>, AppError> {
+    let current = jar.get("session").map(|cookie| cookie.value().to_string());
+    let sessions = list_sessions(&state.db, user.id)?
+        .into_iter()
+        .map(|session| SessionInfo {
+            id: session_public_id(&session.id),
+            current: current.as_deref() == Some(session.id.as_str()),
+            created_at: session.created_at,
+            expires_at: session.expires_at,
+            last_seen_at: session.last_seen_at,
+            ip: session.ip,
+            user_agent: session.user_agent,
+        })
+        .collect();
+    Ok(Json(sessions))
+}
+
+pub async fn revoke_session(
+    SessionAuth(user): SessionAuth,
+    State(state): State<AppState>,
+    axum::extract::Path(id): axum::extract::Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let session = list_sessions(&state.db, user.id)?
+        .into_iter()
+        .find(|session| session_public_id(&session.id) == id)
+        .ok_or(AppError::NotFound)?;
+
+    delete_session(&state.db, &session.id)?;
+    info!(id, "Revoked session");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Logs the user out of all sessions, including the current one.
+pub async fn revoke_all_sessions(
+    SessionAuth(user): SessionAuth,
+    State(state): State<AppState>,
+    jar: CookieJar,
+) -> Result<(CookieJar, impl IntoResponse), AppError> {
+    delete_user_sessions(&state.db, user.id)?;
+    info!(user = %user.username, "Logged out everywhere");
+
+    let cookie = Cookie::build(("session", ""))
+        .path("/")
+        .http_only(true)
+        .max_age(time::Duration::seconds(0));
+
+    Ok((jar.remove(cookie), StatusCode::NO_CONTENT))
+}
+
 pub async fn list_tokens(
-    _auth: SessionAuth,
+    SessionAuth(user): SessionAuth,
     State(state): State<AppState>,
 ) -> Result<Json<Vec<crate::models::ApiToken>>, AppError> {
-    let tokens = list_api_tokens(&state.db)?;
+    let tokens = list_api_tokens(&state.db, user.id)?;
     Ok(Json(tokens))
 }
 
 pub async fn create_token(
-    _auth: SessionAuth,
+    SessionAuth(user): SessionAuth,
     State(state): State<AppState>,
     Json(req): Json<CreateApiToken>,
-) -> Result<Json<crate::models::ApiToken>, AppError> {
+) -> Result<Json<CreatedApiToken>, AppError> {
+    if req.scopes.is_empty() {
+        return Err(AppError::BadRequest("Tokens need at least one scope"));
+    }
+    if let Some(list_id) = req.list_id {
+        if list_role(&state.db, user.id, list_id)?.is_none() {
+            return Err(AppError::BadRequest("List not found"));
+        }
+    }
+
+    let now = unix_now();
+    if req.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(AppError::BadRequest("Expiry must lie in the future"));
+    }
+
     let token_value = generate_token();
-    let token = create_api_token(&state.db, &token_value, req.name.as_deref())?;
-    info!(name = ?req.name, "Created API token");
-    Ok(Json(token))
+    let api_token = create_api_token(
+        &state.db,
+        user.id,
+        &token_value,
+        req.name.as_deref(),
+        &req.scopes,
+        req.list_id,
+        req.expires_at,
+    )?;
+    info!(name = ?req.name, prefix = %api_token.prefix, scopes = ?req.scopes, "Created API token");
+    Ok(Json(CreatedApiToken {
+        api_token,
+        token: token_value,
+    }))
 }
 
 pub async fn revoke_token(
-    _auth: SessionAuth,
+    SessionAuth(user): SessionAuth,
     State(state): State<AppState>,
     axum::extract::Path(id): axum::extract::Path<i64>,
 ) -> Result<impl IntoResponse, AppError> {
-    if delete_api_token(&state.db, id)? {
+    if delete_api_token(&state.db, user.id, id)? {
         info!(id, "Revoked API token");
         Ok(StatusCode::NO_CONTENT)
     } else {
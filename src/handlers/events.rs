@@ -0,0 +1,67 @@
+use std::convert::Infallible;
+
+use axum::extract::State;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::Extension;
+use futures_util::stream::{self, Stream};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::middleware::{Auth, Login};
+use crate::AppState;
+
+/// Streams changes to the user's todos and lists as Server-Sent Events, so
+/// that other devices can apply them as they happen. A `resync` event means
+/// that changes were missed and everything has to be reloaded.
+///
+/// The stream ends once its login is revoked or expires, which is checked
+/// before every event and every `Login::CHECK_INTERVAL`.
+pub async fn events(
+    Auth(user): Auth,
+    Extension(login): Extension<Login>,
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    info!(user = %user.username, "Opened event stream");
+    let receiver = state.events.subscribe();
+    let check = tokio::time::interval(Login::CHECK_INTERVAL);
+    let user_id = user.id;
+
+    let events = stream::unfold((receiver, check), move |(mut receiver, mut check)| {
+        let (db, login) = (state.db.clone(), login.clone());
+        async move {
+            loop {
+                let event = tokio::select! {
+                    envelope = receiver.recv() => match envelope {
+                        Ok(envelope) if envelope.user_ids.contains(&user_id) => {
+                            let change = &envelope.change;
+                            Event::default()
+                                .event(change.name())
+                                .json_data(change)
+                                .expect("serializing change")
+                        }
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!(user_id, missed, "Event stream fell behind");
+                            Event::default().event("resync").data("{}")
+                        }
+                        Err(RecvError::Closed) => return None,
+                    },
+                    _ = check.tick() => {
+                        if login.is_valid(&db) {
+                            continue;
+                        }
+                        info!(user_id, "Login of event stream was revoked or expired");
+                        return None;
+                    }
+                };
+                if !login.is_valid(&db) {
+                    info!(user_id, "Login of event stream was revoked or expired");
+                    return None;
+                }
+                return Some((Ok(event), (receiver, check)));
+            }
+        }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
@@ -1,3 +1,8 @@
 pub mod api;
 pub mod auth;
+pub mod events;
+pub mod totp;
+pub mod users;
 pub mod web;
+pub mod webhooks;
+pub mod ws;
@@ -0,0 +1,116 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use tracing::{info, warn};
+
+use crate::auth::{generate_recovery_code, hash_recovery_code};
+use crate::dates::unix_now;
+use crate::db::{
+    count_recovery_codes, disable_totp, enable_totp, get_totp_state, record_totp_step,
+    set_pending_totp_secret, use_recovery_code, DbPool,
+};
+use crate::error::AppError;
+use crate::middleware::SessionAuth;
+use crate::models::{RecoveryCodes, TotpCode, TotpSetup, TotpStatus};
+use crate::totp;
+use crate::AppState;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Checks a TOTP code, or a recovery code, of a user with two-factor
+/// authentication enabled. Accepted codes cannot be used again.
+pub fn verify_second_factor(db: &DbPool, user_id: i64, code: &str) -> Result<bool, AppError> {
+    let state = get_totp_state(db, user_id)?;
+    let Some(secret) = state.secret else {
+        return Ok(false);
+    };
+
+    if let Some(step) = totp::verify(&secret, code, unix_now() as u64, state.last_step) {
+        record_totp_step(db, user_id, step)?;
+        return Ok(true);
+    }
+    if use_recovery_code(db, user_id, &hash_recovery_code(code))? {
+        info!(user_id, "Used recovery code");
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+pub async fn totp_status(
+    SessionAuth(user): SessionAuth,
+    State(state): State<AppState>,
+) -> Result<Json<TotpStatus>, AppError> {
+    Ok(Json(TotpStatus {
+        enabled: user.totp_enabled,
+        recovery_codes_left: count_recovery_codes(&state.db, user.id)?,
+    }))
+}
+
+/// Starts enrollment with a new secret, which takes effect once a code for it
+/// is confirmed through `enable`.
+pub async fn setup_totp(
+    SessionAuth(user): SessionAuth,
+    State(state): State<AppState>,
+) -> Result<Json<TotpSetup>, AppError> {
+    if user.totp_enabled {
+        return Err(AppError::BadRequest(
+            "Two-factor authentication is already enabled",
+        ));
+    }
+
+    let secret = totp::generate_secret();
+    set_pending_totp_secret(&state.db, user.id, &secret)?;
+
+    let otpauth_uri = totp::otpauth_uri(&secret, &user.username);
+    Ok(Json(TotpSetup {
+        qr_code_svg: totp::qr_code_svg(&otpauth_uri),
+        otpauth_uri,
+        secret,
+    }))
+}
+
+pub async fn enable(
+    SessionAuth(user): SessionAuth,
+    State(state): State<AppState>,
+    Json(req): Json<TotpCode>,
+) -> Result<Json<RecoveryCodes>, AppError> {
+    let totp_state = get_totp_state(&state.db, user.id)?;
+    let Some(secret) = totp_state.pending_secret else {
+        return Err(AppError::BadRequest(
+            "Set up two-factor authentication first",
+        ));
+    };
+    let Some(step) = totp::verify(&secret, &req.code, unix_now() as u64, None) else {
+        return Err(AppError::BadRequest("Invalid code"));
+    };
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+    let hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| hash_recovery_code(code))
+        .collect();
+    enable_totp(&state.db, user.id, step, &hashes)?;
+
+    info!(user = %user.username, "Enabled two-factor authentication");
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+pub async fn disable(
+    SessionAuth(user): SessionAuth,
+    State(state): State<AppState>,
+    Json(req): Json<TotpCode>,
+) -> Result<impl IntoResponse, AppError> {
+    if !user.totp_enabled {
+        return Err(AppError::BadRequest(
+            "Two-factor authentication is not enabled",
+        ));
+    }
+    if !verify_second_factor(&state.db, user.id, &req.code)? {
+        warn!(user = %user.username, "Invalid code to disable two-factor authentication");
+        return Err(AppError::BadRequest("Invalid code"));
+    }
+
+    disable_totp(&state.db, user.id)?;
+    info!(user = %user.username, "Disabled two-factor authentication");
+    Ok(StatusCode::NO_CONTENT)
+}
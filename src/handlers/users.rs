@@ -0,0 +1,59 @@
+use axum::extract::{Path, State};
+use axum::{http::StatusCode, response::IntoResponse, Json};
+use tracing::info;
+
+use crate::auth::hash_password;
+use crate::db::{create_user, delete_user, list_users};
+use crate::error::AppError;
+use crate::middleware::{AdminAuth, Auth};
+use crate::models::{CreateUser, User};
+use crate::AppState;
+
+pub async fn current_user(Auth(user): Auth) -> Json<User> {
+    Json(user)
+}
+
+pub async fn list_all_users(
+    _auth: AdminAuth,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<User>>, AppError> {
+    let users = list_users(&state.db)?;
+    Ok(Json(users))
+}
+
+pub async fn create_new_user(
+    _auth: AdminAuth,
+    State(state): State<AppState>,
+    Json(req): Json<CreateUser>,
+) -> Result<(StatusCode, Json<User>), AppError> {
+    let username = req.username.trim();
+    if username.is_empty() {
+        return Err(AppError::BadRequest("Username cannot be empty"));
+    }
+    if req.password.is_empty() {
+        return Err(AppError::BadRequest("Password cannot be empty"));
+    }
+
+    let password_hash = hash_password(&req.password);
+    let user = create_user(&state.db, username, &password_hash, req.is_admin)?
+        .ok_or(AppError::BadRequest("Username is already taken"))?;
+    info!(id = user.id, username = %user.username, "Created user");
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+pub async fn delete_existing_user(
+    AdminAuth(admin): AdminAuth,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    if id == admin.id {
+        return Err(AppError::BadRequest("Cannot delete your own account"));
+    }
+
+    if delete_user(&state.db, id)? {
+        info!(id, "Deleted user");
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound)
+    }
+}
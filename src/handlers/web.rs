@@ -3,15 +3,24 @@ use axum::http::{header, StatusCode};
 use axum::response::{Html, IntoResponse, Redirect, Response};
 
 use crate::assets::{APP_JS, INDEX_HTML, LOGIN_HTML, OUTPUT_CSS};
-use crate::middleware::MaybeAuth;
+use crate::middleware::{CsrfToken, MaybeAuth};
 use crate::AppState;
 
-pub async fn index(MaybeAuth(authenticated): MaybeAuth, State(state): State<AppState>) -> Response {
+pub async fn index(
+    MaybeAuth(authenticated): MaybeAuth,
+    CsrfToken(csrf_token): CsrfToken,
+    State(state): State<AppState>,
+) -> Response {
     if !authenticated {
         let login_path = format!("{}/login", state.base_path);
         return Redirect::to(&login_path).into_response();
     }
-    Html(inject_base_path(INDEX_HTML, &state.base_path)).into_response()
+    let html = inject_base_path(INDEX_HTML, &state.base_path);
+    Html(inject_csrf_token(
+        &html,
+        csrf_token.as_deref().unwrap_or_default(),
+    ))
+    .into_response()
 }
 
 pub async fn login_page(
@@ -26,7 +35,14 @@ pub async fn login_page(
         };
         return Redirect::to(&index_path).into_response();
     }
-    Html(inject_base_path(LOGIN_HTML, &state.base_path)).into_response()
+    let mut html = inject_base_path(LOGIN_HTML, &state.base_path);
+    if state.oidc.is_some() {
+        html = html.replace(
+            "<head>",
+            "<head>\n    <script>window.OIDC_ENABLED = true;</script>",
+        );
+    }
+    Html(html).into_response()
 }
 
 pub async fn static_file(Path(path): Path<String>) -> Response {
@@ -56,3 +72,9 @@ fn inject_base_path(html: &str, base_path: &str) -> String {
     html.replace("href=\"/static/", &format!("href=\"{}/static/", base_path))
         .replace("src=\"/static/", &format!("src=\"{}/static/", base_path))
 }
+
+fn inject_csrf_token(html: &str, csrf_token: &str) -> String {
+    // The web UI sends the token along with every change
+    let script = format!(r#"<script>window.CSRF_TOKEN = "{}";</script>"#, csrf_token);
+    html.replace("<head>", &format!("<head>\n    {}", script))
+}
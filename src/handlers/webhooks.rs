@@ -0,0 +1,66 @@
+use axum::extract::{Path, State};
+use axum::{http::StatusCode, response::IntoResponse, Json};
+use tracing::info;
+
+use crate::auth::generate_token;
+use crate::db::{create_webhook, delete_webhook, list_webhook_deliveries, list_webhooks};
+use crate::error::AppError;
+use crate::middleware::SessionAuth;
+use crate::models::{CreateWebhook, CreatedWebhook, Webhook, WebhookDelivery};
+use crate::AppState;
+
+pub async fn list_all_webhooks(
+    SessionAuth(user): SessionAuth,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<Webhook>>, AppError> {
+    let webhooks = list_webhooks(&state.db, user.id)?;
+    Ok(Json(webhooks))
+}
+
+pub async fn create_new_webhook(
+    SessionAuth(user): SessionAuth,
+    State(state): State<AppState>,
+    Json(req): Json<CreateWebhook>,
+) -> Result<(StatusCode, Json<CreatedWebhook>), AppError> {
+    let url = state.webhooks.check_url(req.url.trim()).await?;
+    if req.events.is_empty() {
+        return Err(AppError::BadRequest("Webhooks need at least one event"));
+    }
+    let secret = match req.secret {
+        Some(secret) if secret.trim().is_empty() => {
+            return Err(AppError::BadRequest("Secret cannot be empty"))
+        }
+        Some(secret) => secret,
+        None => generate_token(),
+    };
+
+    let webhook = create_webhook(&state.db, user.id, url.as_str(), &req.events, &secret)?;
+    info!(id = webhook.id, url = %webhook.url, events = ?webhook.events, "Created webhook");
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedWebhook { webhook, secret }),
+    ))
+}
+
+pub async fn delete_existing_webhook(
+    SessionAuth(user): SessionAuth,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    if delete_webhook(&state.db, user.id, id)? {
+        info!(id, "Deleted webhook");
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound)
+    }
+}
+
+/// The delivery log of a webhook: its latest deliveries and how they went.
+pub async fn list_deliveries(
+    SessionAuth(user): SessionAuth,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<WebhookDelivery>>, AppError> {
+    let deliveries = list_webhook_deliveries(&state.db, user.id, id)?.ok_or(AppError::NotFound)?;
+    Ok(Json(deliveries))
+}
@@ -0,0 +1,299 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use axum_extra::extract::Query;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+use crate::auth::constant_time_eq;
+use crate::error::AppError;
+use crate::etag::{Conditions, Tagged};
+use crate::handlers::api;
+use crate::middleware::{Auth, AuthError, CsrfToken, Login};
+use crate::models::{ApiToken, Scope, User};
+use crate::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct WsParams {
+    /// Browsers can't set headers on WebSocket requests, so connections made
+    /// with a browser login pass their CSRF token here.
+    csrf_token: Option<String>,
+}
+
+/// A command sent by the client. `id` is echoed in the reply.
+#[derive(Debug, Deserialize)]
+struct Command {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// Opens a WebSocket that pushes the same changes as `GET /api/events` and
+/// accepts commands mirroring the REST API, e.g.
+/// `{"id": 1, "method": "create_todo", "params": {"title": "Milk"}}`.
+pub async fn websocket(
+    Auth(user): Auth,
+    Extension(login): Extension<Login>,
+    token: Option<Extension<ApiToken>>,
+    CsrfToken(csrf_token): CsrfToken,
+    Query(params): Query<WsParams>,
+    State(state): State<AppState>,
+    upgrade: WebSocketUpgrade,
+) -> Result<Response, AuthError> {
+    // Browsers send cookies along with WebSocket requests from other sites
+    if let (None, Some(expected)) = (&token, csrf_token) {
+        let given = params.csrf_token.unwrap_or_default();
+        if !constant_time_eq(&expected, &given) {
+            warn!(user = %user.username, "WebSocket without valid CSRF token");
+            return Err(AuthError::InvalidCsrfToken);
+        }
+    }
+    let can_write = token.is_none_or(|Extension(token)| {
+        token
+            .scopes
+            .iter()
+            .any(|granted| granted.grants(Scope::TodosWrite))
+    });
+
+    info!(user = %user.username, "Opened WebSocket");
+    Ok(upgrade.on_upgrade(move |socket| serve(socket, state, user, login, can_write)))
+}
+
+/// Serves a WebSocket until the client leaves or its login is revoked or
+/// expires, which is checked before every command and every
+/// `Login::CHECK_INTERVAL`.
+async fn serve(mut socket: WebSocket, state: AppState, user: User, login: Login, can_write: bool) {
+    let mut receiver = state.events.subscribe();
+    let mut check = tokio::time::interval(Login::CHECK_INTERVAL);
+    let mut logged_out = false;
+
+    loop {
+        let reply = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    if !login.is_valid(&state.db) {
+                        logged_out = true;
+                        break;
+                    }
+                    handle_command(&state, &user, can_write, text.as_str()).await
+                }
+                // Pings are answered by axum
+                Some(Ok(Message::Binary(_) | Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+            },
+            envelope = receiver.recv() => match envelope {
+                Ok(envelope) if envelope.user_ids.contains(&user.id) => {
+                    json!({ "event": envelope.change.name(), "data": envelope.change })
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(user_id = user.id, missed, "WebSocket fell behind");
+                    json!({ "event": "resync", "data": {} })
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = check.tick() => {
+                if login.is_valid(&state.db) {
+                    continue;
+                }
+                logged_out = true;
+                break;
+            }
+        };
+
+        if socket
+            .send(Message::Text(reply.to_string().into()))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    if logged_out {
+        info!(user = %user.username, "Login of WebSocket was revoked or expired");
+        let _ = socket.send(Message::Close(None)).await;
+    }
+    info!(user = %user.username, "Closed WebSocket");
+}
+
+/// Runs a command and returns the reply: `{"id", "result"}` on success, or
+/// `{"id", "error": {"status", "message"}}` with the status and message the
+/// REST API would have responded with.
+async fn handle_command(state: &AppState, user: &User, can_write: bool, text: &str) -> Value {
+    let Ok(command) = serde_json::from_str::<Command>(text) else {
+        return json!({
+            "id": null,
+            "error": { "status": 400, "message": "Invalid command" },
+        });
+    };
+
+    let result = if !can_write && !is_read_only(&command.method) {
+        warn!(method = %command.method, "API token lacks scope");
+        Err(AppError::Forbidden)
+    } else {
+        dispatch(state, user, &command.method, command.params).await
+    };
+
+    match result {
+        Ok(result) => json!({ "id": command.id, "result": result }),
+        Err(err) => {
+            let response = err.into_response();
+            let status = response.status().as_u16();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap_or_default();
+            let message = serde_json::from_slice::<Value>(&body)
+                .ok()
+                .and_then(|body| body["error"].as_str().map(str::to_string))
+                .unwrap_or_default();
+            json!({
+                "id": command.id,
+                "error": { "status": status, "message": message },
+            })
+        }
+    }
+}
+
+fn is_read_only(method: &str) -> bool {
+    matches!(
+        method,
+        "list_todos" | "search" | "sync" | "get_todo" | "list_lists" | "get_list" | "list_members"
+    )
+}
+
+/// Calls the REST handler behind a command. Ids that are part of the path in
+/// the REST API are passed as parameters: `id` for the todo or list, and
+/// `list_id` and `user_id` for list members. Instead of `If-Match`, changes to
+/// a todo take its expected `version`.
+async fn dispatch(
+    state: &AppState,
+    user: &User,
+    method: &str,
+    params: Value,
+) -> Result<Value, AppError> {
+    let auth = || Auth(user.clone());
+    let state = || State(state.clone());
+    let none = Conditions::default;
+
+    let result = match method {
+        "list_todos" => match params.get("list_id").and_then(Value::as_i64) {
+            Some(list_id) => {
+                let filter = parse(params)?;
+                tagged(
+                    api::list_todos_of_list(auth(), state(), none(), Path(list_id), Query(filter))
+                        .await?,
+                )
+            }
+            None => {
+                tagged(api::list_all_todos(auth(), state(), none(), Query(parse(params)?)).await?)
+            }
+        },
+        "search" => to_value(api::search(auth(), state(), Query(parse(params)?)).await?),
+        "sync" => to_value(api::sync(auth(), state(), Query(parse(params)?)).await?),
+        "get_todo" => {
+            let id = param(&params, "id")?;
+            tagged(api::get_single_todo(auth(), state(), none(), Path(id)).await?)
+        }
+        "create_todo" => {
+            let (_, todo) = api::create_new_todo(auth(), state(), Json(parse(params)?)).await?;
+            to_value(todo)
+        }
+        "update_todo" => {
+            let id = param(&params, "id")?;
+            let if_match = if_match(&params);
+            let req = parse(params)?;
+            let (_, todo) =
+                api::update_existing_todo(auth(), state(), if_match, Path(id), Json(req)).await?;
+            to_value(todo)
+        }
+        "delete_todo" => {
+            let id = param(&params, "id")?;
+            let if_match = if_match(&params);
+            api::delete_existing_todo(auth(), state(), if_match, Path(id)).await?;
+            Value::Null
+        }
+        "reorder_todos" => match params.get("list_id").and_then(Value::as_i64) {
+            Some(list_id) => {
+                let req = parse(params)?;
+                to_value(api::reorder_list(auth(), state(), Path(list_id), Json(req)).await?)
+            }
+            None => to_value(api::reorder(auth(), state(), Json(parse(params)?)).await?),
+        },
+        "list_lists" => tagged(api::list_all_lists(auth(), state(), none()).await?),
+        "get_list" => {
+            let id = param(&params, "id")?;
+            to_value(api::get_single_list(auth(), state(), Path(id)).await?)
+        }
+        "create_list" => {
+            let (_, list) = api::create_new_list(auth(), state(), Json(parse(params)?)).await?;
+            to_value(list)
+        }
+        "update_list" => {
+            let id = param(&params, "id")?;
+            let req = parse(params)?;
+            to_value(api::update_existing_list(auth(), state(), Path(id), Json(req)).await?)
+        }
+        "delete_list" => {
+            let id = param(&params, "id")?;
+            api::delete_existing_list(auth(), state(), Path(id)).await?;
+            Value::Null
+        }
+        "list_members" => {
+            let list_id = param(&params, "list_id")?;
+            to_value(api::list_all_members(auth(), state(), Path(list_id)).await?)
+        }
+        "add_member" => {
+            let list_id = param(&params, "list_id")?;
+            let req = parse(params)?;
+            let (_, member) = api::add_member(auth(), state(), Path(list_id), Json(req)).await?;
+            to_value(member)
+        }
+        "update_member" => {
+            let ids = (param(&params, "list_id")?, param(&params, "user_id")?);
+            let req = parse(params)?;
+            to_value(api::update_member(auth(), state(), Path(ids), Json(req)).await?)
+        }
+        "remove_member" => {
+            let ids = (param(&params, "list_id")?, param(&params, "user_id")?);
+            api::remove_member(auth(), state(), Path(ids)).await?;
+            Value::Null
+        }
+        _ => return Err(AppError::BadRequest("Unknown method")),
+    };
+    Ok(result)
+}
+
+/// Reads the request body or query of a command from its parameters, which
+/// may contain id parameters besides.
+fn parse<T: DeserializeOwned>(mut params: Value) -> Result<T, AppError> {
+    if params.is_null() {
+        params = json!({});
+    }
+    serde_json::from_value(params).map_err(|_| AppError::BadRequest("Invalid parameters"))
+}
+
+fn param(params: &Value, name: &str) -> Result<i64, AppError> {
+    params
+        .get(name)
+        .and_then(Value::as_i64)
+        .ok_or(AppError::BadRequest("Invalid parameters"))
+}
+
+/// The `version` parameter of a change, which works like `If-Match`.
+fn if_match(params: &Value) -> Conditions {
+    Conditions::version(params.get("version").and_then(Value::as_i64))
+}
+
+fn tagged<T: serde::Serialize>(tagged: Tagged<T>) -> Value {
+    serde_json::to_value(tagged.body).expect("serializing response")
+}
+
+fn to_value<T: serde::Serialize>(Json(value): Json<T>) -> Value {
+    serde_json::to_value(value).expect("serializing response")
+}
@@ -1,28 +1,57 @@
 pub mod assets;
 pub mod auth;
+pub mod dates;
 pub mod db;
 pub mod error;
+pub mod etag;
+pub mod events;
 pub mod handlers;
+pub mod markdown;
 pub mod middleware;
 pub mod models;
+pub mod oidc;
+pub mod rate_limit;
+pub mod totp;
+pub mod webhooks;
 
 use std::sync::Arc;
 
+use auth::CsrfKey;
 use axum::{
+    http::HeaderName,
     routing::{delete, get, post, put},
     Router,
 };
 use db::DbPool;
+use events::EventHub;
+use oidc::OidcClient;
+use rate_limit::{LoginLimiter, TrustedProxy};
+use webhooks::Webhooks;
 
 #[derive(Clone)]
 pub struct AppState {
     pub db: DbPool,
-    pub password_hash: Arc<String>,
     pub base_path: Arc<String>,
+    /// Reverse proxies whose `X-Forwarded-For` header is trusted.
+    pub trusted_proxies: Arc<Vec<TrustedProxy>>,
+    /// Header naming the logged-in user, set by an authenticating reverse
+    /// proxy. Only honored on requests coming straight from a trusted proxy.
+    pub auth_header: Option<HeaderName>,
+    pub login_limiter: Arc<LoginLimiter>,
+    /// Key for the CSRF tokens that browser logins send along with changes.
+    pub csrf_key: Arc<CsrfKey>,
+    /// Changes to todos and lists, for the clients of `GET /api/events`.
+    pub events: Arc<EventHub>,
+    /// Queue of webhook deliveries, sent by `webhooks::deliver`.
+    pub webhooks: Arc<Webhooks>,
+    /// Set if users can log in through an OpenID Connect provider.
+    pub oidc: Option<Arc<OidcClient>>,
 }
 
 pub fn create_app(state: AppState) -> Router {
     let base_path = state.base_path.clone();
+    let csrf_protection =
+        axum::middleware::from_fn_with_state(state.clone(), middleware::csrf_protection);
 
     let app_routes = Router::new()
         .route("/", get(handlers::web::index))
@@ -30,19 +59,102 @@ pub fn create_app(state: AppState) -> Router {
         .route("/static/{*path}", get(handlers::web::static_file))
         .route("/api/login", post(handlers::auth::login))
         .route("/api/logout", post(handlers::auth::logout))
+        .route("/api/oidc/login", get(handlers::auth::oidc_login))
+        .route("/api/oidc/callback", get(handlers::auth::oidc_callback))
+        .route("/api/sessions", get(handlers::auth::list_all_sessions))
+        .route("/api/sessions", delete(handlers::auth::revoke_all_sessions))
+        .route("/api/sessions/{id}", delete(handlers::auth::revoke_session))
+        .route("/api/totp", get(handlers::totp::totp_status))
+        .route("/api/totp/setup", post(handlers::totp::setup_totp))
+        .route("/api/totp/enable", post(handlers::totp::enable))
+        .route("/api/totp/disable", post(handlers::totp::disable))
         .route("/api/tokens", get(handlers::auth::list_tokens))
         .route("/api/tokens", post(handlers::auth::create_token))
         .route("/api/tokens/{id}", delete(handlers::auth::revoke_token))
+        .route("/api/webhooks", get(handlers::webhooks::list_all_webhooks))
+        .route(
+            "/api/webhooks",
+            post(handlers::webhooks::create_new_webhook),
+        )
+        .route(
+            "/api/webhooks/{id}",
+            delete(handlers::webhooks::delete_existing_webhook),
+        )
+        .route(
+            "/api/webhooks/{id}/deliveries",
+            get(handlers::webhooks::list_deliveries),
+        )
+        .route("/api/me", get(handlers::users::current_user))
+        .route("/api/users", get(handlers::users::list_all_users))
+        .route("/api/users", post(handlers::users::create_new_user))
+        .route(
+            "/api/users/{id}",
+            delete(handlers::users::delete_existing_user),
+        )
+        .route("/api/events", get(handlers::events::events))
+        .route("/api/ws", get(handlers::ws::websocket))
+        .route("/api/sync", get(handlers::api::sync))
+        .route("/api/lists", get(handlers::api::list_all_lists))
+        .route("/api/lists", post(handlers::api::create_new_list))
+        .route("/api/lists/{id}", get(handlers::api::get_single_list))
+        .route("/api/lists/{id}", put(handlers::api::update_existing_list))
+        .route(
+            "/api/lists/{id}",
+            delete(handlers::api::delete_existing_list),
+        )
+        .route(
+            "/api/lists/{id}/todos",
+            get(handlers::api::list_todos_of_list),
+        )
+        .route(
+            "/api/lists/{id}/todos",
+            post(handlers::api::create_todo_in_list),
+        )
+        .route(
+            "/api/lists/{id}/todos/{todo_id}",
+            get(handlers::api::get_todo_of_list),
+        )
+        .route(
+            "/api/lists/{id}/todos/{todo_id}",
+            put(handlers::api::update_todo_of_list),
+        )
+        .route(
+            "/api/lists/{id}/todos/{todo_id}",
+            delete(handlers::api::delete_todo_of_list),
+        )
+        .route(
+            "/api/lists/{id}/members",
+            get(handlers::api::list_all_members),
+        )
+        .route("/api/lists/{id}/members", post(handlers::api::add_member))
+        .route(
+            "/api/lists/{id}/members/{user_id}",
+            put(handlers::api::update_member),
+        )
+        .route(
+            "/api/lists/{id}/members/{user_id}",
+            delete(handlers::api::remove_member),
+        )
+        .route(
+            "/api/lists/{id}/todos/reorder",
+            put(handlers::api::reorder_list),
+        )
+        .route(
+            "/api/lists/{id}/todos/plain",
+            get(handlers::api::plain_text_list),
+        )
         .route("/api/todos", get(handlers::api::list_all_todos))
         .route("/api/todos", post(handlers::api::create_new_todo))
         .route("/api/todos/reorder", put(handlers::api::reorder))
         .route("/api/todos/plain", get(handlers::api::plain_text_todos))
+        .route("/api/todos/search", get(handlers::api::search))
         .route("/api/todos/{id}", get(handlers::api::get_single_todo))
         .route("/api/todos/{id}", put(handlers::api::update_existing_todo))
         .route(
             "/api/todos/{id}",
             delete(handlers::api::delete_existing_todo),
         )
+        .layer(csrf_protection)
         .layer(
             tower::ServiceBuilder::new()
                 .layer(tower_http::trace::TraceLayer::new_for_http())
@@ -55,6 +167,6 @@ pub fn create_app(state: AppState) -> Router {
     if base_path.is_empty() {
         app_routes
     } else {
-        Router::new().nest(&*base_path, app_routes)
+        Router::new().nest(&base_path, app_routes)
     }
 }
@@ -1,11 +1,30 @@
-use std::{net::Ipv4Addr, sync::Arc};
+use std::{
+    io::{BufRead, IsTerminal},
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use tracing::info;
+use axum::http::HeaderName;
+use tracing::{info, warn};
 
-use donezo::{auth, create_app, db, AppState};
+use donezo::{
+    auth::{self, PasswordSource},
+    create_app, db,
+    oidc::{OidcClient, OidcConfig},
+    rate_limit::TrustedProxy,
+    webhooks::{self, Webhooks},
+    AppState,
+};
 
 #[tokio::main]
 async fn main() {
+    if std::env::args().nth(1).as_deref() == Some("hash-password") {
+        hash_password_command();
+        return;
+    }
+
     tracing_subscriber::fmt::init();
 
     let port: u16 = std::env::var("DONEZO_PORT")
@@ -13,7 +32,13 @@ async fn main() {
         .parse()
         .expect("port number");
 
-    let password = std::env::var("DONEZO_PASSWORD").expect("DONEZO_PASSWORD to be set");
+    let password_hash = PasswordSource {
+        password: std::env::var("DONEZO_PASSWORD").ok(),
+        hash: std::env::var("DONEZO_PASSWORD_HASH").ok(),
+        file: std::env::var_os("DONEZO_PASSWORD_FILE").map(PathBuf::from),
+    }
+    .resolve()
+    .unwrap_or_else(|err| panic!("admin password: {err}"));
 
     let base_path = std::env::var("DONEZO_BASE_PATH")
         .ok()
@@ -27,15 +52,62 @@ async fn main() {
         })
         .unwrap_or_default();
 
-    let password_hash = Arc::new(auth::hash_password(&password));
+    let trusted_proxies = std::env::var("DONEZO_TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter(|proxy| !proxy.trim().is_empty())
+        .map(|proxy| TrustedProxy::parse(proxy).expect("trusted proxy to be an IP or CIDR range"))
+        .collect::<Vec<_>>();
+
+    let auth_header = std::env::var("DONEZO_AUTH_HEADER")
+        .ok()
+        .filter(|header| !header.trim().is_empty())
+        .map(|header| {
+            HeaderName::try_from(header.trim()).expect("DONEZO_AUTH_HEADER to be a header name")
+        });
+    if auth_header.is_some() && trusted_proxies.is_empty() {
+        panic!("DONEZO_AUTH_HEADER requires DONEZO_TRUSTED_PROXIES to be set");
+    }
+
+    let oidc = match std::env::var("DONEZO_OIDC_ISSUER") {
+        Ok(issuer) => {
+            let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+            let config = OidcConfig {
+                issuer,
+                client_id: env("DONEZO_OIDC_CLIENT_ID").expect("DONEZO_OIDC_CLIENT_ID to be set"),
+                client_secret: env("DONEZO_OIDC_CLIENT_SECRET"),
+                redirect_url: env("DONEZO_OIDC_REDIRECT_URL")
+                    .expect("DONEZO_OIDC_REDIRECT_URL to be set"),
+                username_claim: env("DONEZO_OIDC_USERNAME_CLAIM")
+                    .unwrap_or_else(|| "email".to_string()),
+            };
+            let client = OidcClient::discover(config)
+                .await
+                .unwrap_or_else(|err| panic!("OpenID Connect discovery: {err}"));
+            Some(Arc::new(client))
+        }
+        Err(_) => None,
+    };
+
+    let allow_private_webhooks = std::env::var("DONEZO_WEBHOOKS_ALLOW_PRIVATE")
+        .is_ok_and(|value| matches!(value.trim(), "1" | "true"));
+
     let db = db::init_db().expect("initializing database");
-    let _ = db::cleanup_expired_sessions(&db);
+    db::bootstrap_admin(&db, &password_hash).expect("creating admin user");
 
     let state = AppState {
         db,
-        password_hash,
         base_path: Arc::new(base_path),
+        trusted_proxies: Arc::new(trusted_proxies),
+        auth_header,
+        login_limiter: Arc::default(),
+        csrf_key: Arc::default(),
+        events: Arc::default(),
+        webhooks: Arc::new(Webhooks::default().allow_private(allow_private_webhooks)),
+        oidc,
     };
+    tokio::spawn(webhooks::deliver(state.db.clone(), state.webhooks.clone()));
+    tokio::spawn(prune(state.db.clone()));
     let app = create_app(state);
     let addr = (Ipv4Addr::UNSPECIFIED, port);
 
@@ -45,5 +117,56 @@ async fn main() {
 
     info!("running on {addr:?}");
 
-    axum::serve(listener, app).await.expect("failed serving");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .expect("failed serving");
+}
+
+/// How often expired sessions, old tombstones and old webhook deliveries are
+/// dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Drops expired and outdated rows, at startup and then every
+/// `PRUNE_INTERVAL`.
+async fn prune(db: db::DbPool) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = db::cleanup_expired_sessions(&db) {
+            warn!(?err, "Failed to clean up expired sessions");
+        }
+        if let Err(err) = db::prune_tombstones(&db) {
+            warn!(?err, "Failed to prune tombstones");
+        }
+        if let Err(err) = db::prune_webhook_deliveries(&db) {
+            warn!(?err, "Failed to prune webhook deliveries");
+        }
+    }
+}
+
+/// `donezo hash-password` reads a password from stdin and prints its hash for
+/// use in `DONEZO_PASSWORD_HASH`.
+fn hash_password_command() {
+    let stdin = std::io::stdin();
+    let password = if stdin.is_terminal() {
+        // Typed passwords are not echoed
+        eprint!("Password: ");
+        rpassword::read_password().expect("reading password")
+    } else {
+        let mut password = String::new();
+        stdin
+            .lock()
+            .read_line(&mut password)
+            .expect("reading password");
+        password
+    };
+    let password = password.trim_end_matches(['\n', '\r']);
+    if password.is_empty() {
+        eprintln!("The password must not be empty");
+        std::process::exit(1);
+    }
+    println!("{}", auth::hash_password(password));
 }
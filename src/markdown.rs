@@ -0,0 +1,15 @@
+use pulldown_cmark::{html, Options, Parser};
+
+/// Renders Markdown to HTML that is safe to insert into the page. Raw HTML in
+/// the source is sanitized rather than escaped, and links get
+/// `rel="noopener noreferrer"`.
+pub fn render(source: &str) -> String {
+    let parser = Parser::new_ext(
+        source,
+        Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TABLES,
+    );
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, parser);
+
+    ammonia::clean(&unsafe_html)
+}
@@ -1,22 +1,66 @@
-use axum::http::{header::AUTHORIZATION, request::Parts, StatusCode};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{
+    header::{HeaderName, AUTHORIZATION, USER_AGENT},
+    request::Parts,
+    StatusCode,
+};
+use axum::middleware::Next;
 use axum::response::{IntoResponse, Response};
 use axum::{extract::FromRequestParts, Json};
 use serde_json::json;
 use tracing::warn;
 
-use crate::db::{get_api_token_by_value, get_session, DbPool};
+use crate::dates::unix_now;
+use crate::db::{
+    get_api_token, get_api_token_by_value, get_session, get_user, get_user_by_name,
+    record_api_token_use, touch_session, DbPool,
+};
 use crate::error::AppError;
+use crate::models::{ApiToken, Scope, Session, User};
+use crate::rate_limit::client_ip;
 use crate::AppState;
 
-/// Represents an authenticated request (via session cookie or API token)
-pub struct Auth;
+/// Represents an authenticated request (via session cookie, proxy header or API
+/// token) and its user. The request carries the `Login` it was authenticated
+/// with as an extension, and requests with an API token the `ApiToken` as well.
+pub struct Auth(pub User);
+
+/// Represents an authenticated request via session cookie or proxy header only
+/// (no API tokens)
+pub struct SessionAuth(pub User);
 
-/// Represents an authenticated request via session cookie only (no API tokens)
-pub struct SessionAuth;
+/// Represents a request by an admin, authenticated via session cookie or proxy
+/// header only
+pub struct AdminAuth(pub User);
 
 /// Represents an optional authentication status
 pub struct MaybeAuth(pub bool);
 
+/// The CSRF token of a request's browser login, which the web UI has to send
+/// along with changes. `None` if the request isn't logged in through a browser.
+pub struct CsrfToken(pub Option<String>);
+
+/// What a request was authenticated with. Connections that outlive the request,
+/// like event streams and WebSockets, check now and then that it is still valid.
+#[derive(Debug, Clone)]
+pub enum Login {
+    /// The user named by a trusted proxy, who stays logged in as long as the
+    /// user exists.
+    Proxy(i64),
+    Session(String),
+    Token(i64),
+}
+
+/// Where a request comes from, as recorded for sessions and API tokens. The IP
+/// is taken from `X-Forwarded-For` for requests through trusted proxies.
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
 impl FromRequestParts<AppState> for Auth {
     type Rejection = AuthError;
 
@@ -24,12 +68,43 @@ impl FromRequestParts<AppState> for Auth {
         parts: &mut Parts,
         state: &AppState,
     ) -> Result<Self, Self::Rejection> {
-        if check_session(parts, &state.db) {
-            return Ok(Auth);
+        if let Some((user, login)) = browser_login(parts, state) {
+            parts.extensions.insert(login);
+            return Ok(Auth(user));
         }
 
-        if check_bearer_token(parts, &state.db)? {
-            return Ok(Auth);
+        if let Some((token, user)) = bearer_token_user(parts, &state.db)? {
+            if token
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= unix_now())
+            {
+                warn!(prefix = %token.prefix, "Expired API token used");
+                return Err(AuthError::Unauthorized);
+            }
+            let scope = required_scope(parts);
+            if !token.scopes.iter().any(|granted| granted.grants(scope)) {
+                warn!(prefix = %token.prefix, scope = scope.as_str(), "API token lacks scope");
+                return Err(AuthError::Forbidden);
+            }
+            if token
+                .list_id
+                .is_some_and(|list_id| !allowed_for_list(parts, list_id))
+            {
+                warn!(prefix = %token.prefix, path = %parts.uri.path(), "API token used outside its list");
+                return Err(AuthError::Forbidden);
+            }
+
+            let client = ClientInfo::from_parts(parts, state);
+            record_api_token_use(
+                &state.db,
+                token.id,
+                client.ip.map(|ip| ip.to_string()).as_deref(),
+                client.user_agent.as_deref(),
+            )?;
+            // Lets handlers check the token's scopes beyond those of the request
+            parts.extensions.insert(Login::Token(token.id));
+            parts.extensions.insert(token);
+            return Ok(Auth(user));
         }
 
         warn!("Unauthorized API access attempt");
@@ -44,14 +119,83 @@ impl FromRequestParts<AppState> for SessionAuth {
         parts: &mut Parts,
         state: &AppState,
     ) -> Result<Self, Self::Rejection> {
-        if check_session(parts, &state.db) {
-            return Ok(SessionAuth);
+        if let Some(user) = browser_user(parts, state) {
+            return Ok(SessionAuth(user));
         }
 
         Err(AuthError::Unauthorized)
     }
 }
 
+impl FromRequestParts<AppState> for AdminAuth {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let SessionAuth(user) = SessionAuth::from_request_parts(parts, state).await?;
+        if !user.is_admin {
+            warn!(user = %user.username, "Forbidden admin access attempt");
+            return Err(AuthError::Forbidden);
+        }
+
+        Ok(AdminAuth(user))
+    }
+}
+
+impl Login {
+    /// How often connections outliving their request check their login.
+    pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// Whether the login is still valid: sessions and tokens may have been
+    /// revoked or expired since the request came in.
+    pub fn is_valid(&self, db: &DbPool) -> bool {
+        let now = unix_now();
+        match self {
+            Login::Proxy(user_id) => matches!(get_user(db, *user_id), Ok(Some(_))),
+            Login::Session(id) => {
+                matches!(get_session(db, id), Ok(Some(session)) if session.expires_at > now)
+            }
+            Login::Token(id) => matches!(
+                get_api_token(db, *id),
+                Ok(Some(token)) if token.expires_at.is_none_or(|expires_at| expires_at > now)
+            ),
+        }
+    }
+}
+
+impl FromRequestParts<AppState> for ClientInfo {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(ClientInfo::from_parts(parts, state))
+    }
+}
+
+impl ClientInfo {
+    fn from_parts(parts: &Parts, state: &AppState) -> Self {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let forwarded_for = parts
+            .headers
+            .get(X_FORWARDED_FOR)
+            .and_then(|value| value.to_str().ok());
+        let ip = client_ip(peer, forwarded_for, &state.trusted_proxies);
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.chars().take(MAX_USER_AGENT_LEN).collect());
+        ClientInfo { ip, user_agent }
+    }
+}
+
 impl FromRequestParts<AppState> for MaybeAuth {
     type Rejection = std::convert::Infallible;
 
@@ -59,11 +203,125 @@ impl FromRequestParts<AppState> for MaybeAuth {
         parts: &mut Parts,
         state: &AppState,
     ) -> Result<Self, Self::Rejection> {
-        Ok(MaybeAuth(check_session(parts, &state.db)))
+        Ok(MaybeAuth(browser_user(parts, state).is_some()))
+    }
+}
+
+impl FromRequestParts<AppState> for CsrfToken {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let token = csrf_subject(parts, state).map(|subject| state.csrf_key.token(&subject));
+        Ok(CsrfToken(token))
+    }
+}
+
+/// Rejects changes from browsers that don't send the CSRF token of their
+/// login, so that other sites can't make changes on a user's behalf. Requests
+/// without a browser login are exempt: `Auth` only falls back to their API
+/// token, which browsers never send on their own.
+pub async fn csrf_protection(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.method().is_safe() {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    if let Some(subject) = csrf_subject(&parts, &state) {
+        let token = parts
+            .headers
+            .get(X_CSRF_TOKEN)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if !state.csrf_key.verify(&subject, token) {
+            warn!(method = %parts.method, path = %parts.uri.path(), "Change without valid CSRF token");
+            return AuthError::InvalidCsrfToken.into_response();
+        }
+    }
+    next.run(Request::from_parts(parts, body)).await
+}
+
+/// Identifies the browser login of a request, which its CSRF token is derived
+/// from: the user named by a trusted proxy, or the session.
+fn csrf_subject(parts: &Parts, state: &AppState) -> Option<String> {
+    match proxy_user(parts, state) {
+        Some(user) => Some(format!("user:{}", user.id)),
+        None => valid_session(parts, &state.db).map(|session| session.id),
     }
 }
 
-fn check_session(parts: &Parts, db: &DbPool) -> bool {
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+pub const X_CSRF_TOKEN: HeaderName = HeaderName::from_static("x-csrf-token");
+
+/// Longer user agents are cut off before they are stored.
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// The user logged in through the web UI: named by the proxy header, if
+/// configured, or else by the session cookie.
+fn browser_user(parts: &Parts, state: &AppState) -> Option<User> {
+    browser_login(parts, state).map(|(user, _)| user)
+}
+
+fn browser_login(parts: &Parts, state: &AppState) -> Option<(User, Login)> {
+    match proxy_user(parts, state) {
+        Some(user) => {
+            let login = Login::Proxy(user.id);
+            Some((user, login))
+        }
+        None => session_user(parts, state),
+    }
+}
+
+/// The user named by the configured auth header. The header is ignored unless
+/// the request comes straight from a trusted proxy, since anyone else could
+/// set it.
+fn proxy_user(parts: &Parts, state: &AppState) -> Option<User> {
+    let header = state.auth_header.as_ref()?;
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())?;
+    if !state
+        .trusted_proxies
+        .iter()
+        .any(|proxy| proxy.contains(peer))
+    {
+        return None;
+    }
+
+    let username = parts.headers.get(header)?.to_str().ok()?.trim();
+    if username.is_empty() {
+        return None;
+    }
+    let user = get_user_by_name(&state.db, username).ok().flatten();
+    if user.is_none() {
+        warn!(username, "Proxy header names an unknown user");
+    }
+    user
+}
+
+fn session_user(parts: &Parts, state: &AppState) -> Option<(User, Login)> {
+    let db = &state.db;
+    let session = valid_session(parts, db)?;
+    let client = ClientInfo::from_parts(parts, state);
+    let _ = touch_session(
+        db,
+        &session.id,
+        client.ip.map(|ip| ip.to_string()).as_deref(),
+        client.user_agent.as_deref(),
+    );
+    let user = get_user(db, session.user_id).ok().flatten()?;
+    Some((user, Login::Session(session.id)))
+}
+
+/// The unexpired session named by the session cookie.
+fn valid_session(parts: &Parts, db: &DbPool) -> Option<Session> {
     let cookies = parts
         .headers
         .get_all("cookie")
@@ -78,32 +336,57 @@ fn check_session(parts: &Parts, db: &DbPool) -> bool {
     for (name, value) in cookies {
         if name == "session" {
             if let Ok(Some(session)) = get_session(db, value) {
-                let now = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs() as i64;
-                if session.expires_at > now {
-                    return true;
+                if session.expires_at > unix_now() {
+                    return Some(session);
                 }
             }
         }
     }
-    false
+    None
 }
 
-fn check_bearer_token(parts: &Parts, db: &DbPool) -> Result<bool, AppError> {
+fn bearer_token_user(parts: &Parts, db: &DbPool) -> Result<Option<(ApiToken, User)>, AppError> {
     if let Some(auth_header) = parts.headers.get(AUTHORIZATION) {
         if let Ok(auth_str) = auth_header.to_str() {
             if let Some(token) = auth_str.strip_prefix("Bearer ") {
-                return Ok(get_api_token_by_value(db, token)?.is_some());
+                return match get_api_token_by_value(db, token)? {
+                    Some(token) => Ok(get_user(db, token.user_id)?.map(|user| (token, user))),
+                    None => Ok(None),
+                };
             }
         }
     }
-    Ok(false)
+    Ok(None)
+}
+
+/// The scope an API token needs for a request: reading the plain-text export,
+/// reading anything else, or making changes. `HEAD` reads like `GET`.
+fn required_scope(parts: &Parts) -> Scope {
+    if !parts.method.is_safe() {
+        Scope::TodosWrite
+    } else if parts.uri.path().ends_with("/plain") {
+        Scope::PlainRead
+    } else {
+        Scope::TodosRead
+    }
+}
+
+/// Whether a token restricted to `list_id` may make the request: it may read
+/// the list and work with its todos under `/api/lists/{list_id}/todos`, but not
+/// rename or delete the list or change who it is shared with.
+fn allowed_for_list(parts: &Parts, list_id: i64) -> bool {
+    let list = format!("/api/lists/{list_id}");
+    match parts.uri.path().strip_prefix(&list) {
+        Some("") => parts.method.is_safe(),
+        Some(rest) => rest == "/todos" || rest.starts_with("/todos/"),
+        None => false,
+    }
 }
 
 pub enum AuthError {
     Unauthorized,
+    Forbidden,
+    InvalidCsrfToken,
     Internal(String),
 }
 
@@ -115,6 +398,14 @@ impl IntoResponse for AuthError {
                 Json(json!({ "error": "Unauthorized" })),
             )
                 .into_response(),
+            AuthError::Forbidden => {
+                (StatusCode::FORBIDDEN, Json(json!({ "error": "Forbidden" }))).into_response()
+            }
+            AuthError::InvalidCsrfToken => (
+                StatusCode::FORBIDDEN,
+                Json(json!({ "error": "Invalid CSRF token", "csrf_invalid": true })),
+            )
+                .into_response(),
             AuthError::Internal(msg) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(json!({ "error": msg })),
@@ -128,9 +419,14 @@ impl From<AppError> for AuthError {
     fn from(err: AppError) -> Self {
         match err {
             AppError::Database(msg) => AuthError::Internal(msg),
-            AppError::Unauthorized => AuthError::Unauthorized,
+            AppError::Unauthorized | AppError::TotpRequired => AuthError::Unauthorized,
+            AppError::Forbidden => AuthError::Forbidden,
             AppError::NotFound => AuthError::Internal("Not found".to_string()),
             AppError::BadRequest(msg) => AuthError::Internal(msg.to_string()),
+            AppError::PreconditionFailed => AuthError::Internal("Precondition failed".to_string()),
+            AppError::TooManyRequests { .. } => {
+                AuthError::Internal("Too many requests".to_string())
+            }
         }
     }
 }
@@ -1,24 +1,233 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Todo {
     pub id: i64,
+    pub list_id: i64,
+    pub parent_id: Option<i64>,
     pub title: String,
+    /// Markdown source of the notes.
+    pub notes: Option<String>,
     pub completed: bool,
     pub position: i64,
+    pub priority: Priority,
+    pub due_at: Option<String>,
+    pub recurrence: Option<Recurrence>,
+    pub tags: Vec<String>,
     pub created_at: i64,
     pub updated_at: i64,
+    /// Changes whenever the todo does. Sent as its `ETag`, and expected in
+    /// `If-Match` to make sure a change doesn't overwrite someone else's.
+    pub version: i64,
+}
+
+/// A single todo along with its notes rendered to sanitized HTML.
+#[derive(Debug, Clone, Serialize)]
+pub struct TodoDetail {
+    #[serde(flatten)]
+    pub todo: Todo,
+    pub notes_html: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateTodo {
     pub title: String,
+    pub notes: Option<String>,
+    pub list_id: Option<i64>,
+    /// Makes the new todo a subtask; it is created in its parent's list.
+    pub parent_id: Option<i64>,
+    #[serde(default)]
+    pub priority: Priority,
+    pub due_at: Option<String>,
+    pub recurrence: Option<Recurrence>,
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpdateTodo {
     pub title: Option<String>,
+    /// `null` clears the notes, a missing field leaves them untouched.
+    #[serde(default, deserialize_with = "double_option")]
+    pub notes: Option<Option<String>>,
     pub completed: Option<bool>,
+    /// Also applies `completed` to all subtasks.
+    #[serde(default)]
+    pub cascade: bool,
+    pub list_id: Option<i64>,
+    /// `null` turns a subtask into a top-level todo, a missing field leaves it untouched.
+    #[serde(default, deserialize_with = "double_option")]
+    pub parent_id: Option<Option<i64>>,
+    pub priority: Option<Priority>,
+    /// `null` clears the due date, a missing field leaves it untouched.
+    #[serde(default, deserialize_with = "double_option")]
+    pub due_at: Option<Option<String>>,
+    /// `null` stops the todo from recurring.
+    #[serde(default, deserialize_with = "double_option")]
+    pub recurrence: Option<Option<Recurrence>>,
+    /// Replaces all tags of the todo.
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    None,
+    Low,
+    Medium,
+    High,
+}
+
+impl Priority {
+    /// Stored as 0 (none) to 3 (high) so that it sorts in SQL.
+    pub fn level(self) -> i64 {
+        self as i64
+    }
+
+    pub fn from_level(level: i64) -> Self {
+        match level {
+            1 => Priority::Low,
+            2 => Priority::Medium,
+            3.. => Priority::High,
+            _ => Priority::None,
+        }
+    }
+
+    /// Marker prefixed to titles in the plain-text export.
+    pub fn marker(self) -> &'static str {
+        match self {
+            Priority::None => "",
+            Priority::Low => "! ",
+            Priority::Medium => "!! ",
+            Priority::High => "!!! ",
+        }
+    }
+}
+
+/// When a recurring todo is completed, a new occurrence is created with the
+/// next due date and the rule moves over to it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Recurrence {
+    Daily,
+    Weekly {
+        weekdays: Vec<Weekday>,
+    },
+    /// Day of the month, moved to the last day in shorter months.
+    Monthly {
+        day: u8,
+    },
+    /// Due `days` after the day the previous occurrence was completed.
+    AfterCompletion {
+        days: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+/// Query parameters narrowing down which todos are listed.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TodoFilter {
+    pub list_id: Option<i64>,
+    pub completed: Option<bool>,
+    pub due: Option<DueFilter>,
+    /// Tags the todo must have, or must not have when prefixed with `-`.
+    #[serde(default)]
+    pub tag: Vec<String>,
+    /// Defaults to `due` when filtering by due date and to `position` otherwise.
+    pub sort: Option<TodoSort>,
+}
+
+/// Order of listed todos. Subtasks stay below their parent unless filtering by
+/// due date, so the order applies among siblings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TodoSort {
+    /// Manual order within each list.
+    Position,
+    /// Highest priority first.
+    Priority,
+    /// Earliest due date first, todos without a due date last.
+    Due,
+    /// Oldest first.
+    Created,
+}
+
+/// Options of the plain-text export.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PlainTextOptions {
+    /// Prefixes titles with `!`, `!!` or `!!!` by priority.
+    #[serde(default)]
+    pub markers: bool,
+}
+
+/// Open todos that are due in a given window, relative to the current UTC date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DueFilter {
+    /// Due today, including todos whose due time has already passed today.
+    Today,
+    /// Due date or due time lies in the past.
+    Overdue,
+    /// Due within the next seven days, today included.
+    Week,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub list_id: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    #[serde(flatten)]
+    pub todo: Todo,
+    pub snippet: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyncQuery {
+    /// The `seq` of the previous sync. Missing on the first sync.
+    #[serde(default)]
+    pub since: i64,
+}
+
+/// Changes since a client's previous sync.
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncChanges {
+    /// Cursor to pass as `since` on the next sync.
+    pub seq: i64,
+    /// Set if `todos` holds all todos and everything the client has stored
+    /// has to be replaced, e.g. because it hasn't synced for too long.
+    pub reset: bool,
+    /// All lists the user can see. Lists missing here were deleted or are no
+    /// longer shared with the user, along with their todos.
+    pub lists: Vec<TodoList>,
+    /// Todos created or changed since the previous sync.
+    pub todos: Vec<Todo>,
+    /// Todos deleted or moved out of a list since the previous sync. Apply
+    /// these before `todos`, which holds the moved todos in their new list.
+    pub deleted: Vec<Tombstone>,
+}
+
+/// A todo that is gone from a list.
+#[derive(Debug, Clone, Serialize)]
+pub struct Tombstone {
+    pub id: i64,
+    pub list_id: i64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,27 +235,371 @@ pub struct ReorderTodos {
     pub ids: Vec<i64>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TodoList {
+    pub id: i64,
+    pub name: String,
+    pub position: i64,
+    /// The authenticated user's role on the list.
+    pub role: Role,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateList {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateList {
+    pub name: String,
+}
+
+/// What a user may do with a list. Each role includes the ones below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Sees the list and its todos.
+    Viewer,
+    /// Also creates, edits, reorders and deletes todos.
+    Editor,
+    /// Also renames and deletes the list and manages its members.
+    Owner,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(role: &str) -> Self {
+        match role {
+            "owner" => Role::Owner,
+            "editor" => Role::Editor,
+            _ => Role::Viewer,
+        }
+    }
+}
+
+/// A user with access to a list, including its owner.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListMember {
+    pub user_id: i64,
+    pub username: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddListMember {
+    pub username: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateListMember {
+    pub role: Role,
+}
+
+/// Name of the admin account whose password is given at startup.
+pub const ADMIN_USERNAME: &str = "admin";
+
+/// An account. Lists, todos, sessions and API tokens each belong to one user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub is_admin: bool,
+    /// Whether logins need a TOTP code besides the password.
+    pub totp_enabled: bool,
+    pub created_at: i64,
+}
+
+/// The stored two-factor state of a user.
+#[derive(Debug, Clone, Default)]
+pub struct TotpState {
+    pub secret: Option<String>,
+    /// Secret being enrolled, until it is confirmed with a first code.
+    pub pending_secret: Option<String>,
+    /// Last time step a code was used for, to reject replays.
+    pub last_step: Option<i64>,
+}
+
+/// A new TOTP secret to be added to an authenticator app.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpSetup {
+    pub secret: String,
+    pub otpauth_uri: String,
+    pub qr_code_svg: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TotpStatus {
+    pub enabled: bool,
+    pub recovery_codes_left: i64,
+}
+
+/// A TOTP code, or for disabling also a recovery code.
+#[derive(Debug, Deserialize)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+/// Recovery codes, shown once when two-factor authentication is enabled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUser {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Session {
     pub id: String,
+    pub user_id: i64,
     pub created_at: i64,
     pub expires_at: i64,
+    pub last_seen_at: Option<i64>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// A session as shown to its user. The session id is the secret stored in the
+/// cookie, so sessions are identified by a hash of it instead.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub created_at: i64,
+    pub expires_at: i64,
+    pub last_seen_at: Option<i64>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Whether this is the session making the request.
+    pub current: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiToken {
     pub id: i64,
-    pub token: String,
+    pub user_id: i64,
+    /// First characters of the token, to tell tokens apart. Only a hash of
+    /// the full token is stored.
+    pub prefix: String,
     pub name: Option<String>,
+    pub scopes: Vec<Scope>,
+    /// Restricts the token to the routes of a single list.
+    pub list_id: Option<i64>,
     pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub last_ip: Option<String>,
+    pub last_user_agent: Option<String>,
+}
+
+/// A newly created API token, the only time its full value is available.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedApiToken {
+    #[serde(flatten)]
+    pub api_token: ApiToken,
+    pub token: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateApiToken {
     pub name: Option<String>,
+    /// Defaults to all scopes.
+    #[serde(default = "Scope::all")]
+    pub scopes: Vec<Scope>,
+    pub list_id: Option<i64>,
+    /// Unix timestamp after which the token is rejected; never by default.
+    pub expires_at: Option<i64>,
+}
+
+/// What an API token may be used for. Logged-in sessions may do everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Scope {
+    /// `GET` requests, including the plain-text export.
+    #[serde(rename = "todos:read")]
+    TodosRead,
+    /// All other requests.
+    #[serde(rename = "todos:write")]
+    TodosWrite,
+    /// Only the plain-text export.
+    #[serde(rename = "plain:read")]
+    PlainRead,
+}
+
+impl Scope {
+    pub fn all() -> Vec<Scope> {
+        vec![Scope::TodosRead, Scope::TodosWrite, Scope::PlainRead]
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::TodosRead => "todos:read",
+            Scope::TodosWrite => "todos:write",
+            Scope::PlainRead => "plain:read",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "todos:read" => Some(Scope::TodosRead),
+            "todos:write" => Some(Scope::TodosWrite),
+            "plain:read" => Some(Scope::PlainRead),
+            _ => None,
+        }
+    }
+
+    /// Whether a token with this scope may access a route requiring `required`.
+    pub fn grants(self, required: Scope) -> bool {
+        self == required || (self == Scope::TodosRead && required == Scope::PlainRead)
+    }
+}
+
+/// A todo event that webhooks can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WebhookEvent {
+    #[serde(rename = "todo.created")]
+    TodoCreated,
+    /// Any change, including completing the todo.
+    #[serde(rename = "todo.updated")]
+    TodoUpdated,
+    /// Sent along with `todo.updated` when a todo is marked as done.
+    #[serde(rename = "todo.completed")]
+    TodoCompleted,
+    #[serde(rename = "todo.deleted")]
+    TodoDeleted,
+}
+
+impl WebhookEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WebhookEvent::TodoCreated => "todo.created",
+            WebhookEvent::TodoUpdated => "todo.updated",
+            WebhookEvent::TodoCompleted => "todo.completed",
+            WebhookEvent::TodoDeleted => "todo.deleted",
+        }
+    }
+
+    pub fn parse(event: &str) -> Option<Self> {
+        match event {
+            "todo.created" => Some(WebhookEvent::TodoCreated),
+            "todo.updated" => Some(WebhookEvent::TodoUpdated),
+            "todo.completed" => Some(WebhookEvent::TodoCompleted),
+            "todo.deleted" => Some(WebhookEvent::TodoDeleted),
+            _ => None,
+        }
+    }
+}
+
+/// A URL that is sent the events of all lists the user can see.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateWebhook {
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    /// Key for the signatures of deliveries; generated if missing.
+    pub secret: Option<String>,
+}
+
+/// A newly created webhook, the only time its secret is shown.
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    pub webhook: Webhook,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// Waiting for its first attempt or to be retried.
+    Pending,
+    Delivered,
+    /// Gave up after the last retry.
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(status: &str) -> Self {
+        match status {
+            "delivered" => DeliveryStatus::Delivered,
+            "failed" => DeliveryStatus::Failed,
+            _ => DeliveryStatus::Pending,
+        }
+    }
+}
+
+/// An event sent, or to be sent, to a webhook.
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub event: WebhookEvent,
+    pub status: DeliveryStatus,
+    pub attempts: i64,
+    /// HTTP status of the last attempt, if the webhook responded.
+    pub response_status: Option<u16>,
+    /// Why the last attempt failed.
+    pub error: Option<String>,
+    pub created_at: i64,
+    pub last_attempt_at: Option<i64>,
+    pub next_attempt_at: Option<i64>,
+}
+
+/// Query of the redirect back from the OpenID Connect provider.
+#[derive(Debug, Deserialize)]
+pub struct OidcCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct LoginRequest {
+    /// Defaults to the admin account, which used to be the only one.
+    #[serde(default = "admin_username")]
+    pub username: String,
     pub password: String,
+    /// Required once two-factor authentication is enabled; a recovery code
+    /// works as well.
+    pub totp_code: Option<String>,
+}
+
+fn admin_username() -> String {
+    ADMIN_USERNAME.to_string()
+}
+
+/// Distinguishes an explicit `null` (`Some(None)`) from a missing field (`None`).
+fn double_option<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
 }
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::auth::generate_token;
+use crate::dates::unix_now;
+
+/// How long a login may take at the identity provider.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Logins kept waiting for the identity provider at most. Anyone can start
+/// logins, so beyond this the oldest ones are dropped.
+pub const MAX_PENDING_LOGINS: usize = 1000;
+
+/// Scopes requested from the identity provider.
+const SCOPES: &str = "openid email profile";
+
+/// How donezo is registered with an OpenID Connect identity provider.
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    /// Issuer URL, e.g. `https://auth.example.com/realms/home`.
+    pub issuer: String,
+    pub client_id: String,
+    /// Only needed for confidential clients; public clients rely on PKCE.
+    pub client_secret: Option<String>,
+    /// Absolute URL of `/api/oidc/callback`, as registered with the provider.
+    pub redirect_url: String,
+    /// ID token claim holding the donezo username, `email` by default.
+    pub username_claim: String,
+}
+
+/// Logs users in with the authorization code flow and PKCE.
+pub struct OidcClient {
+    config: OidcConfig,
+    issuer: String,
+    authorization_endpoint: Url,
+    token_endpoint: String,
+    http: reqwest::Client,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+/// A login sent to the identity provider, keyed by its `state` parameter.
+struct PendingLogin {
+    code_verifier: String,
+    nonce: String,
+    started: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+impl OidcClient {
+    /// Fetches the provider's endpoints from its discovery document. All of
+    /// them have to use https, since ID tokens are trusted for coming from the
+    /// token endpoint; plain http is only accepted on loopback addresses.
+    pub async fn discover(config: OidcConfig) -> Result<Self, String> {
+        secure_url("issuer", &config.issuer)?;
+        let http = reqwest::Client::new();
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        );
+        let metadata: ProviderMetadata = http
+            .get(&url)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| format!("fetching {url}: {err}"))?
+            .json()
+            .await
+            .map_err(|err| format!("parsing {url}: {err}"))?;
+        if metadata.issuer.trim_end_matches('/') != config.issuer.trim_end_matches('/') {
+            return Err(format!(
+                "issuer {} doesn't match the configured {}",
+                metadata.issuer, config.issuer
+            ));
+        }
+        let authorization_endpoint =
+            secure_url("authorization endpoint", &metadata.authorization_endpoint)?;
+        secure_url("token endpoint", &metadata.token_endpoint)?;
+
+        Ok(OidcClient {
+            config,
+            issuer: metadata.issuer,
+            authorization_endpoint,
+            token_endpoint: metadata.token_endpoint,
+            http,
+            pending: Mutex::default(),
+        })
+    }
+
+    /// Starts a login and returns its `state` along with the URL to send the
+    /// browser to.
+    pub fn start_login(&self) -> (String, String) {
+        let state = generate_token();
+        let login = PendingLogin {
+            code_verifier: generate_token(),
+            nonce: generate_token(),
+            started: Instant::now(),
+        };
+
+        let mut url = self.authorization_endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("scope", SCOPES)
+            .append_pair("state", &state)
+            .append_pair("nonce", &login.nonce)
+            .append_pair("code_challenge", &code_challenge(&login.code_verifier))
+            .append_pair("code_challenge_method", "S256");
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, login| login.started.elapsed() < LOGIN_TIMEOUT);
+        if pending.len() >= MAX_PENDING_LOGINS {
+            let oldest = pending
+                .iter()
+                .min_by_key(|(_, login)| login.started)
+                .map(|(state, _)| state.clone());
+            if let Some(oldest) = oldest {
+                pending.remove(&oldest);
+            }
+        }
+        pending.insert(state.clone(), login);
+        (state, url.into())
+    }
+
+    /// Finishes the login started with `state`: redeems the authorization code
+    /// and returns the username from the ID token.
+    pub async fn finish_login(&self, state: &str, code: &str) -> Result<String, String> {
+        let login = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|login| login.started.elapsed() < LOGIN_TIMEOUT)
+            .ok_or("unknown or expired login")?;
+
+        let mut request = self.http.post(&self.token_endpoint).form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &login.code_verifier),
+        ]);
+        if let Some(secret) = &self.config.client_secret {
+            request = request.basic_auth(&self.config.client_id, Some(secret));
+        }
+        let tokens: TokenResponse = request
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| format!("redeeming authorization code: {err}"))?
+            .json()
+            .await
+            .map_err(|err| format!("parsing token response: {err}"))?;
+
+        let claims = id_token_claims(&tokens.id_token)?;
+        self.validate_claims(&claims, &login.nonce, unix_now())?;
+        self.username(&claims)
+    }
+
+    /// Checks that the ID token was issued by the provider for this client and
+    /// this login. Its signature isn't checked: the token comes straight from
+    /// the token endpoint over TLS (enforced by `discover`), which OpenID
+    /// Connect Core (3.1.3.7) allows in place of a signature check.
+    fn validate_claims(&self, claims: &Value, nonce: &str, now: i64) -> Result<(), String> {
+        if claims["iss"].as_str() != Some(self.issuer.as_str()) {
+            return Err("ID token from another issuer".to_string());
+        }
+        let client_id = self.config.client_id.as_str();
+        let audience_ok = match &claims["aud"] {
+            Value::String(aud) => aud == client_id,
+            Value::Array(auds) => {
+                auds.iter().any(|aud| aud.as_str() == Some(client_id))
+                    && (auds.len() == 1 || claims["azp"].as_str() == Some(client_id))
+            }
+            _ => false,
+        };
+        if !audience_ok {
+            return Err("ID token for another client".to_string());
+        }
+        if claims["exp"].as_i64().is_none_or(|exp| exp <= now) {
+            return Err("expired ID token".to_string());
+        }
+        if claims["nonce"].as_str() != Some(nonce) {
+            return Err("ID token for another login".to_string());
+        }
+        Ok(())
+    }
+
+    fn username(&self, claims: &Value) -> Result<String, String> {
+        let claim = self.config.username_claim.as_str();
+        if claim == "email" && claims["email_verified"] != Value::Bool(true) {
+            return Err("email address not verified".to_string());
+        }
+        claims[claim]
+            .as_str()
+            .map(str::trim)
+            .filter(|username| !username.is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| format!("ID token lacks the {claim} claim"))
+    }
+}
+
+/// Parses one of the provider's URLs, which has to use https unless it points
+/// to the server itself.
+fn secure_url(name: &str, url: &str) -> Result<Url, String> {
+    let url = Url::parse(url).map_err(|err| format!("invalid {name}: {err}"))?;
+    let loopback = url.host_str().is_some_and(|host| {
+        host == "localhost"
+            || host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .is_ok_and(|ip| ip.is_loopback())
+    });
+    match url.scheme() {
+        "https" => Ok(url),
+        "http" if loopback => Ok(url),
+        _ => Err(format!("{name} {url} doesn't use https")),
+    }
+}
+
+/// The PKCE `S256` challenge for a code verifier.
+fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// Decodes the payload of an ID token (a JWT).
+fn id_token_claims(id_token: &str) -> Result<Value, String> {
+    let payload = id_token.split('.').nth(1).ok_or("malformed ID token")?;
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .map_err(|_| "malformed ID token")?;
+    serde_json::from_slice(&payload).map_err(|_| "malformed ID token".to_string())
+}
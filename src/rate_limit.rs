@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Failed logins per client IP before it has to wait between attempts.
+const IP_FREE_ATTEMPTS: u32 = 5;
+const IP_BASE_DELAY: Duration = Duration::from_secs(30);
+const IP_MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Failed logins across all clients before everyone has to wait, which caps
+/// the CPU spent on password hashes during a distributed attack.
+const GLOBAL_FREE_ATTEMPTS: u32 = 100;
+const GLOBAL_BASE_DELAY: Duration = Duration::from_secs(5);
+const GLOBAL_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Failures are forgotten after this long without another one.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Tracked client IPs above which forgotten entries are pruned.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Throttles login attempts with exponential backoff, per client IP and
+/// globally. Once the free attempts are used up, every further failure doubles
+/// the time until the next attempt is allowed.
+#[derive(Default)]
+pub struct LoginLimiter {
+    state: Mutex<LimiterState>,
+}
+
+#[derive(Default)]
+struct LimiterState {
+    per_ip: HashMap<IpAddr, Failures>,
+    global: Failures,
+}
+
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    last: Option<Instant>,
+    blocked_until: Option<Instant>,
+}
+
+/// Why a login attempt was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lockout {
+    Ip(Duration),
+    Global(Duration),
+}
+
+impl Lockout {
+    /// Time until the next attempt, rounded up to whole seconds.
+    pub fn retry_after(self) -> u64 {
+        let (Lockout::Ip(wait) | Lockout::Global(wait)) = self;
+        wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+    }
+}
+
+impl LoginLimiter {
+    /// Fails if `ip` or all clients have to wait before the next attempt.
+    pub fn check(&self, ip: Option<IpAddr>, now: Instant) -> Result<(), Lockout> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(wait) = state.global.wait(now) {
+            return Err(Lockout::Global(wait));
+        }
+        match ip.and_then(|ip| state.per_ip.get_mut(&ip)) {
+            Some(failures) => match failures.wait(now) {
+                Some(wait) => Err(Lockout::Ip(wait)),
+                None => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Records a failed attempt and returns the lockout it causes, if any.
+    pub fn record_failure(&self, ip: Option<IpAddr>, now: Instant) -> Option<Lockout> {
+        let mut state = self.state.lock().unwrap();
+        if state.per_ip.len() > PRUNE_THRESHOLD {
+            state.per_ip.retain(|_, failures| !failures.forgotten(now));
+        }
+
+        let global = state.global.record(
+            now,
+            GLOBAL_FREE_ATTEMPTS,
+            GLOBAL_BASE_DELAY,
+            GLOBAL_MAX_DELAY,
+        );
+        let per_ip = ip.and_then(|ip| {
+            state.per_ip.entry(ip).or_default().record(
+                now,
+                IP_FREE_ATTEMPTS,
+                IP_BASE_DELAY,
+                IP_MAX_DELAY,
+            )
+        });
+        per_ip.map(Lockout::Ip).or(global.map(Lockout::Global))
+    }
+
+    /// Clears the failures of `ip` after a successful login.
+    pub fn record_success(&self, ip: Option<IpAddr>) {
+        if let Some(ip) = ip {
+            self.state.lock().unwrap().per_ip.remove(&ip);
+        }
+    }
+}
+
+impl Failures {
+    fn wait(&self, now: Instant) -> Option<Duration> {
+        self.blocked_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    fn forgotten(&self, now: Instant) -> bool {
+        self.last
+            .is_none_or(|last| now.duration_since(last) > FORGET_AFTER)
+    }
+
+    fn record(
+        &mut self,
+        now: Instant,
+        free_attempts: u32,
+        base_delay: Duration,
+        max_delay: Duration,
+    ) -> Option<Duration> {
+        if self.forgotten(now) {
+            self.count = 0;
+        }
+        self.count += 1;
+        self.last = Some(now);
+
+        let excess = self.count.checked_sub(free_attempts)?;
+        let delay = base_delay
+            .saturating_mul(2u32.saturating_pow(excess))
+            .min(max_delay);
+        self.blocked_until = Some(now + delay);
+        Some(delay)
+    }
+}
+
+/// An IP address or CIDR range of a reverse proxy whose `X-Forwarded-For`
+/// header is trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrustedProxy {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl TrustedProxy {
+    /// Parses `10.0.0.1`, `10.0.0.0/8` or `fd00::/8`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (addr, prefix_len) = match value.split_once('/') {
+            Some((addr, prefix_len)) => {
+                (addr.parse::<IpAddr>().ok()?, Some(prefix_len.parse().ok()?))
+            }
+            None => (value.parse::<IpAddr>().ok()?, None),
+        };
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        (prefix_len <= max_len).then_some(TrustedProxy { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = usize::from(prefix_len / 8);
+    let rest_bits = prefix_len % 8;
+    if net[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest_bits);
+    net[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+/// The client address of a request: the peer address, or, if the peer is a
+/// trusted proxy, the last address in `X-Forwarded-For` that isn't.
+pub fn client_ip(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[TrustedProxy],
+) -> Option<IpAddr> {
+    let trusted = |ip: IpAddr| trusted_proxies.iter().any(|proxy| proxy.contains(ip));
+
+    let peer = peer?;
+    if !trusted(peer) {
+        return Some(peer);
+    }
+    let Some(forwarded_for) = forwarded_for else {
+        return Some(peer);
+    };
+
+    let mut client = peer;
+    for hop in forwarded_for.rsplit(',') {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) => {
+                client = ip;
+                if !trusted(ip) {
+                    break;
+                }
+            }
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
@@ -0,0 +1,132 @@
+use hmac::{Hmac, Mac};
+use qrcode::{render::svg, QrCode};
+use rand::Rng;
+use sha1::Sha1;
+
+/// Time-based one-time passwords (RFC 6238) with the parameters authenticator
+/// apps expect: HMAC-SHA1, six digits and 30 second steps.
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+/// Codes of the previous and the next step are accepted as well, to allow for
+/// clock drift and slow typing.
+const ALLOWED_DRIFT: i64 = 1;
+const SECRET_LEN: usize = 20;
+const ISSUER: &str = "Donezo";
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A new random secret, Base32 encoded as expected by authenticator apps.
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_LEN];
+    rand::rng().fill(&mut secret);
+    base32_encode(&secret)
+}
+
+/// The `otpauth://` URI that authenticator apps import, usually as a QR code.
+pub fn otpauth_uri(secret: &str, username: &str) -> String {
+    let label = format!("{ISSUER}:{username}");
+    format!(
+        "otpauth://totp/{}?secret={secret}&issuer={ISSUER}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}",
+        percent_encode(&label)
+    )
+}
+
+/// Renders `uri` as a QR code in SVG.
+pub fn qr_code_svg(uri: &str) -> String {
+    QrCode::new(uri.as_bytes())
+        .map(|code| {
+            code.render::<svg::Color>()
+                .min_dimensions(200, 200)
+                .quiet_zone(true)
+                .build()
+        })
+        .unwrap_or_default()
+}
+
+/// The time step a Unix timestamp falls into.
+pub fn step_at(unix_time: u64) -> i64 {
+    (unix_time / STEP_SECS) as i64
+}
+
+/// The code for a time step, or `None` if the secret isn't valid Base32.
+pub fn code_at_step(secret: &str, step: i64) -> Option<String> {
+    let key = base32_decode(secret)?;
+    let mut mac = Hmac::<Sha1>::new_from_slice(&key).ok()?;
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = usize::from(hash[hash.len() - 1] & 0x0f);
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    Some(format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    ))
+}
+
+/// Checks `code` against the steps around `unix_time` and returns the matching
+/// step. Steps up to `last_step` were already used and are rejected, so that
+/// an observed code cannot be replayed.
+pub fn verify(secret: &str, code: &str, unix_time: u64, last_step: Option<i64>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let current = step_at(unix_time);
+    (current - ALLOWED_DRIFT..=current + ALLOWED_DRIFT)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| code_at_step(secret, *step).is_some_and(|expected| expected == code))
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[(buffer >> bits) as usize & 0x1f] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 0x1f] as char);
+    }
+    encoded
+}
+
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in encoded.bytes().filter(|c| *c != b'=' && *c != b' ') {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
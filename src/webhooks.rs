@@ -0,0 +1,339 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::Url;
+use serde_json::json;
+use sha2::Sha256;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+use crate::auth::to_hex;
+use crate::dates::unix_now;
+use crate::db::{
+    due_webhook_deliveries, enqueue_webhook_deliveries, next_webhook_attempt,
+    record_webhook_attempt, DbPool, PendingDelivery,
+};
+use crate::error::AppError;
+use crate::models::{DeliveryStatus, Todo, WebhookEvent};
+
+/// Attempts per delivery before giving up.
+const MAX_ATTEMPTS: i64 = 8;
+
+/// Deliveries sent per round before looking for newly queued ones.
+const BATCH_SIZE: i64 = 50;
+
+/// How long a webhook may take to respond.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the worker sleeps when nothing is queued and no one wakes it.
+const IDLE: Duration = Duration::from_secs(60);
+
+pub const X_DONEZO_EVENT: &str = "x-donezo-event";
+pub const X_DONEZO_DELIVERY: &str = "x-donezo-delivery";
+pub const X_DONEZO_SIGNATURE: &str = "x-donezo-signature";
+
+/// Queues webhook deliveries and wakes the worker sending them.
+pub struct Webhooks {
+    wake: Notify,
+    /// Delay before the first retry, doubling with every further one.
+    retry_delay: Duration,
+    /// Whether webhooks may go to loopback, private and link-local addresses.
+    /// Otherwise any user could make the server send requests into its own
+    /// network.
+    allow_private: bool,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Webhooks::with_retry_delay(Duration::from_secs(60))
+    }
+}
+
+impl Webhooks {
+    pub fn with_retry_delay(retry_delay: Duration) -> Self {
+        Webhooks {
+            wake: Notify::new(),
+            retry_delay,
+            allow_private: false,
+        }
+    }
+
+    /// Allows webhooks to local and private addresses, e.g. for home
+    /// automation on the same network.
+    pub fn allow_private(mut self, allow: bool) -> Self {
+        self.allow_private = allow;
+        self
+    }
+
+    /// Checks that webhooks can be sent to `url`: it has to be an http(s) URL
+    /// and, unless private addresses are allowed, resolve to public addresses
+    /// only.
+    pub async fn check_url(&self, url: &str) -> Result<Url, AppError> {
+        let url = Url::parse(url)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https") && url.host_str().is_some())
+            .ok_or(AppError::BadRequest("Invalid webhook URL"))?;
+        if self.allow_private {
+            return Ok(url);
+        }
+
+        let host = url.host_str().unwrap_or_default();
+        let public = match literal_ip(host) {
+            Some(ip) => is_public(ip),
+            None => {
+                let port = url.port_or_known_default().unwrap_or_default();
+                match tokio::net::lookup_host((host, port)).await {
+                    Ok(addrs) => {
+                        let addrs: Vec<SocketAddr> = addrs.collect();
+                        !addrs.is_empty() && addrs.iter().all(|addr| is_public(addr.ip()))
+                    }
+                    Err(_) => return Err(AppError::BadRequest("Webhook host not found")),
+                }
+            }
+        };
+        if !public {
+            return Err(AppError::BadRequest(
+                "Webhooks can only be sent to public addresses",
+            ));
+        }
+        Ok(url)
+    }
+
+    /// Queues `event` for the webhooks of the given users, usually everyone who
+    /// can see the todo's list.
+    pub fn trigger(
+        &self,
+        db: &DbPool,
+        user_ids: &[i64],
+        event: WebhookEvent,
+        todo: &Todo,
+    ) -> Result<(), AppError> {
+        let payload = json!({
+            "event": event,
+            "created_at": unix_now(),
+            "todo": todo,
+        });
+        if enqueue_webhook_deliveries(db, user_ids, event, &payload.to_string())? > 0 {
+            self.wake.notify_one();
+        }
+        Ok(())
+    }
+
+    /// When to retry a delivery that failed for the `attempts`th time, or
+    /// `None` to give up.
+    fn next_attempt(&self, attempts: i64) -> Option<i64> {
+        if attempts >= MAX_ATTEMPTS {
+            return None;
+        }
+        let delay = self.retry_delay * 2u32.pow(attempts as u32 - 1);
+        Some(unix_now() + delay.as_secs() as i64)
+    }
+}
+
+/// The signature of a delivery's body, sent as `X-Donezo-Signature`.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", to_hex(&mac.finalize().into_bytes()))
+}
+
+/// Sends queued deliveries as they come in and retries failed ones, forever.
+/// Deliveries queued before a restart are picked up again.
+pub async fn deliver(db: DbPool, webhooks: Arc<Webhooks>) {
+    let mut client = reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .user_agent("donezo-webhooks");
+    if !webhooks.allow_private {
+        // Host names are resolved again for every connection, so checking
+        // them when the webhook was created isn't enough
+        client = client.dns_resolver(PublicResolver);
+    }
+    let client = client.build().expect("building webhook client");
+
+    loop {
+        let wait = match deliver_due(&db, &webhooks, &client).await {
+            Ok(()) => match next_webhook_attempt(&db) {
+                Ok(Some(at)) => Duration::from_secs((at - unix_now()).max(0) as u64).min(IDLE),
+                Ok(None) => IDLE,
+                Err(err) => {
+                    warn!(?err, "Failed to look up the next webhook delivery");
+                    IDLE
+                }
+            },
+            Err(err) => {
+                warn!(?err, "Failed to send webhook deliveries");
+                IDLE
+            }
+        };
+        tokio::select! {
+            _ = webhooks.wake.notified() => {}
+            _ = tokio::time::sleep(wait) => {}
+        }
+    }
+}
+
+async fn deliver_due(
+    db: &DbPool,
+    webhooks: &Webhooks,
+    client: &reqwest::Client,
+) -> Result<(), AppError> {
+    loop {
+        let due = due_webhook_deliveries(db, unix_now(), BATCH_SIZE)?;
+        if due.is_empty() {
+            return Ok(());
+        }
+        for delivery in due {
+            let (response_status, result) = send(client, webhooks, &delivery).await;
+            let attempts = delivery.attempts + 1;
+            let (status, next_attempt_at) = match &result {
+                Ok(()) => (DeliveryStatus::Delivered, None),
+                Err(_) => match webhooks.next_attempt(attempts) {
+                    Some(at) => (DeliveryStatus::Pending, Some(at)),
+                    None => (DeliveryStatus::Failed, None),
+                },
+            };
+            match &result {
+                Ok(()) => info!(id = delivery.id, url = %delivery.url, "Delivered webhook"),
+                Err(err) => {
+                    warn!(id = delivery.id, url = %delivery.url, attempts, %err, "Webhook delivery failed")
+                }
+            }
+            record_webhook_attempt(
+                db,
+                delivery.id,
+                status,
+                response_status,
+                result.err().as_deref(),
+                next_attempt_at,
+            )?;
+        }
+    }
+}
+
+/// Posts a delivery. Anything but a 2xx response counts as a failure.
+async fn send(
+    client: &reqwest::Client,
+    webhooks: &Webhooks,
+    delivery: &PendingDelivery,
+) -> (Option<u16>, Result<(), String>) {
+    let literal = Url::parse(&delivery.url)
+        .ok()
+        .and_then(|url| url.host_str().and_then(literal_ip));
+    if !webhooks.allow_private && literal.is_some_and(|ip| !is_public(ip)) {
+        return (None, Err("not a public address".to_string()));
+    }
+
+    let response = client
+        .post(&delivery.url)
+        .header("content-type", "application/json")
+        .header(X_DONEZO_EVENT, delivery.event.as_str())
+        .header(X_DONEZO_DELIVERY, delivery.id.to_string())
+        .header(
+            X_DONEZO_SIGNATURE,
+            sign(&delivery.secret, &delivery.payload),
+        )
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    match response {
+        Ok(response) if response.status().is_success() => {
+            (Some(response.status().as_u16()), Ok(()))
+        }
+        Ok(response) => {
+            let status = response.status();
+            (
+                Some(status.as_u16()),
+                Err(format!("responded with {status}")),
+            )
+        }
+        Err(err) => (None, Err(err.without_url().to_string())),
+    }
+}
+
+/// Resolves host names for deliveries, leaving out addresses that aren't
+/// public.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The IP address a URL's host is made of, if it isn't a name.
+fn literal_ip(host: &str) -> Option<IpAddr> {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+/// The IPv4 address an IPv6 address leads to: IPv4-mapped `::ffff:a.b.c.d`,
+/// IPv4-compatible `::a.b.c.d`, NAT64 `64:ff9b::/96` and 6to4 `2002::/16`.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let [.., a, b, c, d] = ip.octets();
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, _, _] | [0x64, 0xff9b, 0, 0, 0, 0, _, _] => {
+            Some(Ipv4Addr::new(a, b, c, d))
+        }
+        // `::` and `::1` are the unspecified and loopback addresses
+        [0, 0, 0, 0, 0, 0, _, _] if !ip.is_unspecified() && !ip.is_loopback() => {
+            Some(Ipv4Addr::new(a, b, c, d))
+        }
+        [0x2002, high, low, ..] => {
+            let [a, b] = high.to_be_bytes();
+            let [c, d] = low.to_be_bytes();
+            Some(Ipv4Addr::new(a, b, c, d))
+        }
+        _ => None,
+    }
+}
+
+/// Whether an address is reachable on the internet, rather than the server
+/// itself or a private or link-local network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (first == 100 && second & 0xc0 == 64)
+                // Benchmarking, 198.18.0.0/15
+                || (first == 198 && second & 0xfe == 18)
+                // Reserved, 240.0.0.0/4
+                || first >= 240)
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
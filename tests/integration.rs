@@ -1,57 +1,120 @@
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use reqwest::{Client, StatusCode};
+use axum::http::{HeaderMap, HeaderName};
+use futures_util::{SinkExt, StreamExt};
+use reqwest::cookie::{CookieStore, Jar};
+use reqwest::{Client, RequestBuilder, StatusCode, Url};
 use rusqlite::Connection;
 use serde_json::{json, Value};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
-use donezo::{auth, create_app, AppState};
+use donezo::auth::CsrfKey;
+use donezo::oidc::{OidcClient, OidcConfig, MAX_PENDING_LOGINS};
+use donezo::rate_limit::{client_ip, Lockout, LoginLimiter, TrustedProxy};
+use donezo::{auth, create_app, dates, db, totp, webhooks, AppState};
 
 struct TestServer {
     addr: String,
+    client: TestClient,
+    db: db::DbPool,
+    csrf_key: Arc<CsrfKey>,
+}
+
+/// A client that behaves like the web UI in a browser: it keeps cookies and
+/// sends the CSRF token of its session along with changes.
+#[derive(Clone)]
+struct TestClient {
     client: Client,
+    cookies: Arc<Jar>,
+    csrf_key: Arc<CsrfKey>,
+}
+
+impl TestClient {
+    fn new(csrf_key: Arc<CsrfKey>) -> Self {
+        let cookies = Arc::new(Jar::default());
+        let client = Client::builder()
+            .cookie_provider(cookies.clone())
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("Failed to create client");
+        TestClient {
+            client,
+            cookies,
+            csrf_key,
+        }
+    }
+
+    fn get(&self, url: impl AsRef<str>) -> RequestBuilder {
+        self.client.get(url.as_ref())
+    }
+
+    fn post(&self, url: impl AsRef<str>) -> RequestBuilder {
+        self.with_csrf_token(self.client.post(url.as_ref()), url.as_ref())
+    }
+
+    fn put(&self, url: impl AsRef<str>) -> RequestBuilder {
+        self.with_csrf_token(self.client.put(url.as_ref()), url.as_ref())
+    }
+
+    fn delete(&self, url: impl AsRef<str>) -> RequestBuilder {
+        self.with_csrf_token(self.client.delete(url.as_ref()), url.as_ref())
+    }
+
+    /// Adds the token the web UI gets with the page, derived from the session
+    /// cookie the same way the server does.
+    fn with_csrf_token(&self, request: RequestBuilder, url: &str) -> RequestBuilder {
+        match self.session(url) {
+            Some(session) => request.header("X-CSRF-Token", self.csrf_key.token(&session)),
+            None => request,
+        }
+    }
+
+    /// The session cookie sent along with requests to `url`.
+    fn session(&self, url: &str) -> Option<String> {
+        let url = Url::parse(url).unwrap();
+        self.cookies.cookies(&url).and_then(|cookies| {
+            cookies.to_str().unwrap().split("; ").find_map(|cookie| {
+                cookie
+                    .strip_prefix("session=")
+                    .filter(|id| !id.is_empty())
+                    .map(str::to_string)
+            })
+        })
+    }
 }
 
 impl TestServer {
     async fn new() -> Self {
+        Self::with_config(|_| {}).await
+    }
+
+    /// Starts a server whose state is adjusted by `configure` first.
+    async fn with_config(configure: impl FnOnce(&mut AppState)) -> Self {
         // Create in-memory database for testing
         let conn = Connection::open_in_memory().expect("Failed to create in-memory database");
-        conn.execute_batch(
-            "
-            CREATE TABLE IF NOT EXISTS sessions (
-                id TEXT PRIMARY KEY,
-                created_at INTEGER DEFAULT (strftime('%s', 'now')),
-                expires_at INTEGER NOT NULL
-            );
-
-            CREATE TABLE IF NOT EXISTS api_tokens (
-                id INTEGER PRIMARY KEY,
-                token TEXT UNIQUE NOT NULL,
-                name TEXT,
-                created_at INTEGER DEFAULT (strftime('%s', 'now'))
-            );
-
-            CREATE TABLE IF NOT EXISTS todos (
-                id INTEGER PRIMARY KEY,
-                title TEXT NOT NULL,
-                completed INTEGER DEFAULT 0,
-                position INTEGER DEFAULT 0,
-                created_at INTEGER DEFAULT (strftime('%s', 'now')),
-                updated_at INTEGER DEFAULT (strftime('%s', 'now'))
-            );
-            ",
-        )
-        .expect("Failed to create tables");
-
-        let db = Arc::new(Mutex::new(conn));
-        let password_hash = Arc::new(auth::hash_password("testpassword"));
+        let db = db::init_db_with_connection(conn).expect("Failed to create tables");
+        db::bootstrap_admin(&db, &auth::hash_password("testpassword"))
+            .expect("Failed to create admin user");
         let base_path = Arc::new(String::new());
 
-        let state = AppState {
-            db,
-            password_hash,
+        let mut state = AppState {
+            db: db.clone(),
             base_path,
+            trusted_proxies: Arc::new(Vec::new()),
+            auth_header: None,
+            login_limiter: Arc::default(),
+            oidc: None,
+            csrf_key: Arc::default(),
+            events: Arc::default(),
+            webhooks: Arc::default(),
         };
+        configure(&mut state);
+        let csrf_key = state.csrf_key.clone();
+        tokio::spawn(webhooks::deliver(state.db.clone(), state.webhooks.clone()));
         let app = create_app(state);
 
         // Bind to random available port
@@ -62,17 +125,25 @@ impl TestServer {
 
         // Spawn server in background
         tokio::spawn(async move {
-            axum::serve(listener, app).await.unwrap();
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
         });
 
-        // Create client with cookie store
-        let client = Client::builder()
-            .cookie_store(true)
-            .redirect(reqwest::redirect::Policy::none())
-            .build()
-            .expect("Failed to create client");
+        TestServer {
+            addr,
+            client: TestClient::new(csrf_key.clone()),
+            db,
+            csrf_key,
+        }
+    }
 
-        TestServer { addr, client }
+    /// Creates a client with its own cookie store, e.g. to log in as another user.
+    fn new_client(&self) -> TestClient {
+        TestClient::new(self.csrf_key.clone())
     }
 
     fn url(&self, path: &str) -> String {
@@ -467,8 +538,9 @@ async fn test_api_tokens() {
     let token_value = token["token"].as_str().unwrap().to_string();
     let token_id = token["id"].as_i64().unwrap();
     assert_eq!(token_value.len(), 64); // 64 character token
+    assert_eq!(token["prefix"], token_value[..8]);
 
-    // List tokens (should have one)
+    // List tokens (should have one, without revealing it)
     let resp = server
         .client
         .get(server.url("/api/tokens"))
@@ -478,6 +550,8 @@ async fn test_api_tokens() {
     assert_eq!(resp.status(), StatusCode::OK);
     let tokens: Vec<Value> = resp.json().await.unwrap();
     assert_eq!(tokens.len(), 1);
+    assert_eq!(tokens[0]["prefix"], token_value[..8]);
+    assert!(tokens[0].get("token").is_none());
 
     // Revoke the token
     let resp = server
@@ -569,6 +643,387 @@ async fn test_api_token_authentication() {
     assert!(body.contains("API created todo"));
 }
 
+#[tokio::test]
+async fn test_scoped_api_tokens() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    let resp = server
+        .client
+        .post(server.url("/api/lists"))
+        .json(&json!({"name": "Wall"}))
+        .send()
+        .await
+        .unwrap();
+    let list: Value = resp.json().await.unwrap();
+    let list_id = list["id"].as_i64().unwrap();
+
+    let mut tokens = Vec::new();
+    for body in [
+        json!({"name": "Read", "scopes": ["todos:read"]}),
+        json!({"name": "Plain", "scopes": ["plain:read"]}),
+        json!({"name": "Wall", "scopes": ["todos:read"], "list_id": list_id}),
+    ] {
+        let resp = server
+            .client
+            .post(server.url("/api/tokens"))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let token: Value = resp.json().await.unwrap();
+        assert_eq!(token["scopes"], body["scopes"]);
+        tokens.push(format!("Bearer {}", token["token"].as_str().unwrap()));
+    }
+    let resp = server
+        .client
+        .post(server.url("/api/tokens"))
+        .json(&json!({"scopes": []}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let client = Client::new();
+    let request = |method: reqwest::Method, path: &str, token: &str| {
+        client
+            .request(method, server.url(path))
+            .header("Authorization", token)
+            .json(&json!({"title": "From token"}))
+            .send()
+    };
+    let list_todos = format!("/api/lists/{list_id}/todos");
+    let other_list_todos = format!("/api/lists/{}/todos", list_id + 1);
+    let cases = [
+        (
+            &tokens[0],
+            reqwest::Method::GET,
+            "/api/todos",
+            StatusCode::OK,
+        ),
+        (
+            &tokens[0],
+            reqwest::Method::GET,
+            "/api/todos/plain",
+            StatusCode::OK,
+        ),
+        (
+            &tokens[0],
+            reqwest::Method::HEAD,
+            "/api/todos",
+            StatusCode::OK,
+        ),
+        (
+            &tokens[0],
+            reqwest::Method::POST,
+            "/api/todos",
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            &tokens[1],
+            reqwest::Method::GET,
+            "/api/todos/plain",
+            StatusCode::OK,
+        ),
+        (
+            &tokens[1],
+            reqwest::Method::HEAD,
+            "/api/todos/plain",
+            StatusCode::OK,
+        ),
+        (
+            &tokens[1],
+            reqwest::Method::GET,
+            "/api/todos",
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            &tokens[2],
+            reqwest::Method::GET,
+            &list_todos,
+            StatusCode::OK,
+        ),
+        (
+            &tokens[2],
+            reqwest::Method::GET,
+            "/api/todos",
+            StatusCode::FORBIDDEN,
+        ),
+        (
+            &tokens[2],
+            reqwest::Method::GET,
+            &other_list_todos,
+            StatusCode::FORBIDDEN,
+        ),
+    ];
+    for (token, method, path, expected) in cases {
+        let resp = request(method.clone(), path, token).await.unwrap();
+        assert_eq!(resp.status(), expected, "{method} {path}");
+    }
+
+    // Tokens restricted to a list work with its todos, but not the list itself
+    let resp = server
+        .client
+        .post(server.url("/api/tokens"))
+        .json(&json!({"name": "Bot", "list_id": list_id}))
+        .send()
+        .await
+        .unwrap();
+    let token: Value = resp.json().await.unwrap();
+    let token = format!("Bearer {}", token["token"].as_str().unwrap());
+    let resp = request(reqwest::Method::POST, &list_todos, &token)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let todo: Value = resp.json().await.unwrap();
+    assert_eq!(todo["list_id"], list_id);
+    let todo_path = format!("{list_todos}/{}", todo["id"]);
+
+    let resp = client
+        .put(server.url(&todo_path))
+        .header("Authorization", &token)
+        .json(&json!({"completed": true}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = client
+        .put(server.url(&todo_path))
+        .header("Authorization", &token)
+        .json(&json!({"list_id": list_id + 1}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // Todos of other lists can't be reached through the list
+    let resp = server
+        .client
+        .post(server.url("/api/lists"))
+        .json(&json!({"name": "Private"}))
+        .send()
+        .await
+        .unwrap();
+    let private: Value = resp.json().await.unwrap();
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Elsewhere", "list_id": private["id"]}))
+        .send()
+        .await
+        .unwrap();
+    let other: Value = resp.json().await.unwrap();
+    let resp = request(
+        reqwest::Method::GET,
+        &format!("{list_todos}/{}", other["id"]),
+        &token,
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let list_path = format!("/api/lists/{list_id}");
+    let members = format!("{list_path}/members");
+    let cases = [
+        (reqwest::Method::GET, list_path.as_str(), StatusCode::OK),
+        (reqwest::Method::PUT, &list_path, StatusCode::FORBIDDEN),
+        (reqwest::Method::DELETE, &list_path, StatusCode::FORBIDDEN),
+        (reqwest::Method::GET, &members, StatusCode::FORBIDDEN),
+        (reqwest::Method::POST, &members, StatusCode::FORBIDDEN),
+        (reqwest::Method::POST, "/api/todos", StatusCode::FORBIDDEN),
+        (reqwest::Method::DELETE, &todo_path, StatusCode::NO_CONTENT),
+    ];
+    for (method, path, expected) in cases {
+        let resp = request(method.clone(), path, &token).await.unwrap();
+        assert_eq!(resp.status(), expected, "{method} {path}");
+    }
+}
+
+#[tokio::test]
+async fn test_api_token_expiry_and_usage() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    let resp = server
+        .client
+        .post(server.url("/api/tokens"))
+        .json(&json!({"expires_at": 1_000_000_000}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let expires_at = 4_102_444_800_i64; // 2100-01-01
+    let resp = server
+        .client
+        .post(server.url("/api/tokens"))
+        .json(&json!({"name": "Expiring", "expires_at": expires_at}))
+        .send()
+        .await
+        .unwrap();
+    let token: Value = resp.json().await.unwrap();
+    assert_eq!(token["expires_at"], expires_at);
+    assert!(token["last_used_at"].is_null());
+    let bearer = format!("Bearer {}", token["token"].as_str().unwrap());
+
+    let client = Client::new();
+    let resp = client
+        .get(server.url("/api/todos"))
+        .header("Authorization", &bearer)
+        .header("User-Agent", "wall-dashboard/1.0")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = server
+        .client
+        .get(server.url("/api/tokens"))
+        .send()
+        .await
+        .unwrap();
+    let tokens: Vec<Value> = resp.json().await.unwrap();
+    assert!(tokens[0]["last_used_at"].is_i64());
+    assert_eq!(tokens[0]["last_ip"], "127.0.0.1");
+    assert_eq!(tokens[0]["last_user_agent"], "wall-dashboard/1.0");
+
+    server
+        .db
+        .lock()
+        .unwrap()
+        .execute("UPDATE api_tokens SET expires_at = 1", [])
+        .unwrap();
+    let resp = client
+        .get(server.url("/api/todos"))
+        .header("Authorization", &bearer)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[test]
+fn test_plaintext_api_tokens_migrated() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        "
+        CREATE TABLE api_tokens (
+            id INTEGER PRIMARY KEY,
+            token TEXT UNIQUE NOT NULL,
+            name TEXT,
+            created_at INTEGER DEFAULT (strftime('%s', 'now'))
+        );
+        INSERT INTO api_tokens (token, name) VALUES ('legacytoken0123456789', 'Old');
+        ",
+    )
+    .unwrap();
+
+    let db = db::init_db_with_connection(conn).unwrap();
+    let admin = db::bootstrap_admin(&db, &auth::hash_password("testpassword")).unwrap();
+
+    let token = db::get_api_token_by_value(&db, "legacytoken0123456789")
+        .unwrap()
+        .expect("legacy token still works");
+    assert_eq!(token.prefix, "legacyto");
+    assert_eq!(token.user_id, admin.id);
+
+    let stored: String = db
+        .lock()
+        .unwrap()
+        .query_row("SELECT token_hash FROM api_tokens", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(stored, auth::hash_token("legacytoken0123456789"));
+}
+
+#[test]
+fn test_baseline_database_migrated() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        "
+        CREATE TABLE sessions (
+            id TEXT PRIMARY KEY,
+            created_at INTEGER DEFAULT (strftime('%s', 'now')),
+            expires_at INTEGER NOT NULL
+        );
+        CREATE TABLE api_tokens (
+            id INTEGER PRIMARY KEY,
+            token TEXT UNIQUE NOT NULL,
+            name TEXT,
+            created_at INTEGER DEFAULT (strftime('%s', 'now'))
+        );
+        CREATE TABLE todos (
+            id INTEGER PRIMARY KEY,
+            title TEXT NOT NULL,
+            completed INTEGER DEFAULT 0,
+            position INTEGER DEFAULT 0,
+            created_at INTEGER DEFAULT (strftime('%s', 'now')),
+            updated_at INTEGER DEFAULT (strftime('%s', 'now'))
+        );
+        INSERT INTO sessions (id, expires_at) VALUES ('oldsession', 4102444800);
+        INSERT INTO api_tokens (token, name) VALUES ('baselinetoken0123456789', 'Script');
+        INSERT INTO todos (title, completed, position) VALUES ('Second', 0, 2);
+        INSERT INTO todos (title, completed, position) VALUES ('First', 1, 1);
+        INSERT INTO todos (title, completed, position) VALUES ('Third', 0, 3);
+        ",
+    )
+    .unwrap();
+
+    let db = db::init_db_with_connection(conn).unwrap();
+    let admin = db::bootstrap_admin(&db, &auth::hash_password("testpassword")).unwrap();
+
+    let lists = db::list_lists(&db, admin.id).unwrap();
+    assert_eq!(lists.len(), 1);
+    assert_eq!(lists[0].name, "Todos");
+
+    let todos = db::list_todos(&db, admin.id, &Default::default()).unwrap();
+    let summary: Vec<_> = todos
+        .iter()
+        .map(|todo| {
+            (
+                todo.title.as_str(),
+                todo.position,
+                todo.completed,
+                todo.list_id,
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("First", 1, true, lists[0].id),
+            ("Second", 2, false, lists[0].id),
+            ("Third", 3, false, lists[0].id),
+        ]
+    );
+
+    let session = db::get_session(&db, "oldsession")
+        .unwrap()
+        .expect("session survives");
+    assert_eq!(session.user_id, admin.id);
+    assert_eq!(session.expires_at, 4102444800);
+
+    let token = db::get_api_token_by_value(&db, "baselinetoken0123456789")
+        .unwrap()
+        .expect("token still works");
+    assert_eq!(token.user_id, admin.id);
+    assert_eq!(token.name.as_deref(), Some("Script"));
+}
+
 #[tokio::test]
 async fn test_logout() {
     let server = TestServer::new().await;
@@ -612,10 +1067,179 @@ async fn test_logout() {
 }
 
 #[tokio::test]
-async fn test_authenticated_user_redirected_from_login() {
+async fn test_login_rate_limiting() {
+    let server = TestServer::with_config(|state| {
+        state.trusted_proxies = Arc::new(vec![TrustedProxy::parse("127.0.0.1").unwrap()]);
+    })
+    .await;
+
+    let login = |client_ip: &str, password: &str| {
+        server
+            .client
+            .post(server.url("/api/login"))
+            .header("X-Forwarded-For", format!("{client_ip}, 127.0.0.1"))
+            .json(&json!({"password": password}))
+            .send()
+    };
+
+    for _ in 0..5 {
+        let resp = login("203.0.113.7", "wrong").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    // Locked out, even with the right password
+    let resp = login("203.0.113.7", "testpassword").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = resp.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((1..=30).contains(&retry_after));
+
+    // Other clients behind the same proxy are unaffected
+    let resp = login("203.0.113.8", "testpassword").await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Without trusting the proxy, the header cannot be used to dodge the limit
+    let server = TestServer::new().await;
+    for i in 0..5 {
+        let resp = server
+            .client
+            .post(server.url("/api/login"))
+            .header("X-Forwarded-For", format!("198.51.100.{i}"))
+            .json(&json!({"password": "wrong"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    let resp = server
+        .client
+        .post(server.url("/api/login"))
+        .header("X-Forwarded-For", "198.51.100.99")
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[test]
+fn test_login_backoff() {
+    let limiter = LoginLimiter::default();
+    let ip = Some("203.0.113.7".parse().unwrap());
+    let start = std::time::Instant::now();
+    let secs = std::time::Duration::from_secs;
+
+    for _ in 0..4 {
+        assert_eq!(limiter.record_failure(ip, start), None);
+    }
+    assert_eq!(
+        limiter.record_failure(ip, start),
+        Some(Lockout::Ip(secs(30)))
+    );
+    assert_eq!(
+        limiter.check(ip, start + secs(10)),
+        Err(Lockout::Ip(secs(20)))
+    );
+    assert_eq!(limiter.check(ip, start + secs(30)), Ok(()));
+
+    // Each further failure doubles the wait
+    let later = start + secs(30);
+    assert_eq!(
+        limiter.record_failure(ip, later),
+        Some(Lockout::Ip(secs(60)))
+    );
+    assert_eq!(
+        limiter.record_failure(ip, later),
+        Some(Lockout::Ip(secs(120)))
+    );
+
+    // Failures are forgotten after an hour, and cleared by a successful login
+    let much_later = later + secs(2 * 60 * 60);
+    assert_eq!(limiter.record_failure(ip, much_later), None);
+    limiter.record_success(ip);
+    assert_eq!(limiter.check(ip, much_later), Ok(()));
+
+    // Many failures from different clients lock out everyone
+    let limiter = LoginLimiter::default();
+    for i in 0..100u32 {
+        let ip = Some(std::net::Ipv4Addr::from(0x0a00_0000 + i).into());
+        limiter.record_failure(ip, start);
+    }
+    assert_eq!(limiter.check(ip, start), Err(Lockout::Global(secs(5))));
+}
+
+#[test]
+fn test_client_ip_behind_proxies() {
+    let proxies = [
+        TrustedProxy::parse("10.0.0.0/8").unwrap(),
+        TrustedProxy::parse("fd00::1").unwrap(),
+    ];
+    assert!(TrustedProxy::parse("10.0.0.0/33").is_none());
+    assert!(TrustedProxy::parse("proxy").is_none());
+
+    let ip = |s: &str| s.parse().unwrap();
+    // Untrusted peers are taken as they are
+    assert_eq!(
+        client_ip(Some(ip("192.0.2.1")), Some("203.0.113.7"), &proxies),
+        Some(ip("192.0.2.1"))
+    );
+    // The last untrusted hop is the client
+    assert_eq!(
+        client_ip(
+            Some(ip("10.1.2.3")),
+            Some("198.51.100.1, 203.0.113.7, 10.0.0.2"),
+            &proxies
+        ),
+        Some(ip("203.0.113.7"))
+    );
+    assert_eq!(
+        client_ip(Some(ip("fd00::1")), None, &proxies),
+        Some(ip("fd00::1"))
+    );
+}
+
+#[test]
+fn test_totp_codes() {
+    // RFC 6238 test vectors, truncated to six digits
+    let secret = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+    for (time, code) in [
+        (59, "287082"),
+        (1_111_111_109, "081804"),
+        (1_234_567_890, "005924"),
+        (2_000_000_000, "279037"),
+    ] {
+        assert_eq!(
+            totp::code_at_step(secret, totp::step_at(time)).as_deref(),
+            Some(code)
+        );
+    }
+
+    assert_eq!(totp::verify(secret, "287082", 59, None), Some(1));
+    assert_eq!(totp::verify(secret, " 287082 ", 75, None), Some(1));
+    // Used steps cannot be replayed
+    assert_eq!(totp::verify(secret, "287082", 59, Some(1)), None);
+    // One step of drift either way is accepted, but no more
+    let next = totp::code_at_step(secret, 2).unwrap();
+    assert_eq!(totp::verify(secret, &next, 59, None), Some(2));
+    assert_eq!(totp::verify(secret, &next, 5, None), None);
+    assert_eq!(totp::verify(secret, "28708", 59, None), None);
+
+    let uri = totp::otpauth_uri(secret, "admin");
+    assert!(uri.starts_with("otpauth://totp/Donezo%3Aadmin?secret=GEZDGNBV"));
+}
+
+#[tokio::test]
+async fn test_totp_login() {
     let server = TestServer::new().await;
+    let now = || {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    };
 
-    // Login
     server
         .client
         .post(server.url("/api/login"))
@@ -624,33 +1248,2960 @@ async fn test_authenticated_user_redirected_from_login() {
         .await
         .unwrap();
 
-    // Try to access login page (should redirect to /)
     let resp = server
         .client
-        .get(server.url("/login"))
+        .post(server.url("/api/totp/setup"))
         .send()
         .await
         .unwrap();
-    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
-    assert_eq!(resp.headers().get("location").unwrap(), "/");
-}
+    let setup: Value = resp.json().await.unwrap();
+    let secret = setup["secret"].as_str().unwrap().to_string();
+    assert!(setup["otpauth_uri"].as_str().unwrap().contains(&secret));
+    assert!(setup["qr_code_svg"].as_str().unwrap().contains("<svg"));
 
-#[tokio::test]
-async fn test_authenticated_access_to_index() {
-    let server = TestServer::new().await;
+    let resp = server
+        .client
+        .post(server.url("/api/totp/enable"))
+        .json(&json!({"code": "000000x"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
 
-    // Login
-    server
+    let step = totp::step_at(now());
+    let code = totp::code_at_step(&secret, step).unwrap();
+    let resp = server
+        .client
+        .post(server.url("/api/totp/enable"))
+        .json(&json!({"code": code}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let enabled: Value = resp.json().await.unwrap();
+    let recovery_codes = enabled["recovery_codes"].as_array().unwrap();
+    assert_eq!(recovery_codes.len(), 10);
+
+    let resp = server
         .client
+        .get(server.url("/api/me"))
+        .send()
+        .await
+        .unwrap();
+    let me: Value = resp.json().await.unwrap();
+    assert_eq!(me["totp_enabled"], true);
+
+    // The password alone is no longer enough
+    let client = server.new_client();
+    let resp = client
         .post(server.url("/api/login"))
         .json(&json!({"password": "testpassword"}))
         .send()
         .await
         .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["totp_required"], true);
 
-    // Access index page (should succeed)
-    let resp = server.client.get(server.url("/")).send().await.unwrap();
+    // The code used for enabling cannot be replayed, the next one works
+    let resp = client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword", "totp_code": code}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let next_code = totp::code_at_step(&secret, step + 1).unwrap();
+    let resp = client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword", "totp_code": next_code}))
+        .send()
+        .await
+        .unwrap();
     assert_eq!(resp.status(), StatusCode::OK);
-    let body = resp.text().await.unwrap();
-    assert!(body.contains("Tasks"));
+
+    // Recovery codes work once, regardless of case and dashes
+    let recovery_code = recovery_codes[0].as_str().unwrap();
+    for expected in [StatusCode::OK, StatusCode::UNAUTHORIZED] {
+        let resp = server
+            .new_client()
+            .post(server.url("/api/login"))
+            .json(&json!({
+                "password": "testpassword",
+                "totp_code": recovery_code.replace('-', "").to_uppercase()
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), expected);
+    }
+
+    let resp = server
+        .client
+        .post(server.url("/api/totp/disable"))
+        .json(&json!({"code": recovery_codes[1]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let resp = server
+        .new_client()
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_session_management() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .header("User-Agent", "laptop")
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+    let phone = server.new_client();
+    phone
+        .post(server.url("/api/login"))
+        .header("User-Agent", "phone")
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    let resp = server
+        .client
+        .get(server.url("/api/sessions"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let sessions: Vec<Value> = resp.json().await.unwrap();
+    assert_eq!(sessions.len(), 2);
+    let current: Vec<&Value> = sessions.iter().filter(|s| s["current"] == true).collect();
+    assert_eq!(current.len(), 1);
+    assert_eq!(current[0]["ip"], "127.0.0.1");
+    let other = sessions.iter().find(|s| s["current"] == false).unwrap();
+    assert_eq!(other["user_agent"], "phone");
+
+    // Revoke the phone's session
+    let resp = server
+        .client
+        .delete(server.url(&format!("/api/sessions/{}", other["id"].as_str().unwrap())))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let resp = phone.get(server.url("/api/todos")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = server
+        .client
+        .get(server.url("/api/todos"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Log out everywhere
+    phone
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+    let resp = server
+        .client
+        .delete(server.url("/api/sessions"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    for client in [&server.client, &phone] {
+        let resp = client.get(server.url("/api/todos")).send().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[tokio::test]
+async fn test_authenticated_user_redirected_from_login() {
+    let server = TestServer::new().await;
+
+    // Login
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    // Try to access login page (should redirect to /)
+    let resp = server
+        .client
+        .get(server.url("/login"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    assert_eq!(resp.headers().get("location").unwrap(), "/");
+}
+
+#[tokio::test]
+async fn test_authenticated_access_to_index() {
+    let server = TestServer::new().await;
+
+    // Login
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    // Access index page (should succeed)
+    let resp = server.client.get(server.url("/")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = resp.text().await.unwrap();
+    assert!(body.contains("Tasks"));
+}
+
+#[tokio::test]
+async fn test_list_crud() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    // No lists until one is created explicitly or implicitly
+    let resp = server
+        .client
+        .get(server.url("/api/lists"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let lists: Vec<Value> = resp.json().await.unwrap();
+    assert!(lists.is_empty());
+
+    // Create a list
+    let resp = server
+        .client
+        .post(server.url("/api/lists"))
+        .json(&json!({"name": "Groceries"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let list: Value = resp.json().await.unwrap();
+    assert_eq!(list["name"], "Groceries");
+    let list_id = list["id"].as_i64().unwrap();
+
+    // Empty names are rejected
+    let resp = server
+        .client
+        .post(server.url("/api/lists"))
+        .json(&json!({"name": "  "}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // Rename it
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/lists/{}", list_id)))
+        .json(&json!({"name": "Shopping"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let list: Value = resp.json().await.unwrap();
+    assert_eq!(list["name"], "Shopping");
+
+    // Add a todo to it
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Milk", "list_id": list_id}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let todo: Value = resp.json().await.unwrap();
+    assert_eq!(todo["list_id"], list_id);
+    let todo_id = todo["id"].as_i64().unwrap();
+
+    // Adding to a missing list fails
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Eggs", "list_id": 9999}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // Deleting the list removes its todos
+    let resp = server
+        .client
+        .delete(server.url(&format!("/api/lists/{}", list_id)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    let resp = server
+        .client
+        .get(server.url(&format!("/api/todos/{}", todo_id)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let resp = server
+        .client
+        .get(server.url(&format!("/api/lists/{}", list_id)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_todos_scoped_to_list() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    // A todo without list_id lands in an implicitly created default list
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Water plants"}))
+        .send()
+        .await
+        .unwrap();
+    let todo: Value = resp.json().await.unwrap();
+    let default_list = todo["list_id"].as_i64().unwrap();
+
+    let resp = server
+        .client
+        .post(server.url("/api/lists"))
+        .json(&json!({"name": "Ops"}))
+        .send()
+        .await
+        .unwrap();
+    let list: Value = resp.json().await.unwrap();
+    let ops = list["id"].as_i64().unwrap();
+
+    let mut ids = Vec::new();
+    for title in ["Rotate certs", "Patch servers", "Check backups"] {
+        let resp = server
+            .client
+            .post(server.url("/api/todos"))
+            .json(&json!({"title": title, "list_id": ops}))
+            .send()
+            .await
+            .unwrap();
+        let todo: Value = resp.json().await.unwrap();
+        ids.push(todo["id"].as_i64().unwrap());
+    }
+
+    // Only the list's own todos are returned
+    let resp = server
+        .client
+        .get(server.url(&format!("/api/lists/{}/todos", ops)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let todos: Vec<Value> = resp.json().await.unwrap();
+    assert_eq!(todos.len(), 3);
+    assert!(todos.iter().all(|t| t["list_id"] == ops));
+
+    // Reorder within the list
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/lists/{}/todos/reorder", ops)))
+        .json(&json!({"ids": [ids[2], ids[0], ids[1]]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let todos: Vec<Value> = resp.json().await.unwrap();
+    let titles: Vec<&str> = todos.iter().map(|t| t["title"].as_str().unwrap()).collect();
+    assert_eq!(titles, ["Check backups", "Rotate certs", "Patch servers"]);
+
+    // Plain text export of a single list
+    let resp = server
+        .client
+        .get(server.url(&format!("/api/lists/{}/todos/plain", ops)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = resp.text().await.unwrap();
+    assert_eq!(body, "Check backups\nRotate certs\nPatch servers\n");
+
+    // Moving a todo to another list appends it there
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/todos/{}", ids[0])))
+        .json(&json!({"list_id": default_list}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = server
+        .client
+        .get(server.url(&format!("/api/lists/{}/todos/plain", default_list)))
+        .send()
+        .await
+        .unwrap();
+    let body = resp.text().await.unwrap();
+    assert_eq!(body, "Water plants\nRotate certs\n");
+
+    // Missing lists are reported as such
+    let resp = server
+        .client
+        .get(server.url("/api/lists/9999/todos"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_due_dates() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    let today = time::OffsetDateTime::now_utc().date();
+    let day = |offset: i64| dates::format_date(today + time::Duration::days(offset));
+
+    let todos = [
+        ("Yesterday", Some(day(-1))),
+        ("Today", Some(day(0))),
+        ("In three days", Some(day(3))),
+        ("In ten days", Some(day(10))),
+        ("Last year", Some("2000-01-01T12:00:00+02:00".to_string())),
+        ("Someday", None),
+    ];
+    for (title, due_at) in &todos {
+        let resp = server
+            .client
+            .post(server.url("/api/todos"))
+            .json(&json!({"title": title, "due_at": due_at}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+    }
+
+    // Date-times are normalized to UTC
+    let resp = server
+        .client
+        .get(server.url("/api/todos"))
+        .send()
+        .await
+        .unwrap();
+    let all: Vec<Value> = resp.json().await.unwrap();
+    let last_year = all.iter().find(|t| t["title"] == "Last year").unwrap();
+    assert_eq!(last_year["due_at"], "2000-01-01T10:00:00Z");
+    let someday = all.iter().find(|t| t["title"] == "Someday").unwrap();
+    assert!(someday["due_at"].is_null());
+
+    let due = |filter: &'static str| {
+        let client = &server.client;
+        let url = server.url(&format!("/api/todos?due={}", filter));
+        async move {
+            let resp = client.get(url).send().await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let todos: Vec<Value> = resp.json().await.unwrap();
+            todos
+                .iter()
+                .map(|t| t["title"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        }
+    };
+
+    assert_eq!(due("today").await, ["Today"]);
+    assert_eq!(due("overdue").await, ["Last year", "Yesterday"]);
+    assert_eq!(due("week").await, ["Today", "In three days"]);
+
+    // Completed todos are no longer due
+    let yesterday = all.iter().find(|t| t["title"] == "Yesterday").unwrap();
+    let yesterday_id = yesterday["id"].as_i64().unwrap();
+    server
+        .client
+        .put(server.url(&format!("/api/todos/{}", yesterday_id)))
+        .json(&json!({"completed": true}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(due("overdue").await, ["Last year"]);
+
+    // Clearing and rejecting due dates
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/todos/{}", yesterday_id)))
+        .json(&json!({"due_at": null}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let todo: Value = resp.json().await.unwrap();
+    assert!(todo["due_at"].is_null());
+
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/todos/{}", yesterday_id)))
+        .json(&json!({"due_at": "next tuesday"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_tags() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    let todos = [
+        ("Write report", vec!["work"]),
+        ("Learn Rust", vec!["Work", "someday", "work"]),
+        ("Paint fence", vec!["home"]),
+    ];
+    let mut ids = Vec::new();
+    for (title, tags) in &todos {
+        let resp = server
+            .client
+            .post(server.url("/api/todos"))
+            .json(&json!({"title": title, "tags": tags}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let todo: Value = resp.json().await.unwrap();
+        ids.push(todo["id"].as_i64().unwrap());
+    }
+
+    // Tags are normalized, deduplicated and sorted
+    let resp = server
+        .client
+        .get(server.url(&format!("/api/todos/{}", ids[1])))
+        .send()
+        .await
+        .unwrap();
+    let todo: Value = resp.json().await.unwrap();
+    assert_eq!(todo["tags"], json!(["someday", "work"]));
+
+    let titles = |query: &'static str| {
+        let client = &server.client;
+        let url = server.url(&format!("/api/todos?{}", query));
+        async move {
+            let todos: Vec<Value> = client.get(url).send().await.unwrap().json().await.unwrap();
+            todos
+                .iter()
+                .map(|t| t["title"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        }
+    };
+
+    assert_eq!(titles("tag=work").await, ["Write report", "Learn Rust"]);
+    assert_eq!(titles("tag=work&tag=-someday").await, ["Write report"]);
+    assert_eq!(titles("tag=-work").await, ["Paint fence"]);
+    assert!(titles("tag=work&tag=home").await.is_empty());
+
+    // Replacing tags
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/todos/{}", ids[2])))
+        .json(&json!({"tags": ["work"]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let todo: Value = resp.json().await.unwrap();
+    assert_eq!(todo["tags"], json!(["work"]));
+    assert!(titles("tag=home").await.is_empty());
+
+    // Plain text export honours the filter
+    let resp = server
+        .client
+        .get(server.url("/api/todos/plain?tag=work&tag=-someday"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.text().await.unwrap(), "Write report\nPaint fence\n");
+
+    // Tags that would be read as exclusions are rejected
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Bad", "tags": ["-nope"]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_search() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    let mut ids = Vec::new();
+    for title in [
+        "Buy groceries and milk",
+        "Renew passport",
+        "Milk the cows",
+        "Café reservation",
+    ] {
+        let resp = server
+            .client
+            .post(server.url("/api/todos"))
+            .json(&json!({"title": title}))
+            .send()
+            .await
+            .unwrap();
+        let todo: Value = resp.json().await.unwrap();
+        ids.push(todo["id"].as_i64().unwrap());
+    }
+
+    let search = |q: &'static str| {
+        let client = &server.client;
+        let url = server.url(&format!("/api/todos/search?q={}", q));
+        async move {
+            let resp = client.get(url).send().await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let results: Vec<Value> = resp.json().await.unwrap();
+            results
+        }
+    };
+
+    // Prefix matching on every word
+    let results = search("gro+mil").await;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0]["title"], "Buy groceries and milk");
+    assert_eq!(
+        results[0]["snippet"],
+        "Buy <mark>groceries</mark> and <mark>milk</mark>"
+    );
+
+    // Ranked: the shorter title matches "milk" better
+    let results = search("milk").await;
+    let titles: Vec<&str> = results
+        .iter()
+        .map(|r| r["title"].as_str().unwrap())
+        .collect();
+    assert_eq!(titles, ["Milk the cows", "Buy groceries and milk"]);
+
+    // Diacritics are ignored and quotes cannot break the query
+    assert_eq!(search("cafe").await.len(), 1);
+    assert!(search("%22").await.is_empty());
+
+    // The index follows updates and deletes
+    server
+        .client
+        .put(server.url(&format!("/api/todos/{}", ids[1])))
+        .json(&json!({"title": "Renew driving licence"}))
+        .send()
+        .await
+        .unwrap();
+    assert!(search("passport").await.is_empty());
+    assert_eq!(search("licence").await.len(), 1);
+
+    server
+        .client
+        .delete(server.url(&format!("/api/todos/{}", ids[2])))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(search("milk").await.len(), 1);
+
+    let resp = server
+        .client
+        .get(server.url("/api/todos/search?q="))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_subtasks() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    let create = |body: Value| {
+        let client = &server.client;
+        let url = server.url("/api/todos");
+        async move {
+            let resp = client.post(url).json(&body).send().await.unwrap();
+            assert_eq!(resp.status(), StatusCode::CREATED);
+            let todo: Value = resp.json().await.unwrap();
+            todo["id"].as_i64().unwrap()
+        }
+    };
+    let titles = || {
+        let client = &server.client;
+        let url = server.url("/api/todos");
+        async move {
+            let todos: Vec<Value> = client.get(url).send().await.unwrap().json().await.unwrap();
+            todos
+                .iter()
+                .map(|t| t["title"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        }
+    };
+
+    let release = create(json!({"title": "Release"})).await;
+    let chores = create(json!({"title": "Chores"})).await;
+    let changelog = create(json!({"title": "Changelog", "parent_id": release})).await;
+    let tag = create(json!({"title": "Tag", "parent_id": release})).await;
+    create(json!({"title": "Sign tag", "parent_id": tag})).await;
+
+    // Subtasks directly follow their parent
+    assert_eq!(
+        titles().await,
+        ["Release", "Changelog", "Tag", "Sign tag", "Chores"]
+    );
+
+    // Reordering only touches siblings
+    let resp = server
+        .client
+        .put(server.url("/api/todos/reorder"))
+        .json(&json!({"ids": [tag, changelog]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert_eq!(
+        titles().await,
+        ["Release", "Tag", "Sign tag", "Changelog", "Chores"]
+    );
+
+    // Plain text export indents subtasks
+    let resp = server
+        .client
+        .get(server.url("/api/todos/plain"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.text().await.unwrap(),
+        "Release\n  Tag\n    Sign tag\n  Changelog\nChores\n"
+    );
+
+    // A todo cannot become a subtask of its own subtree
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/todos/{}", release)))
+        .json(&json!({"parent_id": tag}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // Completing with cascade completes the whole subtree
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/todos/{}", release)))
+        .json(&json!({"completed": true, "cascade": true}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = server
+        .client
+        .get(server.url("/api/todos/plain"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.text().await.unwrap(), "Chores\n");
+
+    // Moving a todo to another parent and back to the top level
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/todos/{}", changelog)))
+        .json(&json!({"parent_id": chores}))
+        .send()
+        .await
+        .unwrap();
+    let todo: Value = resp.json().await.unwrap();
+    assert_eq!(todo["parent_id"], chores);
+
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/todos/{}", changelog)))
+        .json(&json!({"parent_id": null}))
+        .send()
+        .await
+        .unwrap();
+    let todo: Value = resp.json().await.unwrap();
+    assert!(todo["parent_id"].is_null());
+
+    // Moving a parent to another list takes its subtasks along
+    let resp = server
+        .client
+        .post(server.url("/api/lists"))
+        .json(&json!({"name": "Archive"}))
+        .send()
+        .await
+        .unwrap();
+    let list: Value = resp.json().await.unwrap();
+    let archive = list["id"].as_i64().unwrap();
+    server
+        .client
+        .put(server.url(&format!("/api/todos/{}", release)))
+        .json(&json!({"list_id": archive}))
+        .send()
+        .await
+        .unwrap();
+    let resp = server
+        .client
+        .get(server.url(&format!("/api/lists/{}/todos", archive)))
+        .send()
+        .await
+        .unwrap();
+    let todos: Vec<Value> = resp.json().await.unwrap();
+    assert_eq!(todos.len(), 3);
+
+    // Deleting a parent deletes its subtasks
+    let resp = server
+        .client
+        .delete(server.url(&format!("/api/todos/{}", release)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(titles().await, ["Chores", "Changelog"]);
+}
+
+#[tokio::test]
+async fn test_recurring_todos() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    let today = time::OffsetDateTime::now_utc().date();
+    let day = |offset: i64| dates::format_date(today + time::Duration::days(offset));
+
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({
+            "title": "Water plants",
+            "due_at": format!("{}T08:00:00Z", day(-3)),
+            "tags": ["home"],
+            "recurrence": {"type": "daily"}
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let todo: Value = resp.json().await.unwrap();
+    assert_eq!(todo["recurrence"], json!({"type": "daily"}));
+    let id = todo["id"].as_i64().unwrap();
+
+    // Completing it keeps the todo as history and spawns the next occurrence,
+    // skipping the days that already passed
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/todos/{}", id)))
+        .json(&json!({"completed": true}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let done: Value = resp.json().await.unwrap();
+    assert_eq!(done["completed"], true);
+    assert!(done["recurrence"].is_null());
+
+    let resp = server
+        .client
+        .get(server.url("/api/todos?completed=false"))
+        .send()
+        .await
+        .unwrap();
+    let open: Vec<Value> = resp.json().await.unwrap();
+    assert_eq!(open.len(), 1);
+    assert_eq!(open[0]["title"], "Water plants");
+    assert_eq!(open[0]["due_at"], format!("{}T08:00:00Z", day(0)));
+    assert_eq!(open[0]["tags"], json!(["home"]));
+    assert_eq!(open[0]["recurrence"], json!({"type": "daily"}));
+
+    // Completing it again does not spawn twice
+    for _ in 0..2 {
+        server
+            .client
+            .put(server.url(&format!("/api/todos/{}", open[0]["id"])))
+            .json(&json!({"completed": true}))
+            .send()
+            .await
+            .unwrap();
+    }
+    let resp = server
+        .client
+        .get(server.url("/api/todos"))
+        .send()
+        .await
+        .unwrap();
+    let all: Vec<Value> = resp.json().await.unwrap();
+    assert_eq!(all.len(), 3);
+
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Invalid", "recurrence": {"type": "weekly", "weekdays": []}}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_recurring_subtasks_completed_by_cascade() {
+    let server = TestServer::new().await;
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    let create = |body: Value| {
+        let server = &server;
+        async move {
+            let resp = server
+                .client
+                .post(server.url("/api/todos"))
+                .json(&body)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::CREATED);
+            resp.json::<Value>().await.unwrap()
+        }
+    };
+    let complete = |todo: Value| {
+        let server = &server;
+        async move {
+            let resp = server
+                .client
+                .put(server.url(&format!("/api/todos/{}", todo["id"])))
+                .json(&json!({"completed": true, "cascade": true}))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+    };
+    let open_todos = || async {
+        let resp = server
+            .client
+            .get(server.url("/api/todos?completed=false"))
+            .send()
+            .await
+            .unwrap();
+        resp.json::<Vec<Value>>().await.unwrap()
+    };
+
+    // Under a parent that doesn't recur, the next occurrence is a todo of its own
+    let garden = create(json!({"title": "Garden"})).await;
+    let water = create(json!({
+        "title": "Water plants",
+        "parent_id": garden["id"],
+        "recurrence": {"type": "daily"}
+    }))
+    .await;
+    complete(garden.clone()).await;
+
+    let resp = server
+        .client
+        .get(server.url(&format!("/api/todos/{}", water["id"])))
+        .send()
+        .await
+        .unwrap();
+    let done: Value = resp.json().await.unwrap();
+    assert_eq!(done["completed"], true);
+    assert!(done["recurrence"].is_null());
+
+    let open = open_todos().await;
+    assert_eq!(open.len(), 1);
+    assert_ne!(open[0]["id"], water["id"]);
+    assert_eq!(open[0]["title"], "Water plants");
+    assert!(open[0]["parent_id"].is_null());
+    assert_eq!(open[0]["recurrence"], json!({"type": "daily"}));
+    complete(open[0].clone()).await;
+
+    // Under a recurring parent, it follows the parent's next occurrence
+    let review = create(json!({"title": "Review", "recurrence": {"type": "daily"}})).await;
+    create(json!({
+        "title": "Inbox zero",
+        "parent_id": review["id"],
+        "recurrence": {"type": "daily"}
+    }))
+    .await;
+    complete(review.clone()).await;
+
+    let open = open_todos().await;
+    let open_titled = |title: &str| open.iter().find(|todo| todo["title"] == title).unwrap();
+    let next_review = open_titled("Review");
+    let next_inbox = open_titled("Inbox zero");
+    assert_ne!(next_review["id"], review["id"]);
+    assert_eq!(next_inbox["parent_id"], next_review["id"]);
+}
+
+#[tokio::test]
+async fn test_recurrence_limits() {
+    let server = TestServer::new().await;
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    for days in [0, 3651, 4_000_000_000u32] {
+        let resp = server
+            .client
+            .post(server.url("/api/todos"))
+            .json(&json!({
+                "title": "Someday",
+                "recurrence": {"type": "after_completion", "days": days}
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // A series running past the last representable date ends
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({
+            "title": "End of time",
+            "due_at": "9999-12-31",
+            "recurrence": {"type": "daily"}
+        }))
+        .send()
+        .await
+        .unwrap();
+    let todo: Value = resp.json().await.unwrap();
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/todos/{}", todo["id"])))
+        .json(&json!({"completed": true}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let done: Value = resp.json().await.unwrap();
+    assert_eq!(done["completed"], true);
+    assert!(done["recurrence"].is_null());
+
+    let resp = server
+        .client
+        .get(server.url("/api/todos"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let all: Vec<Value> = resp.json().await.unwrap();
+    assert_eq!(all.len(), 1);
+}
+
+#[test]
+fn test_next_due_dates() {
+    use donezo::models::{Recurrence, Weekday};
+    use time::macros::date;
+
+    let today = date!(2024 - 01 - 10); // a Wednesday
+
+    let weekly = Recurrence::Weekly {
+        weekdays: vec![Weekday::Mon, Weekday::Fri],
+    };
+    assert_eq!(
+        dates::next_due(&weekly, Some("2024-01-10"), today).as_deref(),
+        Some("2024-01-12")
+    );
+    assert_eq!(
+        dates::next_due(&weekly, Some("2023-12-01"), today).as_deref(),
+        Some("2024-01-12")
+    );
+    assert_eq!(
+        dates::next_due(&weekly, None, today).as_deref(),
+        Some("2024-01-12")
+    );
+
+    let monthly = Recurrence::Monthly { day: 31 };
+    assert_eq!(
+        dates::next_due(&monthly, Some("2024-01-31"), today).as_deref(),
+        Some("2024-02-29")
+    );
+    assert_eq!(
+        dates::next_due(&monthly, Some("2024-02-29"), today).as_deref(),
+        Some("2024-03-31")
+    );
+
+    let after = Recurrence::AfterCompletion { days: 3 };
+    assert_eq!(
+        dates::next_due(&after, Some("2023-06-01T18:30:00Z"), today).as_deref(),
+        Some("2024-01-13T18:30:00Z")
+    );
+
+    // Series end instead of overflowing past the last representable date
+    let last = "9999-12-31";
+    assert_eq!(dates::next_due(&Recurrence::Daily, Some(last), today), None);
+    assert_eq!(dates::next_due(&weekly, Some(last), today), None);
+    assert_eq!(dates::next_due(&monthly, Some(last), today), None);
+    assert_eq!(
+        dates::next_due(
+            &Recurrence::AfterCompletion { days: 1 },
+            None,
+            date!(9999 - 12 - 31)
+        ),
+        None
+    );
+}
+
+#[tokio::test]
+async fn test_priority() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    let todos = [
+        ("Low", json!("low"), json!("2030-01-03")),
+        ("Unprioritized", json!(null), json!(null)),
+        ("High", json!("high"), json!("2030-01-02")),
+        ("Medium", json!("medium"), json!("2030-01-01")),
+    ];
+    let mut ids = Vec::new();
+    for (title, priority, due_at) in &todos {
+        let mut body = json!({"title": title, "due_at": due_at});
+        if !priority.is_null() {
+            body["priority"] = priority.clone();
+        }
+        let resp = server
+            .client
+            .post(server.url("/api/todos"))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let todo: Value = resp.json().await.unwrap();
+        ids.push(todo["id"].as_i64().unwrap());
+    }
+
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/todos/{}", ids[1])))
+        .json(&json!({"priority": "low"}))
+        .send()
+        .await
+        .unwrap();
+    let todo: Value = resp.json().await.unwrap();
+    assert_eq!(todo["priority"], "low");
+
+    let sorted = |sort: &'static str| {
+        let client = &server.client;
+        let url = server.url(&format!("/api/todos?sort={}", sort));
+        async move {
+            let resp = client.get(url).send().await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let todos: Vec<Value> = resp.json().await.unwrap();
+            todos
+                .iter()
+                .map(|t| t["title"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        }
+    };
+
+    assert_eq!(
+        sorted("position").await,
+        ["Low", "Unprioritized", "High", "Medium"]
+    );
+    assert_eq!(
+        sorted("priority").await,
+        ["High", "Medium", "Low", "Unprioritized"]
+    );
+    assert_eq!(
+        sorted("due").await,
+        ["Medium", "High", "Low", "Unprioritized"]
+    );
+    assert_eq!(
+        sorted("created").await,
+        ["Low", "Unprioritized", "High", "Medium"]
+    );
+
+    let resp = server
+        .client
+        .get(server.url("/api/todos?sort=random"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // Priority markers are opt-in in the plain-text export
+    let resp = server
+        .client
+        .get(server.url("/api/todos/plain?sort=priority&markers=true"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.text().await.unwrap(),
+        "!!! High\n!! Medium\n! Low\n! Unprioritized\n"
+    );
+
+    let resp = server
+        .client
+        .get(server.url("/api/todos/plain?sort=priority"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.text().await.unwrap(),
+        "High\nMedium\nLow\nUnprioritized\n"
+    );
+}
+
+#[tokio::test]
+async fn test_notes() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({
+            "title": "Renew certificate",
+            "notes": "Run `certbot renew` on the **proxy**.\n\nSee [docs](https://example.com).\n\n<script>alert(1)</script>"
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let todo: Value = resp.json().await.unwrap();
+    let id = todo["id"].as_i64().unwrap();
+
+    // The single todo carries the notes rendered to sanitized HTML
+    let resp = server
+        .client
+        .get(server.url(&format!("/api/todos/{}", id)))
+        .send()
+        .await
+        .unwrap();
+    let todo: Value = resp.json().await.unwrap();
+    assert!(todo["notes"].as_str().unwrap().starts_with("Run `certbot"));
+    let html = todo["notes_html"].as_str().unwrap();
+    assert!(html.contains("<code>certbot renew</code>"));
+    assert!(html.contains("<strong>proxy</strong>"));
+    assert!(html.contains("href=\"https://example.com\""));
+    assert!(!html.contains("<script>"));
+
+    // Notes are searchable
+    let resp = server
+        .client
+        .get(server.url("/api/todos/search?q=certbot"))
+        .send()
+        .await
+        .unwrap();
+    let results: Vec<Value> = resp.json().await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert!(results[0]["snippet"]
+        .as_str()
+        .unwrap()
+        .contains("<mark>certbot</mark>"));
+
+    let resp = server
+        .client
+        .put(server.url(&format!("/api/todos/{}", id)))
+        .json(&json!({"notes": "  "}))
+        .send()
+        .await
+        .unwrap();
+    let todo: Value = resp.json().await.unwrap();
+    assert!(todo["notes"].is_null());
+
+    let resp = server
+        .client
+        .get(server.url("/api/todos/search?q=certbot"))
+        .send()
+        .await
+        .unwrap();
+    let results: Vec<Value> = resp.json().await.unwrap();
+    assert!(results.is_empty());
+}
+
+#[tokio::test]
+async fn test_multi_user() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"username": "admin", "password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    let resp = server
+        .client
+        .post(server.url("/api/users"))
+        .json(&json!({"username": "bob", "password": "hunter2"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let bob: Value = resp.json().await.unwrap();
+    assert_eq!(bob["is_admin"], false);
+
+    let resp = server
+        .client
+        .post(server.url("/api/users"))
+        .json(&json!({"username": "bob", "password": "other"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Admin todo"}))
+        .send()
+        .await
+        .unwrap();
+    let admin_todo: Value = resp.json().await.unwrap();
+
+    // Bob logs in with his own password and only sees his own data
+    let bob_client = server.new_client();
+    let resp = bob_client
+        .post(server.url("/api/login"))
+        .json(&json!({"username": "bob", "password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = bob_client
+        .post(server.url("/api/login"))
+        .json(&json!({"username": "bob", "password": "hunter2"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = bob_client.get(server.url("/api/me")).send().await.unwrap();
+    let me: Value = resp.json().await.unwrap();
+    assert_eq!(me["username"], "bob");
+
+    let resp = bob_client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Bob todo"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    let resp = bob_client
+        .get(server.url("/api/todos"))
+        .send()
+        .await
+        .unwrap();
+    let todos: Vec<Value> = resp.json().await.unwrap();
+    assert_eq!(todos.len(), 1);
+    assert_eq!(todos[0]["title"], "Bob todo");
+
+    let resp = bob_client
+        .get(server.url("/api/lists"))
+        .send()
+        .await
+        .unwrap();
+    let lists: Vec<Value> = resp.json().await.unwrap();
+    assert_eq!(lists.len(), 1);
+    assert_ne!(lists[0]["id"], admin_todo["list_id"]);
+
+    let admin_todo_url = server.url(&format!("/api/todos/{}", admin_todo["id"]));
+    let resp = bob_client.get(&admin_todo_url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = bob_client
+        .put(&admin_todo_url)
+        .json(&json!({"completed": true}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let resp = bob_client.delete(&admin_todo_url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let resp = bob_client
+        .get(server.url("/api/todos/search?q=todo"))
+        .send()
+        .await
+        .unwrap();
+    let results: Vec<Value> = resp.json().await.unwrap();
+    assert_eq!(results.len(), 1);
+
+    // Only admins manage users
+    let resp = bob_client
+        .get(server.url("/api/users"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Deleting a user logs them out and removes their data
+    let resp = server
+        .client
+        .delete(server.url(&format!("/api/users/{}", bob["id"])))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let resp = bob_client
+        .get(server.url("/api/todos"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_shared_lists() {
+    let server = TestServer::new().await;
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"username": "admin", "password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+    server
+        .client
+        .post(server.url("/api/users"))
+        .json(&json!({"username": "bob", "password": "hunter2"}))
+        .send()
+        .await
+        .unwrap();
+
+    let resp = server
+        .client
+        .post(server.url("/api/lists"))
+        .json(&json!({"name": "Groceries"}))
+        .send()
+        .await
+        .unwrap();
+    let list: Value = resp.json().await.unwrap();
+    let list_id = list["id"].as_i64().unwrap();
+    assert_eq!(list["role"], "owner");
+
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Milk", "list_id": list_id}))
+        .send()
+        .await
+        .unwrap();
+    let todo: Value = resp.json().await.unwrap();
+    let todo_url = server.url(&format!("/api/todos/{}", todo["id"]));
+
+    let bob_client = server.new_client();
+    bob_client
+        .post(server.url("/api/login"))
+        .json(&json!({"username": "bob", "password": "hunter2"}))
+        .send()
+        .await
+        .unwrap();
+
+    // Not shared yet
+    let resp = bob_client.get(todo_url.clone()).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let members_url = server.url(&format!("/api/lists/{list_id}/members"));
+    let resp = server
+        .client
+        .post(members_url.clone())
+        .json(&json!({"username": "bob", "role": "viewer"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let member: Value = resp.json().await.unwrap();
+    let member_url = format!("{members_url}/{}", member["user_id"]);
+
+    // Viewers read but cannot change anything
+    let resp = bob_client
+        .get(server.url("/api/lists"))
+        .send()
+        .await
+        .unwrap();
+    let lists: Vec<Value> = resp.json().await.unwrap();
+    let shared = lists.iter().find(|l| l["id"] == list_id).unwrap();
+    assert_eq!(shared["role"], "viewer");
+
+    let resp = bob_client
+        .get(server.url(&format!("/api/lists/{list_id}/todos")))
+        .send()
+        .await
+        .unwrap();
+    let todos: Vec<Value> = resp.json().await.unwrap();
+    assert_eq!(todos.len(), 1);
+
+    let resp = bob_client
+        .put(todo_url.clone())
+        .json(&json!({"completed": true}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = bob_client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Eggs", "list_id": list_id}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = bob_client
+        .post(members_url.clone())
+        .json(&json!({"username": "admin", "role": "editor"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Editors change todos but not the list itself
+    let resp = server
+        .client
+        .put(member_url.clone())
+        .json(&json!({"role": "editor"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = bob_client
+        .put(todo_url.clone())
+        .json(&json!({"completed": true}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = bob_client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Eggs", "list_id": list_id}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let resp = bob_client
+        .delete(server.url(&format!("/api/lists/{list_id}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    let resp = bob_client.get(members_url.clone()).send().await.unwrap();
+    let members: Vec<Value> = resp.json().await.unwrap();
+    assert_eq!(members.len(), 2);
+    assert_eq!(members[0]["username"], "admin");
+    assert_eq!(members[0]["role"], "owner");
+
+    // Bob leaves the list again
+    let resp = bob_client.delete(member_url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let resp = bob_client.get(todo_url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn test_password_sources() {
+    let source = |password: Option<&str>, hash: Option<&str>, file| auth::PasswordSource {
+        password: password.map(str::to_string),
+        hash: hash.map(str::to_string),
+        file,
+    };
+
+    let hash = source(Some("secret"), None, None).resolve().unwrap();
+    assert!(auth::verify_password("secret", &hash));
+
+    let existing = auth::hash_password("hashed");
+    let hash = source(None, Some(&format!("{existing}\n")), None)
+        .resolve()
+        .unwrap();
+    assert_eq!(hash, existing);
+    assert!(source(None, Some("not-a-hash"), None).resolve().is_err());
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("password");
+    std::fs::write(&path, "from file\n").unwrap();
+    let hash = source(None, None, Some(path.clone())).resolve().unwrap();
+    assert!(auth::verify_password("from file", &hash));
+
+    std::fs::write(&path, "\n").unwrap();
+    assert!(source(None, None, Some(path.clone())).resolve().is_err());
+    assert!(source(None, None, Some(dir.path().join("missing")))
+        .resolve()
+        .is_err());
+
+    assert!(source(None, None, None).resolve().is_err());
+    assert!(source(Some("secret"), Some(&existing), None)
+        .resolve()
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_proxy_header_auth() {
+    let configure = |proxy: &str| {
+        let proxy = TrustedProxy::parse(proxy).unwrap();
+        move |state: &mut AppState| {
+            state.trusted_proxies = Arc::new(vec![proxy]);
+            state.auth_header = Some(HeaderName::from_static("remote-user"));
+        }
+    };
+    let server = TestServer::with_config(configure("127.0.0.1")).await;
+
+    let resp = server
+        .client
+        .get(server.url("/api/me"))
+        .header("Remote-User", "admin")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let me: Value = resp.json().await.unwrap();
+    assert_eq!(me["username"], "admin");
+
+    // The login page is skipped entirely
+    let resp = server
+        .client
+        .get(server.url("/login"))
+        .header("Remote-User", "admin")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    let resp = server
+        .client
+        .get(server.url("/"))
+        .header("Remote-User", "admin")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Unknown users and requests without the header are not logged in
+    let resp = server
+        .client
+        .get(server.url("/api/me"))
+        .header("Remote-User", "mallory")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let resp = server
+        .client
+        .get(server.url("/api/me"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // The header is ignored from addresses that aren't trusted proxies
+    let server = TestServer::with_config(configure("10.0.0.0/8")).await;
+    let resp = server
+        .client
+        .get(server.url("/api/me"))
+        .header("Remote-User", "admin")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+}
+
+/// A minimal OpenID Connect provider that logs everyone in as `email`.
+#[derive(Default)]
+struct MockIssuer {
+    url: String,
+    email: String,
+    email_verified: Value,
+    code_challenge: String,
+    nonce: String,
+}
+
+async fn start_mock_issuer(email: &str) -> Arc<Mutex<MockIssuer>> {
+    use axum::extract::{Query, State};
+    use axum::response::Redirect;
+    use axum::routing::{get, post};
+    use axum::{Form, Json, Router};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+
+    type Issuer = Arc<Mutex<MockIssuer>>;
+
+    async fn discovery(State(issuer): State<Issuer>) -> Json<Value> {
+        let url = issuer.lock().unwrap().url.clone();
+        Json(json!({
+            "issuer": url,
+            "authorization_endpoint": format!("{url}/authorize"),
+            "token_endpoint": format!("{url}/token"),
+        }))
+    }
+
+    async fn authorize(
+        State(issuer): State<Issuer>,
+        Query(params): Query<HashMap<String, String>>,
+    ) -> Redirect {
+        let mut issuer = issuer.lock().unwrap();
+        issuer.code_challenge = params["code_challenge"].clone();
+        issuer.nonce = params["nonce"].clone();
+        Redirect::to(&format!(
+            "{}?code=mock-code&state={}",
+            params["redirect_uri"], params["state"]
+        ))
+    }
+
+    async fn token(
+        State(issuer): State<Issuer>,
+        Form(params): Form<HashMap<String, String>>,
+    ) -> Result<Json<Value>, StatusCode> {
+        let issuer = issuer.lock().unwrap();
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(params["code_verifier"].as_bytes()));
+        if params["code"] != "mock-code" || challenge != issuer.code_challenge {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        let encode = |value: Value| URL_SAFE_NO_PAD.encode(value.to_string());
+        let claims = json!({
+            "iss": issuer.url,
+            "sub": "1234",
+            "aud": "donezo",
+            "exp": 4_000_000_000i64,
+            "nonce": issuer.nonce,
+            "email": issuer.email,
+            "email_verified": issuer.email_verified,
+        });
+        let id_token = format!(
+            "{}.{}.signature",
+            encode(json!({ "alg": "RS256" })),
+            encode(claims)
+        );
+        Ok(Json(
+            json!({ "access_token": "mock", "token_type": "Bearer", "id_token": id_token }),
+        ))
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let issuer = Arc::new(Mutex::new(MockIssuer {
+        url: format!("http://{}", listener.local_addr().unwrap()),
+        email: email.to_string(),
+        email_verified: Value::Bool(true),
+        ..MockIssuer::default()
+    }));
+    let app = Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/authorize", get(authorize))
+        .route("/token", post(token))
+        .with_state(issuer.clone());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    issuer
+}
+
+#[tokio::test]
+async fn test_oidc_login() {
+    let issuer = start_mock_issuer("alice@example.com").await;
+    let config = OidcConfig {
+        issuer: issuer.lock().unwrap().url.clone(),
+        client_id: "donezo".to_string(),
+        client_secret: None,
+        redirect_url: "http://donezo.test/api/oidc/callback".to_string(),
+        username_claim: "email".to_string(),
+    };
+    let insecure = OidcConfig {
+        issuer: "http://auth.example.com".to_string(),
+        ..config.clone()
+    };
+    assert!(OidcClient::discover(insecure).await.is_err());
+    let oidc = Arc::new(OidcClient::discover(config).await.unwrap());
+    let server = TestServer::with_config(|state| state.oidc = Some(oidc.clone())).await;
+    db::create_user(
+        &server.db,
+        "alice@example.com",
+        &auth::hash_password("x"),
+        false,
+    )
+    .unwrap();
+
+    let resp = server
+        .client
+        .get(server.url("/login"))
+        .send()
+        .await
+        .unwrap();
+    assert!(resp
+        .text()
+        .await
+        .unwrap()
+        .contains("window.OIDC_ENABLED = true"));
+
+    // Runs the browser's part of the flow and returns the redirect back to
+    // donezo, which isn't known to the provider by its test address
+    let login = |client: TestClient| {
+        let server = &server;
+        async move {
+            let resp = client
+                .get(server.url("/api/oidc/login"))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+            let authorize_url = resp.headers()["location"].to_str().unwrap().to_string();
+            let resp = client.get(&authorize_url).send().await.unwrap();
+            let callback = resp.headers()["location"].to_str().unwrap().to_string();
+            let query = callback.split_once('?').unwrap().1.to_string();
+            (client, query)
+        }
+    };
+
+    let (client, query) = login(server.client.clone()).await;
+    let resp = client
+        .get(server.url(&format!("/api/oidc/callback?{query}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = client.get(server.url("/api/me")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let me: Value = resp.json().await.unwrap();
+    assert_eq!(me["username"], "alice@example.com");
+
+    // A login can only be finished once, by the browser that started it
+    let resp = client
+        .get(server.url(&format!("/api/oidc/callback?{query}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    let (_, query) = login(server.new_client()).await;
+    let other_client = server.new_client();
+    let resp = other_client
+        .get(server.url(&format!("/api/oidc/callback?{query}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    assert!(resp.headers()["location"]
+        .to_str()
+        .unwrap()
+        .ends_with("/login?error=oidc"));
+    let resp = other_client
+        .get(server.url("/api/me"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // Users unknown to donezo aren't logged in
+    issuer.lock().unwrap().email = "mallory@example.com".to_string();
+    let (client, query) = login(server.new_client()).await;
+    let resp = client
+        .get(server.url(&format!("/api/oidc/callback?{query}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    let resp = client.get(server.url("/api/me")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // Neither are users whose email address the provider doesn't vouch for
+    issuer.lock().unwrap().email = "alice@example.com".to_string();
+    issuer.lock().unwrap().email_verified = Value::Null;
+    let (client, query) = login(server.new_client()).await;
+    let resp = client
+        .get(server.url(&format!("/api/oidc/callback?{query}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    let resp = client.get(server.url("/api/me")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // Logins started in a flood push out the oldest ones
+    issuer.lock().unwrap().email_verified = Value::Bool(true);
+    let (old_client, old_query) = login(server.new_client()).await;
+    for _ in 0..MAX_PENDING_LOGINS - 1 {
+        oidc.start_login();
+    }
+    let (client, query) = login(server.new_client()).await;
+    let resp = old_client
+        .get(server.url(&format!("/api/oidc/callback?{old_query}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+    let resp = client
+        .get(server.url(&format!("/api/oidc/callback?{query}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_csrf_protection() {
+    let server = TestServer::new().await;
+    let client = Client::builder().cookie_store(true).build().unwrap();
+    let resp = client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Changes without the token of the session are refused
+    let create_todo = |token: Option<&str>| {
+        let mut request = client
+            .post(server.url("/api/todos"))
+            .json(&json!({"title": "Buy milk"}));
+        if let Some(token) = token {
+            request = request.header("X-CSRF-Token", token);
+        }
+        request.send()
+    };
+    let resp = create_todo(None).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let body: Value = resp.json().await.unwrap();
+    assert_eq!(body["csrf_invalid"], true);
+    let resp = create_todo(Some("0123456789abcdef")).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = client.post(server.url("/api/logout")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // The token comes with the page
+    let page = client
+        .get(server.url("/"))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let token = page
+        .split_once(r#"window.CSRF_TOKEN = ""#)
+        .and_then(|(_, rest)| rest.split_once('"'))
+        .map(|(token, _)| token.to_string())
+        .unwrap();
+    assert_eq!(token.len(), 64);
+    let resp = create_todo(Some(&token)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    // Reading needs no token, and neither do API tokens
+    let resp = client.get(server.url("/api/todos")).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = client
+        .post(server.url("/api/tokens"))
+        .header("X-CSRF-Token", &token)
+        .json(&json!({"name": "Script"}))
+        .send()
+        .await
+        .unwrap();
+    let api_token: Value = resp.json().await.unwrap();
+    let resp = Client::new()
+        .post(server.url("/api/todos"))
+        .bearer_auth(api_token["token"].as_str().unwrap())
+        .json(&json!({"title": "From a script"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+
+    // Any other authorization header leaves the browser login in charge
+    let resp = client
+        .post(server.url("/api/todos"))
+        .basic_auth("admin", Some("testpassword"))
+        .json(&json!({"title": "Forged"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    let resp = client
+        .post(server.url("/api/logout"))
+        .header("Authorization", "garbage")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Other sessions have other tokens
+    let other = server.new_client();
+    other
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+    let resp = other
+        .client
+        .post(server.url("/api/todos"))
+        .header("X-CSRF-Token", &token)
+        .json(&json!({"title": "Forged"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+}
+
+/// Reads Server-Sent Events from a streaming response.
+struct EventStream {
+    response: reqwest::Response,
+    buffer: String,
+}
+
+impl EventStream {
+    async fn open(client: &TestClient, url: String) -> Self {
+        let response = client
+            .get(url)
+            .timeout(std::time::Duration::from_secs(10))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/event-stream"));
+        EventStream {
+            response,
+            buffer: String::new(),
+        }
+    }
+
+    /// The name and data of the next event, skipping keep-alive comments.
+    async fn next(&mut self) -> (String, Value) {
+        loop {
+            if let Some((event, rest)) = self.buffer.split_once("\n\n") {
+                let event = event.to_string();
+                self.buffer = rest.to_string();
+                let field = |name: &str| {
+                    event
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .map(str::trim)
+                };
+                if let (Some(name), Some(data)) = (field("event:"), field("data:")) {
+                    return (name.to_string(), serde_json::from_str(data).unwrap());
+                }
+                continue;
+            }
+            let chunk = self.response.chunk().await.unwrap().expect("stream ended");
+            self.buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+    }
+
+    /// Whether the server ended the stream without sending another event.
+    async fn ended(&mut self) -> bool {
+        while let Some(chunk) = self.response.chunk().await.unwrap() {
+            if std::str::from_utf8(&chunk).unwrap().contains("data:") {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[tokio::test]
+async fn test_event_stream() {
+    let server = TestServer::new().await;
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+    db::create_user(
+        &server.db,
+        "bob",
+        &auth::hash_password("bobpassword"),
+        false,
+    )
+    .unwrap();
+    let bob = server.new_client();
+    bob.post(server.url("/api/login"))
+        .json(&json!({"username": "bob", "password": "bobpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    let mut events = EventStream::open(&server.client, server.url("/api/events")).await;
+    let mut bob_events = EventStream::open(&bob, server.url("/api/events")).await;
+
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Water plants"}))
+        .send()
+        .await
+        .unwrap();
+    let todo: Value = resp.json().await.unwrap();
+    let id = todo["id"].as_i64().unwrap();
+    let list_id = todo["list_id"].as_i64().unwrap();
+    let (name, data) = events.next().await;
+    assert_eq!(name, "created");
+    assert_eq!(data["todo"]["title"], "Water plants");
+
+    server
+        .client
+        .put(server.url(&format!("/api/todos/{id}")))
+        .json(&json!({"completed": true}))
+        .send()
+        .await
+        .unwrap();
+    let (name, data) = events.next().await;
+    assert_eq!(name, "updated");
+    assert_eq!(data["todo"]["completed"], true);
+
+    server
+        .client
+        .put(server.url(&format!("/api/lists/{list_id}/todos/reorder")))
+        .json(&json!({"ids": [id]}))
+        .send()
+        .await
+        .unwrap();
+    let (name, data) = events.next().await;
+    assert_eq!(name, "reordered");
+    assert_eq!(data, json!({"list_id": list_id, "ids": [id]}));
+
+    server
+        .client
+        .delete(server.url(&format!("/api/todos/{id}")))
+        .send()
+        .await
+        .unwrap();
+    let (name, data) = events.next().await;
+    assert_eq!(name, "deleted");
+    assert_eq!(data, json!({"id": id, "list_id": list_id}));
+
+    // Bob saw none of that, only the changes to his own todos
+    bob.post(server.url("/api/todos"))
+        .json(&json!({"title": "Bob's todo"}))
+        .send()
+        .await
+        .unwrap();
+    let (name, data) = bob_events.next().await;
+    assert_eq!(name, "created");
+    assert_eq!(data["todo"]["title"], "Bob's todo");
+
+    // Sharing a list tells the new member about it
+    server
+        .client
+        .post(server.url(&format!("/api/lists/{list_id}/members")))
+        .json(&json!({"username": "bob", "role": "editor"}))
+        .send()
+        .await
+        .unwrap();
+    let (name, data) = bob_events.next().await;
+    assert_eq!(name, "list_changed");
+    assert_eq!(data["list_id"], list_id);
+
+    // Logging out everywhere ends the stream instead of sending further changes
+    bob.delete(server.url("/api/sessions"))
+        .send()
+        .await
+        .unwrap();
+    server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Shared secret", "list_id": list_id}))
+        .send()
+        .await
+        .unwrap();
+    assert!(bob_events.ended().await);
+}
+
+/// A connection to `/api/ws`.
+struct WsClient(WebSocketStream<MaybeTlsStream<TcpStream>>);
+
+impl WsClient {
+    /// Connects with the given query string and headers, or returns the status
+    /// the upgrade was refused with.
+    async fn connect(
+        server: &TestServer,
+        query: &str,
+        headers: &[(&str, String)],
+    ) -> Result<Self, StatusCode> {
+        let url = server
+            .url(&format!("/api/ws{query}"))
+            .replacen("http", "ws", 1);
+        let mut request = url.into_client_request().unwrap();
+        for (name, value) in headers {
+            request.headers_mut().insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        match tokio_tungstenite::connect_async(request).await {
+            Ok((stream, _)) => Ok(WsClient(stream)),
+            Err(tungstenite::Error::Http(response)) => Err(response.status()),
+            Err(err) => panic!("WebSocket connection failed: {err}"),
+        }
+    }
+
+    /// Sends a command and returns its reply.
+    async fn call(&mut self, id: i64, method: &str, params: Value) -> Value {
+        let command = json!({"id": id, "method": method, "params": params});
+        self.0
+            .send(Message::text(command.to_string()))
+            .await
+            .unwrap();
+        let reply = self.next().await;
+        assert_eq!(reply["id"], id);
+        reply
+    }
+
+    async fn next(&mut self) -> Value {
+        loop {
+            let message = self.0.next().await.expect("socket closed").unwrap();
+            if let Message::Text(text) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_websocket() {
+    let server = TestServer::new().await;
+    assert_eq!(
+        WsClient::connect(&server, "", &[]).await.err(),
+        Some(StatusCode::UNAUTHORIZED)
+    );
+
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+    let session = server.client.session(&server.url("/")).unwrap();
+    let cookie = [("Cookie", format!("session={session}"))];
+
+    // Other sites can't connect with the browser's cookie
+    assert_eq!(
+        WsClient::connect(&server, "", &cookie).await.err(),
+        Some(StatusCode::FORBIDDEN)
+    );
+    let query = format!("?csrf_token={}", server.csrf_key.token(&session));
+    let mut ws = WsClient::connect(&server, &query, &cookie).await.unwrap();
+
+    let reply = ws
+        .call(1, "create_todo", json!({"title": "Water plants"}))
+        .await;
+    assert_eq!(reply["result"]["title"], "Water plants");
+    let id = reply["result"]["id"].as_i64().unwrap();
+    let list_id = reply["result"]["list_id"].as_i64().unwrap();
+    let event = ws.next().await;
+    assert_eq!(event["event"], "created");
+    assert_eq!(event["data"]["todo"]["id"], id);
+
+    let reply = ws
+        .call(2, "update_todo", json!({"id": id, "completed": true}))
+        .await;
+    assert_eq!(reply["result"]["completed"], true);
+    assert_eq!(ws.next().await["event"], "updated");
+
+    let reply = ws
+        .call(3, "reorder_todos", json!({"list_id": list_id, "ids": [id]}))
+        .await;
+    assert_eq!(reply["result"][0]["id"], id);
+    assert_eq!(ws.next().await["event"], "reordered");
+
+    // Changes made through the REST API arrive as well
+    server
+        .client
+        .delete(server.url(&format!("/api/todos/{id}")))
+        .send()
+        .await
+        .unwrap();
+    let event = ws.next().await;
+    assert_eq!(event["event"], "deleted");
+    assert_eq!(event["data"], json!({"id": id, "list_id": list_id}));
+
+    let reply = ws.call(4, "get_todo", json!({"id": id})).await;
+    assert_eq!(
+        reply["error"],
+        json!({"status": 404, "message": "Not found"})
+    );
+    let reply = ws.call(5, "create_todo", json!({"title": " "})).await;
+    assert_eq!(reply["error"]["status"], 400);
+    let reply = ws.call(6, "drop_tables", json!({})).await;
+    assert_eq!(reply["error"]["message"], "Unknown method");
+
+    // Read-only tokens can't send changes
+    let resp = server
+        .client
+        .post(server.url("/api/tokens"))
+        .json(&json!({"name": "Wall display", "scopes": ["todos:read"]}))
+        .send()
+        .await
+        .unwrap();
+    let token: Value = resp.json().await.unwrap();
+    let authorization = [(
+        "Authorization",
+        format!("Bearer {}", token["token"].as_str().unwrap()),
+    )];
+    let mut ws = WsClient::connect(&server, "", &authorization)
+        .await
+        .unwrap();
+    let reply = ws.call(1, "list_lists", Value::Null).await;
+    assert_eq!(reply["result"][0]["id"], list_id);
+    let reply = ws
+        .call(2, "create_todo", json!({"title": "From the wall"}))
+        .await;
+    assert_eq!(reply["error"]["status"], 403);
+
+    // Revoking the token closes the socket before the next command runs
+    server
+        .client
+        .delete(server.url(&format!("/api/tokens/{}", token["id"])))
+        .send()
+        .await
+        .unwrap();
+    ws.0.send(Message::text(
+        json!({"id": 3, "method": "list_lists"}).to_string(),
+    ))
+    .await
+    .unwrap();
+    assert!(matches!(
+        ws.0.next().await,
+        Some(Ok(Message::Close(_))) | None
+    ));
+}
+
+#[tokio::test]
+async fn test_sync() {
+    let server = TestServer::new().await;
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+    let sync = |since: i64| {
+        let client = server.client.clone();
+        let url = server.url(&format!("/api/sync?since={since}"));
+        async move {
+            let resp = client.get(url).send().await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            resp.json::<Value>().await.unwrap()
+        }
+    };
+    let create = |body: Value| {
+        let client = server.client.clone();
+        let url = server.url("/api/todos");
+        async move {
+            let resp = client.post(url).json(&body).send().await.unwrap();
+            resp.json::<Value>().await.unwrap()["id"].as_i64().unwrap()
+        }
+    };
+    let ids = |changes: &Value, field: &str| -> Vec<i64> {
+        changes[field]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["id"].as_i64().unwrap())
+            .collect()
+    };
+
+    let kept = create(json!({"title": "Keep"})).await;
+    let changes = sync(0).await;
+    assert_eq!(changes["reset"], true);
+    assert_eq!(ids(&changes, "todos"), [kept]);
+    let seq = changes["seq"].as_i64().unwrap();
+
+    let parent = create(json!({"title": "Parent"})).await;
+    let child = create(json!({"title": "Child", "parent_id": parent})).await;
+    let changes = sync(seq).await;
+    assert_eq!(changes["reset"], false);
+    assert_eq!(ids(&changes, "todos"), [parent, child]);
+    assert_eq!(changes["deleted"], json!([]));
+    let seq = changes["seq"].as_i64().unwrap();
+
+    // Nothing changed in between
+    assert_eq!(ids(&sync(seq).await, "todos"), Vec::<i64>::new());
+
+    // Deleting a todo leaves tombstones for its subtasks as well
+    server
+        .client
+        .put(server.url(&format!("/api/todos/{kept}")))
+        .json(&json!({"completed": true}))
+        .send()
+        .await
+        .unwrap();
+    server
+        .client
+        .delete(server.url(&format!("/api/todos/{parent}")))
+        .send()
+        .await
+        .unwrap();
+    let changes = sync(seq).await;
+    assert_eq!(ids(&changes, "todos"), [kept]);
+    assert_eq!(changes["todos"][0]["completed"], true);
+    let mut deleted = ids(&changes, "deleted");
+    deleted.sort();
+    assert_eq!(deleted, [parent, child]);
+    let seq = changes["seq"].as_i64().unwrap();
+
+    // Moving a todo removes it from its old list
+    let old_list = changes["lists"][0]["id"].as_i64().unwrap();
+    let resp = server
+        .client
+        .post(server.url("/api/lists"))
+        .json(&json!({"name": "Shared"}))
+        .send()
+        .await
+        .unwrap();
+    let list: Value = resp.json().await.unwrap();
+    let list_id = list["id"].as_i64().unwrap();
+    server
+        .client
+        .put(server.url(&format!("/api/todos/{kept}")))
+        .json(&json!({"list_id": list_id}))
+        .send()
+        .await
+        .unwrap();
+    let changes = sync(seq).await;
+    assert_eq!(changes["lists"].as_array().unwrap().len(), 2);
+    assert_eq!(
+        changes["deleted"],
+        json!([{"id": kept, "list_id": old_list}])
+    );
+    assert_eq!(ids(&changes, "todos"), [kept]);
+    assert_eq!(changes["todos"][0]["list_id"], list_id);
+
+    // A newly shared list arrives with all its todos
+    db::create_user(
+        &server.db,
+        "bob",
+        &auth::hash_password("bobpassword"),
+        false,
+    )
+    .unwrap();
+    let bob = server.new_client();
+    bob.post(server.url("/api/login"))
+        .json(&json!({"username": "bob", "password": "bobpassword"}))
+        .send()
+        .await
+        .unwrap();
+    let resp = bob.get(server.url("/api/sync")).send().await.unwrap();
+    let changes: Value = resp.json().await.unwrap();
+    assert_eq!(changes["todos"], json!([]));
+    let bob_seq = changes["seq"].as_i64().unwrap();
+    server
+        .client
+        .post(server.url(&format!("/api/lists/{list_id}/members")))
+        .json(&json!({"username": "bob", "role": "viewer"}))
+        .send()
+        .await
+        .unwrap();
+    let resp = bob
+        .get(server.url(&format!("/api/sync?since={bob_seq}")))
+        .send()
+        .await
+        .unwrap();
+    let changes: Value = resp.json().await.unwrap();
+    assert_eq!(changes["reset"], false);
+    assert_eq!(changes["lists"][0]["id"], list_id);
+    assert_eq!(ids(&changes, "todos"), [kept]);
+
+    // Unknown cursors, e.g. after restoring a backup, start over
+    let changes = sync(seq + 1000).await;
+    assert_eq!(changes["reset"], true);
+    assert_eq!(ids(&changes, "todos"), [kept]);
+}
+
+#[tokio::test]
+async fn test_etags() {
+    let server = TestServer::new().await;
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Draft"}))
+        .send()
+        .await
+        .unwrap();
+    let todo: Value = resp.json().await.unwrap();
+    let id = todo["id"].as_i64().unwrap();
+    let todo_url = server.url(&format!("/api/todos/{id}"));
+    let etag = |resp: &reqwest::Response| resp.headers()["etag"].to_str().unwrap().to_string();
+
+    let resp = server.client.get(&todo_url).send().await.unwrap();
+    let version = etag(&resp);
+    assert_eq!(version, format!("\"{}\"", todo["version"]));
+    let resp = server
+        .client
+        .get(&todo_url)
+        .header("If-None-Match", &version)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+
+    // The first tab saves, the second one's edit is refused
+    let resp = server
+        .client
+        .put(&todo_url)
+        .header("If-Match", &version)
+        .json(&json!({"title": "First tab"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let new_version = etag(&resp);
+    assert_ne!(new_version, version);
+    let resp = server
+        .client
+        .put(&todo_url)
+        .header("If-Match", &version)
+        .json(&json!({"title": "Second tab"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+    let resp = server.client.get(&todo_url).send().await.unwrap();
+    let todo: Value = resp.json().await.unwrap();
+    assert_eq!(todo["title"], "First tab");
+
+    // Clients that don't send If-Match still overwrite
+    let resp = server
+        .client
+        .put(&todo_url)
+        .json(&json!({"completed": true}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // Lists answer polling clients with 304 until something changes
+    for path in ["/api/todos", "/api/lists", "/api/lists/1/todos"] {
+        let resp = server.client.get(server.url(path)).send().await.unwrap();
+        let list_etag = etag(&resp);
+        let resp = server
+            .client
+            .get(server.url(path))
+            .header("If-None-Match", format!("W/{list_etag}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED, "{path}");
+        assert!(resp.bytes().await.unwrap().is_empty());
+    }
+    let resp = server
+        .client
+        .get(server.url("/api/todos"))
+        .send()
+        .await
+        .unwrap();
+    let list_etag = etag(&resp);
+    server
+        .client
+        .put(&todo_url)
+        .json(&json!({"completed": false}))
+        .send()
+        .await
+        .unwrap();
+    let resp = server
+        .client
+        .get(server.url("/api/todos"))
+        .header("If-None-Match", &list_etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = server
+        .client
+        .delete(&todo_url)
+        .header("If-Match", &new_version)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+    let resp = server
+        .client
+        .delete(&todo_url)
+        .header("If-Match", "*")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+}
+
+/// A webhook receiver that fails the first request, passing on the headers
+/// and body of every request it gets.
+async fn start_webhook_receiver() -> (
+    String,
+    tokio::sync::mpsc::UnboundedReceiver<(HeaderMap, String)>,
+) {
+    use axum::routing::post;
+    use axum::Router;
+
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    let failed = Arc::new(Mutex::new(false));
+    let hook = move |headers: HeaderMap, body: String| async move {
+        sender.send((headers, body)).unwrap();
+        let mut failed = failed.lock().unwrap();
+        if *failed {
+            StatusCode::NO_CONTENT
+        } else {
+            *failed = true;
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let app = Router::new().route("/hook", post(hook));
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (url, receiver)
+}
+
+#[tokio::test]
+async fn test_webhooks() {
+    let (url, mut received) = start_webhook_receiver().await;
+    let server = TestServer::with_config(|state| {
+        state.webhooks =
+            Arc::new(webhooks::Webhooks::with_retry_delay(Duration::ZERO).allow_private(true))
+    })
+    .await;
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    for invalid in [
+        json!({"url": "ftp://example.com/hook", "events": ["todo.created"]}),
+        json!({"url": "not a url", "events": ["todo.created"]}),
+        json!({"url": url, "events": []}),
+    ] {
+        let resp = server
+            .client
+            .post(server.url("/api/webhooks"))
+            .json(&invalid)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    let resp = server
+        .client
+        .post(server.url("/api/webhooks"))
+        .json(&json!({"url": url, "events": ["todo.created", "todo.completed"]}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    let webhook: Value = resp.json().await.unwrap();
+    let webhook_id = webhook["id"].as_i64().unwrap();
+    let secret = webhook["secret"].as_str().unwrap().to_string();
+    let webhooks: Value = server
+        .client
+        .get(server.url("/api/webhooks"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(webhooks[0]["id"], webhook_id);
+    assert!(webhooks[0].get("secret").is_none());
+
+    let resp = server
+        .client
+        .post(server.url("/api/todos"))
+        .json(&json!({"title": "Water plants"}))
+        .send()
+        .await
+        .unwrap();
+    let todo: Value = resp.json().await.unwrap();
+    let id = todo["id"].as_i64().unwrap();
+
+    // The first attempt fails and is retried with the same delivery id
+    let (first, _) = received.recv().await.unwrap();
+    let (headers, body) = received.recv().await.unwrap();
+    assert_eq!(headers["x-donezo-delivery"], first["x-donezo-delivery"]);
+    assert_eq!(headers["x-donezo-event"], "todo.created");
+    assert_eq!(
+        headers["x-donezo-signature"].to_str().unwrap(),
+        webhooks::sign(&secret, &body)
+    );
+    let payload: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(payload["event"], "todo.created");
+    assert_eq!(payload["todo"]["id"], id);
+
+    // Renaming isn't subscribed to, completing is
+    for change in [
+        json!({"title": "Water the plants"}),
+        json!({"completed": true}),
+    ] {
+        server
+            .client
+            .put(server.url(&format!("/api/todos/{id}")))
+            .json(&change)
+            .send()
+            .await
+            .unwrap();
+    }
+    let (headers, body) = received.recv().await.unwrap();
+    assert_eq!(headers["x-donezo-event"], "todo.completed");
+    let payload: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(payload["todo"]["title"], "Water the plants");
+    assert_eq!(payload["todo"]["completed"], true);
+
+    // The attempt is logged right after the receiver responds
+    let deliveries_url = server.url(&format!("/api/webhooks/{webhook_id}/deliveries"));
+    let deliveries = loop {
+        let deliveries: Value = server
+            .client
+            .get(&deliveries_url)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        if deliveries[0]["status"] == "delivered" {
+            break deliveries;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(deliveries.as_array().unwrap().len(), 2);
+    assert_eq!(deliveries[0]["event"], "todo.completed");
+    assert_eq!(deliveries[0]["attempts"], 1);
+    assert_eq!(deliveries[1]["event"], "todo.created");
+    assert_eq!(deliveries[1]["status"], "delivered");
+    assert_eq!(deliveries[1]["attempts"], 2);
+    assert_eq!(deliveries[1]["response_status"], 204);
+
+    let resp = server
+        .client
+        .delete(server.url(&format!("/api/webhooks/{webhook_id}")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    let resp = server.client.get(&deliveries_url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // Todos changed along with others get their own events
+    server
+        .client
+        .post(server.url("/api/webhooks"))
+        .json(&json!({"url": url, "events": ["todo.created", "todo.completed", "todo.deleted"]}))
+        .send()
+        .await
+        .unwrap();
+    let resp = server
+        .client
+        .post(server.url("/api/lists"))
+        .json(&json!({"name": "Garden"}))
+        .send()
+        .await
+        .unwrap();
+    let list: Value = resp.json().await.unwrap();
+    let create = |body: Value| {
+        let request = server.client.post(server.url("/api/todos")).json(&body);
+        async move {
+            let todo: Value = request.send().await.unwrap().json().await.unwrap();
+            todo["id"].as_i64().unwrap()
+        }
+    };
+    let mow = create(json!({"title": "Mow", "list_id": list["id"]})).await;
+    create(json!({"title": "Edges", "parent_id": mow})).await;
+    let water = create(json!({
+        "title": "Water",
+        "list_id": list["id"],
+        "recurrence": {"type": "daily"}
+    }))
+    .await;
+    for (id, change) in [
+        (mow, json!({"completed": true, "cascade": true})),
+        (water, json!({"completed": true})),
+    ] {
+        server
+            .client
+            .put(server.url(&format!("/api/todos/{id}")))
+            .json(&change)
+            .send()
+            .await
+            .unwrap();
+    }
+    server
+        .client
+        .delete(server.url(&format!("/api/lists/{}", list["id"])))
+        .send()
+        .await
+        .unwrap();
+
+    let mut events = Vec::new();
+    for _ in 0..11 {
+        let (_, body) = received.recv().await.unwrap();
+        let payload: Value = serde_json::from_str(&body).unwrap();
+        let title = payload["todo"]["title"].as_str().unwrap().to_string();
+        events.push((payload["event"].as_str().unwrap().to_string(), title));
+    }
+    let mut deleted = events.split_off(7);
+    deleted.sort();
+    let expected = [
+        ("todo.created", "Mow"),
+        ("todo.created", "Edges"),
+        ("todo.created", "Water"),
+        ("todo.completed", "Mow"),
+        ("todo.completed", "Edges"),
+        ("todo.completed", "Water"),
+        // The next occurrence
+        ("todo.created", "Water"),
+        ("todo.deleted", "Edges"),
+        ("todo.deleted", "Mow"),
+        ("todo.deleted", "Water"),
+        ("todo.deleted", "Water"),
+    ];
+    let expected: Vec<(String, String)> = expected
+        .iter()
+        .map(|(event, title)| (event.to_string(), title.to_string()))
+        .collect();
+    assert_eq!(events, expected[..7]);
+    assert_eq!(deleted, expected[7..]);
+}
+
+#[tokio::test]
+async fn test_webhooks_to_private_addresses() {
+    let server = TestServer::new().await;
+    server
+        .client
+        .post(server.url("/api/login"))
+        .json(&json!({"password": "testpassword"}))
+        .send()
+        .await
+        .unwrap();
+
+    for url in [
+        "http://127.0.0.1:8080/hook",
+        "http://localhost/hook",
+        "http://169.254.169.254/latest/meta-data",
+        "http://10.0.0.1/hook",
+        "https://192.168.1.10/hook",
+        "http://[::1]/hook",
+        "http://[::ffff:127.0.0.1]/hook",
+        "http://[fd00::1]/hook",
+        "http://198.18.0.1/hook",
+        "http://198.19.255.254/hook",
+        "http://240.0.0.1/hook",
+        "http://[64:ff9b::a9fe:a9fe]/hook",
+        "http://[::127.0.0.1]/hook",
+        "http://[2002:a00:1::1]/hook",
+    ] {
+        let resp = server
+            .client
+            .post(server.url("/api/webhooks"))
+            .json(&json!({"url": url, "events": ["todo.created"]}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{url}");
+        let body: Value = resp.json().await.unwrap();
+        assert_eq!(
+            body["error"], "Webhooks can only be sent to public addresses",
+            "{url}"
+        );
+    }
 }